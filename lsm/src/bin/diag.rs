@@ -0,0 +1,45 @@
+/*
+    Copyright 2014-2015 Zumero, LLC
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// a port of fsharp_lsm/diag.  usage:
+//
+//     diag <file> <op> [args]
+//
+// where op is one of list_segments, list_free_blocks,
+// list_all_keys, list_segment_keys <segment>, or check.
+//
+// the file is opened read-only.  a file made with a comparator,
+// a page transform or a codec which is not built in can only be
+// opened by a program which has them, and which calls
+// lsm::diag::run with them in its settings.
+
+extern crate lsm;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        println!("usage: diag <file> <op> [args]");
+        std::process::exit(2);
+    }
+    match lsm::diag::run(&args[1..], lsm::DEFAULT_SETTINGS) {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    }
+}
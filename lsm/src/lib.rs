@@ -24,12 +24,14 @@
 #![feature(clone_from_slice)]
 #![feature(drain)]
 #![feature(iter_arith)]
+#![feature(collections_bound)]
 
 // TODO turn the following warnings back on later
 #![allow(non_snake_case)]
 #![allow(non_camel_case_types)]
 
 extern crate misc;
+extern crate libc;
 
 use misc::endian;
 use misc::bufndx;
@@ -45,10 +47,16 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::Bound;
 
 const SIZE_32: usize = 4; // like std::mem::size_of::<u32>()
 const SIZE_16: usize = 2; // like std::mem::size_of::<u16>()
 
+// every page ends with a crc32 of everything before it
+const PAGE_CHECKSUM_SIZE: usize = SIZE_32;
+
 pub type PageNum = u32;
 // type PageSize = u32;
 
@@ -67,16 +75,49 @@ pub enum Blob {
 }
 
 #[derive(Debug)]
-enum Error {
+pub enum Error {
     // TODO remove Misc
     Misc(&'static str),
 
     // TODO more detail within CorruptFile
     CorruptFile(&'static str),
 
+    // a page failed its checksum.  pageType is whatever the page
+    // claims to be, if anything.  the header is not a real page,
+    // so it is reported as page 0, with no page type and no segment.
+    CorruptPage {
+        page: PageNum,
+        pageType: Option<PageType>,
+        segment: Option<SegmentNum>,
+    },
+
     Io(std::io::Error),
     Utf8(std::str::Utf8Error),
 
+    // the file does not look like an lsm database at all
+    NotADatabase,
+    // the file is in a format newer than this code understands
+    UnsupportedFormatVersion(u32),
+    // something was compressed with a codec which is neither
+    // built in nor the one in the settings
+    UnknownCodec(u8),
+    // the pages in the file were written with a different page
+    // transform (by id, 0 for none) than the one in the settings
+    WrongPageTransform(u8),
+    // the keys in the file are in the order of a different
+    // comparator (by name, empty for plain byte order) than the
+    // one in the settings
+    WrongComparator(String),
+    // a key read under a snapshot has been written since
+    Conflict(Box<[u8]>),
+    // the write lock is held by somebody else
+    Busy,
+    // MaxOpenFiles leaves no handle for a writer, once the
+    // readers have theirs
+    TooManyOpenFiles(usize),
+    // the db was opened with ReadOnly
+    ReadOnly,
+
     CursorNotValid,
     InvalidPageNumber,
     InvalidPageType,
@@ -91,7 +132,17 @@ impl std::fmt::Display for Error {
             Error::Utf8(ref err) => write!(f, "Utf8 error: {}", err),
             Error::Misc(s) => write!(f, "Misc error: {}", s),
             Error::CorruptFile(s) => write!(f, "Corrupt file: {}", s),
+            Error::CorruptPage{page, pageType, segment} => write!(f, "Corrupt page: {} (type {:?}, segment {:?})", page, pageType, segment),
             Error::Poisoned => write!(f, "Poisoned"),
+            Error::NotADatabase => write!(f, "Not a database"),
+            Error::UnsupportedFormatVersion(v) => write!(f, "Unsupported format version: {}", v),
+            Error::UnknownCodec(id) => write!(f, "Unknown codec: {}", id),
+            Error::WrongPageTransform(id) => write!(f, "Wrong page transform: file uses {}", id),
+            Error::WrongComparator(ref name) => write!(f, "Wrong comparator: file uses {:?}", name),
+            Error::Conflict(ref k) => write!(f, "Conflict: key {:?} changed since the snapshot", k),
+            Error::Busy => write!(f, "Busy"),
+            Error::TooManyOpenFiles(max) => write!(f, "Too many open files: {} leaves none for writing", max),
+            Error::ReadOnly => write!(f, "Read only"),
             Error::CursorNotValid => write!(f, "Cursor not valid"),
             Error::InvalidPageNumber => write!(f, "Invalid page number"),
             Error::InvalidPageType => write!(f, "Invalid page type"),
@@ -107,7 +158,17 @@ impl std::error::Error for Error {
             Error::Utf8(ref err) => std::error::Error::description(err),
             Error::Misc(s) => s,
             Error::CorruptFile(s) => s,
+            Error::CorruptPage{..} => "corrupt page",
             Error::Poisoned => "poisoned",
+            Error::NotADatabase => "not a database",
+            Error::UnsupportedFormatVersion(_) => "unsupported format version",
+            Error::UnknownCodec(_) => "unknown codec",
+            Error::WrongPageTransform(_) => "wrong page transform",
+            Error::WrongComparator(_) => "wrong comparator",
+            Error::Conflict(_) => "conflict",
+            Error::Busy => "busy",
+            Error::TooManyOpenFiles(_) => "too many open files",
+            Error::ReadOnly => "read only",
             Error::CursorNotValid => "cursor not valid",
             Error::InvalidPageNumber => "invalid page number",
             Error::InvalidPageType => "invalid page type",
@@ -191,12 +252,6 @@ impl<'a> Index<usize> for SplitSlice<'a> {
     }
 }
 
-fn split3<T>(a: &mut [T], i: usize) -> (&mut [T], &mut [T], &mut [T]) {
-    let (before, a2) = a.split_at_mut(i);
-    let (islice, after) = a2.split_at_mut(1);
-    (before, islice, after)
-}
-
 pub enum KeyRef<'a> {
     // for an overflowed key, we just punt and read it into memory
     Overflowed(Box<[u8]>),
@@ -320,6 +375,28 @@ impl<'a> KeyRef<'a> {
         return xlen.cmp(&ylen);
     }
 
+    fn as_bytes(&self) -> Cow<[u8]> {
+        match *self {
+            KeyRef::Overflowed(ref a) => Cow::Borrowed(a),
+            KeyRef::Array(a) => Cow::Borrowed(a),
+            KeyRef::Prefixed(front,back) => {
+                let mut k = Vec::with_capacity(front.len() + back.len());
+                k.push_all(front);
+                k.push_all(back);
+                Cow::Owned(k)
+            },
+        }
+    }
+
+    // like cmp, but in the order of the comparator.  with a
+    // comparator, a prefixed key has to be put back together.
+    fn compare_by(c: Option<&'static IComparator>, x: &KeyRef, y: &KeyRef) -> Ordering {
+        match c {
+            Some(c) => c.Compare(&x.as_bytes(), &y.as_bytes()),
+            None => Self::cmp(x, y),
+        }
+    }
+
     pub fn cmp(x: &KeyRef, y: &KeyRef) -> Ordering {
         match (x,y) {
             (&KeyRef::Overflowed(ref x_k), &KeyRef::Overflowed(ref y_k)) => {
@@ -393,9 +470,9 @@ impl<'a> std::fmt::Debug for ValueRef<'a> {
 }
 
 #[derive(Hash,PartialEq,Eq,Copy,Clone,Debug)]
-struct PageBlock {
-    firstPage: PageNum,
-    lastPage: PageNum,
+pub struct PageBlock {
+    pub firstPage: PageNum,
+    pub lastPage: PageNum,
 }
 
 impl PageBlock {
@@ -403,11 +480,11 @@ impl PageBlock {
         PageBlock { firstPage: first, lastPage: last }
     }
 
-    fn count_pages(&self) -> PageNum {
+    pub fn count_pages(&self) -> PageNum {
         self.lastPage - self.firstPage + 1
     }
 
-    fn contains_page(&self, pgnum: PageNum) -> bool {
+    pub fn contains_page(&self, pgnum: PageNum) -> bool {
         (pgnum >= self.firstPage) && (pgnum <= self.lastPage)
     }
 }
@@ -425,9 +502,10 @@ pub type SegmentNum = u64;
 
 trait IPages {
     fn PageSize(&self) -> usize;
+    fn PageTransform(&self) -> Option<&'static IPageTransform>;
     fn Begin(&self) -> Result<PendingSegment>;
     fn GetBlock(&self, token: &mut PendingSegment) -> Result<PageBlock>;
-    fn End(&self, token: PendingSegment, page: PageNum) -> Result<SegmentNum>;
+    fn End(&self, token: PendingSegment, page: PageNum, bloom: Option<SegmentBloom>, graveyard: Option<SegmentGraveyard>) -> Result<SegmentNum>;
 }
 
 #[derive(PartialEq,Copy,Clone)]
@@ -450,6 +528,19 @@ impl<'a> CursorIterator<'a> {
 impl<'a> Iterator for CursorIterator<'a> {
     type Item = Result<kvp>;
     fn next(&mut self) -> Option<Result<kvp>> {
+        // keys hidden by a range tombstone are just dropped
+        while self.csr.IsValid() {
+            match self.csr.isBuried() {
+                Ok(true) => {
+                    let r = self.csr.Next();
+                    if r.is_err() {
+                        return Some(Err(r.err().unwrap()));
+                    }
+                },
+                Ok(false) => break,
+                Err(e) => return Some(Err(e)),
+            }
+        }
         if self.csr.IsValid() {
             let k = {
                 let k = self.csr.KeyRef();
@@ -535,31 +626,595 @@ pub trait ICursor<'a> {
     fn KeyCompare(&self, k: &KeyRef) -> Result<Ordering>;
 }
 
+// how hard the db tries to get things onto the disk
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum SyncMode {
+    // never fsync.  a crash may lose or damage recent commits.
+    Off,
+    // fsync each header after it is written.  a crash can still
+    // leave the header referring to pages which never made it.
+    HeaderOnly,
+    // fsync the file before each header is written, so that the
+    // segments it refers to are on the disk, and again after.
+    Full,
+}
+
+// compresses leaf pages and overflowed values.  the id is stored
+// with everything the codec compresses, so it can never change,
+// and the ones below 16 are reserved for the built-in codecs.
+pub trait ICodec : Sync + Send {
+    fn Id(&self) -> u8;
+    fn Compress(&self, a: &[u8]) -> Vec<u8>;
+    // None unless a is valid and decompresses to exactly len bytes
+    fn Decompress(&self, a: &[u8], len: usize) -> Option<Vec<u8>>;
+}
+
+pub struct Lz4Codec;
+
+impl ICodec for Lz4Codec {
+    fn Id(&self) -> u8 {
+        1
+    }
+
+    fn Compress(&self, a: &[u8]) -> Vec<u8> {
+        lz4::compress(a)
+    }
+
+    fn Decompress(&self, a: &[u8], len: usize) -> Option<Vec<u8>> {
+        lz4::decompress(a, len)
+    }
+}
+
+pub static LZ4 : Lz4Codec = Lz4Codec;
+
+// the built-in codecs can always be read, whatever the settings say.
+// any other codec has to be the one in the settings.
+fn findCodec(id: u8, custom: Option<&'static ICodec>) -> Result<&'static ICodec> {
+    if id == LZ4.Id() {
+        return Ok(&LZ4);
+    }
+    match custom {
+        Some(c) => {
+            if c.Id() == id {
+                Ok(c)
+            } else {
+                Err(Error::UnknownCodec(id))
+            }
+        },
+        None => Err(Error::UnknownCodec(id)),
+    }
+}
+
+// transforms every page on its way to and from the disk, for
+// example to encrypt it.  each page keeps ReservedBytes just before
+// its checksum for the transform's own use, like a nonce or a MAC.
+// the id (which must not be 0) is recorded in the header, and the
+// file can only be opened with the same transform.  records in the
+// write-ahead log go through it too, numbered as if they were pages.
+// the header does not, but it holds no keys or values, just page
+// and segment numbers, counters, the transform id and the name of
+// the comparator.
+pub trait IPageTransform : Sync + Send {
+    fn Id(&self) -> u8;
+    fn ReservedBytes(&self) -> usize;
+    // buf is the whole page except for the checksum, so the
+    // reserved bytes are at its end
+    fn Encode(&self, page: PageNum, buf: &mut [u8]);
+    // false if the page fails whatever check the transform makes
+    fn Decode(&self, page: PageNum, buf: &mut [u8]) -> bool;
+}
+
+fn pageTransformId(t: Option<&'static IPageTransform>) -> u8 {
+    match t {
+        Some(t) => t.Id(),
+        None => 0,
+    }
+}
+
+fn reservedBytes(t: Option<&'static IPageTransform>) -> usize {
+    match t {
+        Some(t) => t.ReservedBytes(),
+        None => 0,
+    }
+}
+
+// the order of the keys.  without one, keys are in plain byte
+// order, as they are in every file from before format 8.  keys
+// which compare Equal are the same key, even if their bytes differ.  the name
+// (which must not be empty) is recorded in the header, and the file
+// can only be opened with a comparator of the same name.  the
+// sequences given to WriteSegmentFromSortedSequence have to be in
+// this order too.
+pub trait IComparator : Sync + Send {
+    fn Name(&self) -> &str;
+    fn Compare(&self, x: &[u8], y: &[u8]) -> Ordering;
+}
+
+fn comparatorName(c: Option<&'static IComparator>) -> &'static str {
+    match c {
+        Some(c) => c.Name(),
+        None => "",
+    }
+}
+
+fn compareKeys(c: Option<&'static IComparator>, x: &[u8], y: &[u8]) -> Ordering {
+    match c {
+        Some(c) => c.Compare(x, y),
+        None => bcmp::Compare(x, y),
+    }
+}
+
+// one segment in currentState, as a merge policy sees it
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct MergeCandidate {
+    pub segment: SegmentNum,
+    pub age: u32,
+    pub pages: PageNum,
+    // already part of a merge which has not been committed
+    pub merging: bool,
+}
+
+// decides what the automatic merger merges.  the segments come
+// newest first, as in currentState.  a proposal is a range of them,
+// none of which may be merging already, and it gets replaced by one
+// segment, one age older than the oldest one in it.  the merger
+// keeps asking until it gets None.  background is false right after
+// a commit, where merges ought to be small, and true on the merge
+// thread.
+pub trait IMergePolicy : Sync + Send {
+    fn Propose(&self, segments: &[MergeCandidate], background: bool) -> Option<std::ops::Range<usize>>;
+}
+
+// like getPossibleMerge in the F# version.  once there are more
+// than Segments segments of one age, the oldest Segments of them get
+// merged.  ages below ForegroundAges are merged in the foreground,
+// all the older ones in the background.
+pub struct TieredMergePolicy {
+    pub Segments: usize,
+    pub ForegroundAges: u32,
+}
+
+impl IMergePolicy for TieredMergePolicy {
+    fn Propose(&self, segments: &[MergeCandidate], background: bool) -> Option<std::ops::Range<usize>> {
+        if 0 == self.Segments {
+            return None;
+        }
+        // youngest first
+        let mut levels = segments.iter()
+            .map(|s| s.age)
+            .filter(|age| (*age >= self.ForegroundAges) == background)
+            .collect::<Vec<u32>>();
+        levels.sort();
+        levels.dedup();
+        for level in levels {
+            // the segments of one age are contiguous
+            let first = match segments.iter().position(|s| s.age == level) {
+                Some(i) => i,
+                None => continue,
+            };
+            let mut end = first;
+            while end < segments.len() && segments[end].age == level {
+                end = end + 1;
+            }
+            // merging something other than the end of the group
+            // would leave the group in pieces
+            let mut start = end;
+            while start > first && !segments[start - 1].merging {
+                start = start - 1;
+            }
+            if end - start > self.Segments {
+                return Some(end - self.Segments .. end);
+            }
+        }
+        None
+    }
+}
+
+// keeps each segment at least Ratio times the size, in pages, of
+// the next newer one.  where that is not so, the two of them get
+// merged, which may in turn be too big for the next older one.
+// the pairs are tried from the oldest end.  merges of more than
+// ForegroundPages pages are left to the background.
+pub struct LeveledMergePolicy {
+    pub Ratio: PageNum,
+    pub ForegroundPages: PageNum,
+}
+
+impl IMergePolicy for LeveledMergePolicy {
+    fn Propose(&self, segments: &[MergeCandidate], background: bool) -> Option<std::ops::Range<usize>> {
+        let mut i = segments.len();
+        while i >= 2 {
+            i = i - 1;
+            if segments[i - 1].pages.saturating_mul(self.Ratio) <= segments[i].pages {
+                continue;
+            }
+            let group = &segments[i - 1 .. i + 1];
+            if group.iter().any(|s| s.merging) {
+                continue;
+            }
+            if !background && group.iter().fold(0, |acc: PageNum, s| acc.saturating_add(s.pages)) > self.ForegroundPages {
+                continue;
+            }
+            return Some(i - 1 .. i + 1);
+        }
+        None
+    }
+}
+
 //#[derive(Copy,Clone)]
 pub struct DbSettings {
     pub AutoMergeEnabled : bool,
-    pub AutoMergeMinimumPages : PageNum,
+    // how many segments of one age the default merge policy
+    // merges at a time
+    pub AutoMergeMinimumSegments : usize,
     pub DefaultPageSize : usize,
     pub PagesPerBlock : PageNum,
+    // when the memtable grows beyond this, it gets flushed to a segment
+    // by the next write which finds the write lock free
+    pub MaxMemtableBytes : usize,
+    // fsync the write-ahead log after every write batch
+    pub SyncWal : bool,
+    pub SyncMode : SyncMode,
+    // size of each segment's bloom filter.  0 means no filter.  with
+    // a Comparator, there are no filters either.
+    pub BloomBitsPerKey : usize,
+    // how many bytes of bloom filters to keep in memory.  a filter
+    // is read the first time a lookup needs it, and the least
+    // recently used ones are dropped to make room.  a filter bigger
+    // than this is read again for every lookup.
+    pub BloomCacheBytes : usize,
+    // codec for new leaf pages and overflowed values.  None means
+    // nothing new gets compressed.
+    pub Compression : Option<&'static ICodec>,
+    // applied to every page of a new file, and to the write-ahead
+    // log.  the header is left plain.  an existing file has to be
+    // opened with whatever it was created with.
+    pub PageTransform : Option<&'static IPageTransform>,
+    // None means plain byte order.  like the page transform, an
+    // existing file has to be opened with whatever it was created
+    // with.
+    pub Comparator : Option<&'static IComparator>,
+    // how many parent and leaf pages to keep in memory, shared by
+    // all the cursors.  0 means no cache.
+    pub PageCacheSize : usize,
+    // segment cursors read pages, and overflows, straight out of a
+    // read-only mapping of the file, instead of copying them.  this
+    // only happens on unix.  anywhere else, pages are read the usual
+    // way, as are pages with a page transform, since they have to be
+    // decoded.
+    pub MmapReads : bool,
+    // how many handles on the file may be open at once.  all the
+    // readers share one, and each writer needs one of its own,
+    // so this has to be at least 2.  a writer waits when they are
+    // all in use.  0 means no limit.
+    pub MaxOpenFiles : usize,
+    // what the automatic merger merges.  None means TieredMergePolicy,
+    // with AutoMergeMinimumSegments segments at a time.
+    pub MergePolicy : Option<&'static IMergePolicy>,
+    // opens the file without asking for write access.  anything
+    // which would write fails with ReadOnly, nothing gets merged,
+    // an older format is not upgraded, and the write-ahead log is
+    // not opened at all, so writes which never made it out of the
+    // log are not seen.
+    pub ReadOnly : bool,
 }
 
 pub const DEFAULT_SETTINGS : DbSettings = 
     DbSettings
     {
         AutoMergeEnabled : true,
-        AutoMergeMinimumPages : 4,
+        AutoMergeMinimumSegments : 4,
         DefaultPageSize : 4096,
         PagesPerBlock : 256,
+        MaxMemtableBytes : 1024 * 1024,
+        SyncWal : false,
+        SyncMode : SyncMode::Full,
+        BloomBitsPerKey : 10,
+        BloomCacheBytes : 16 * 1024 * 1024,
+        Compression : None,
+        PageTransform : None,
+        Comparator : None,
+        PageCacheSize : 256,
+        MmapReads : false,
+        MaxOpenFiles : 16,
+        MergePolicy : None,
+        ReadOnly : false,
     };
 
 #[derive(Clone)]
-struct SegmentInfo {
+pub struct SegmentInfo {
     root : PageNum,
     age : u32,
     // TODO does this grow?  shouldn't it be a boxed array?
     // yes, but then derive clone complains.
     // ideally we could just stop cloning this struct.
-    blocks : Vec<PageBlock> 
+    blocks : Vec<PageBlock>,
+    bloom : Option<SegmentBloom>,
+    graveyard : Option<SegmentGraveyard>,
+    // the change counter of the commit which brought these pairs
+    // into the db.  not stored in the header, so it is 0 for
+    // anything committed before the db was opened.
+    committed : u64,
+}
+
+impl SegmentInfo {
+    pub fn root(&self) -> PageNum {
+        self.root
+    }
+
+    pub fn age(&self) -> u32 {
+        self.age
+    }
+
+    pub fn blocks(&self) -> &[PageBlock] {
+        &self.blocks
+    }
+
+    pub fn count_pages(&self) -> PageNum {
+        self.blocks.iter().map(|b| b.count_pages()).sum()
+    }
+}
+
+// a bloom filter over the keys in one segment.  it can say for
+// sure that a key is not there, which saves a seek.
+struct BloomFilter {
+    hashes: u32,
+    bits: Box<[u8]>,
+}
+
+impl BloomFilter {
+    // FNV-1a
+    fn hash(k: &KeyRef) -> u64 {
+        fn update(h: u64, a: &[u8]) -> u64 {
+            let mut h = h;
+            for b in a {
+                h = (h ^ (*b as u64)).wrapping_mul(0x100000001b3);
+            }
+            h
+        }
+
+        let h = 0xcbf29ce484222325;
+        match *k {
+            KeyRef::Overflowed(ref a) => update(h, a),
+            KeyRef::Prefixed(front, back) => update(update(h, front), back),
+            KeyRef::Array(a) => update(h, a),
+        }
+    }
+
+    fn new(keyHashes: &[u64], bitsPerKey: usize) -> BloomFilter {
+        let countBits = std::cmp::max(64, keyHashes.len() * bitsPerKey);
+        // bitsPerKey * ln(2) hashes gives the fewest false positives
+        let hashes = (bitsPerKey as f64 * 0.69) as u32;
+        let mut f = BloomFilter {
+            hashes: std::cmp::min(30, std::cmp::max(1, hashes)),
+            bits: vec![0; (countBits + 7) / 8].into_boxed_slice(),
+        };
+        for h in keyHashes {
+            let countBits = (f.bits.len() * 8) as u64;
+            // double hashing, with the two halves of the hash
+            let mut x = h & 0xffffffff;
+            let delta = (h >> 32) | 1;
+            for _ in 0 .. f.hashes {
+                let i = (x % countBits) as usize;
+                f.bits[i / 8] |= 1 << (i % 8);
+                x = x.wrapping_add(delta);
+            }
+        }
+        f
+    }
+
+    fn might_contain(&self, k: &KeyRef) -> bool {
+        let h = Self::hash(k);
+        let countBits = (self.bits.len() * 8) as u64;
+        let mut x = h & 0xffffffff;
+        let delta = (h >> 32) | 1;
+        for _ in 0 .. self.hashes {
+            let i = (x % countBits) as usize;
+            if 0 == (self.bits[i / 8] & (1 << (i % 8))) {
+                return false;
+            }
+            x = x.wrapping_add(delta);
+        }
+        true
+    }
+
+    fn encode(&self) -> Box<[u8]> {
+        let mut pb = PageBuilder::new(1 + self.bits.len());
+        pb.PutByte(self.hashes as u8);
+        pb.PutArray(&self.bits);
+        pb.buf
+    }
+
+    fn decode(a: &[u8]) -> Option<BloomFilter> {
+        if a.len() < 2 || a[0] == 0 {
+            None
+        } else {
+            let mut bits = Vec::with_capacity(a.len() - 1);
+            bits.push_all(&a[1 ..]);
+            let f = BloomFilter {
+                hashes: a[0] as u32,
+                bits: bits.into_boxed_slice(),
+            };
+            Some(f)
+        }
+    }
+}
+
+// where a segment's bloom filter is stored (as an overflow,
+// within the segment's blocks).  the filter itself is read when
+// an exact match first needs it, and kept in the bloom cache.
+#[derive(Clone)]
+struct SegmentBloom {
+    firstPage: PageNum,
+    len: usize,
+}
+
+impl SegmentBloom {
+    fn read(&self, fs: &Arc<File>, pgsz: usize, transform: Option<&'static IPageTransform>, g: SegmentNum) -> Result<BloomFilter> {
+        // bloom filters are never compressed
+        let mut strm = try!(myOverflowReadStream::new(fs.clone(), pgsz, true, transform, None, None, self.firstPage, self.len, g));
+        let mut a = Vec::with_capacity(self.len);
+        try!(strm.read_to_end(&mut a));
+        match BloomFilter::decode(&a) {
+            Some(f) => Ok(f),
+            None => Err(Error::CorruptPage {
+                page: self.firstPage,
+                pageType: Some(PageType::OVERFLOW_NODE),
+                segment: Some(g),
+            }),
+        }
+    }
+}
+
+// varint::read trusts the buffer to hold the whole varint.  for
+// bytes which may be damaged, this checks first, and gives None
+// instead of running off the end.
+fn readVarint(a: &[u8], cur: &mut usize) -> Option<u64> {
+    if *cur >= a.len() {
+        return None;
+    }
+    // the first byte says how long the varint is
+    let a0 = a[*cur] as usize;
+    let need =
+        if a0 <= 240 { 1 }
+        else if a0 <= 248 { 2 }
+        else if a0 == 249 { 3 }
+        else { a0 - 246 };
+    if need > a.len() - *cur {
+        return None;
+    }
+    Some(varint::read(a, cur))
+}
+
+// range tombstones.  each range is [start, end), and it hides the
+// keys in it which are older than the range, which means the ones
+// in older segments, but not the ones in its own segment.  the
+// ranges are kept sorted, with no two of them overlapping.
+#[derive(Clone)]
+struct Graveyard {
+    ranges: Vec<(Box<[u8]>,Box<[u8]>)>,
+}
+
+impl Graveyard {
+    fn new() -> Graveyard {
+        Graveyard {
+            ranges: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // ranges which overlap or touch the new one get combined with it
+    fn add(&mut self, c: Option<&'static IComparator>, start: &[u8], end: &[u8]) {
+        if compareKeys(c, start, end) != Ordering::Less {
+            return;
+        }
+        let mut start = start.to_vec().into_boxed_slice();
+        let mut end = end.to_vec().into_boxed_slice();
+        let mut i = 0;
+        while i < self.ranges.len() {
+            let apart = {
+                let (ref s, ref e) = self.ranges[i];
+                compareKeys(c, e, &start) == Ordering::Less || compareKeys(c, &end, s) == Ordering::Less
+            };
+            if apart {
+                i = i + 1;
+            } else {
+                let (s, e) = self.ranges.remove(i);
+                if compareKeys(c, &s, &start) == Ordering::Less {
+                    start = s;
+                }
+                if compareKeys(c, &e, &end) == Ordering::Greater {
+                    end = e;
+                }
+            }
+        }
+        let at = self.ranges.iter().position(|r| compareKeys(c, &r.0, &start) == Ordering::Greater).unwrap_or(self.ranges.len());
+        self.ranges.insert(at, (start, end));
+    }
+
+    fn add_all(&mut self, c: Option<&'static IComparator>, other: &Graveyard) {
+        for &(ref s, ref e) in other.ranges.iter() {
+            self.add(c, s, e);
+        }
+    }
+
+    fn covers(&self, c: Option<&'static IComparator>, k: &KeyRef) -> bool {
+        // the last range which starts at or before k
+        let mut min = 0;
+        let mut max = self.ranges.len();
+        while min < max {
+            let mid = (min + max) / 2;
+            if KeyRef::compare_by(c, &KeyRef::for_slice(&self.ranges[mid].0), k) == Ordering::Greater {
+                max = mid;
+            } else {
+                min = mid + 1;
+            }
+        }
+        min > 0 && KeyRef::compare_by(c, k, &KeyRef::for_slice(&self.ranges[min - 1].1)) == Ordering::Less
+    }
+
+    // approximate, like the memtable's pair sizes
+    fn range_size(start: &[u8], end: &[u8]) -> usize {
+        start.len() + end.len() + 16
+    }
+
+    fn encode(&self) -> Box<[u8]> {
+        let mut len = varint::space_needed_for(self.ranges.len() as u64);
+        for &(ref s, ref e) in self.ranges.iter() {
+            len = len + varint::space_needed_for(s.len() as u64) + s.len();
+            len = len + varint::space_needed_for(e.len() as u64) + e.len();
+        }
+        let mut pb = PageBuilder::new(len);
+        pb.PutVarint(self.ranges.len() as u64);
+        for &(ref s, ref e) in self.ranges.iter() {
+            pb.PutVarint(s.len() as u64);
+            pb.PutArray(s);
+            pb.PutVarint(e.len() as u64);
+            pb.PutArray(e);
+        }
+        pb.buf
+    }
+
+    fn decode(a: &[u8]) -> Option<Graveyard> {
+        fn get(a: &[u8], cur: &mut usize) -> Option<Box<[u8]>> {
+            let len = match readVarint(a, cur) {
+                Some(len) => len,
+                None => return None,
+            };
+            if len > (a.len() - *cur) as u64 {
+                return None;
+            }
+            let len = len as usize;
+            let v = a[*cur .. *cur + len].to_vec().into_boxed_slice();
+            *cur = *cur + len;
+            Some(v)
+        }
+
+        let mut cur = 0;
+        let count = match readVarint(a, &mut cur) {
+            Some(count) => count,
+            None => return None,
+        };
+        let mut g = Graveyard::new();
+        for _ in 0 .. count {
+            match (get(a, &mut cur), get(a, &mut cur)) {
+                (Some(s), Some(e)) => g.ranges.push((s, e)),
+                _ => return None,
+            }
+        }
+        Some(g)
+    }
+}
+
+// like SegmentBloom, but for the segment's range tombstones
+#[derive(Clone)]
+struct SegmentGraveyard {
+    firstPage: PageNum,
+    len: usize,
+    ranges: Arc<Graveyard>,
 }
 
 pub mod utils {
@@ -571,6 +1226,8 @@ pub mod utils {
     use super::Error;
     use super::Result;
     use super::misc;
+    use super::libc;
+    use std::fs::File;
 
     pub fn SeekPage(strm: &mut Seek, pgsz: usize, pageNumber: PageNum) -> Result<u64> {
         if 0==pageNumber { 
@@ -581,6 +1238,289 @@ pub mod utils {
         Ok(v)
     }
 
+    // like read_fully, at pos, without moving the file position,
+    // so readers can share a handle.
+    #[cfg(unix)]
+    pub fn ReadAt(f: &File, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut sofar = 0;
+        let len = buf.len();
+        while sofar < len {
+            let cur = &mut buf[sofar..len];
+            let n = unsafe {
+                libc::pread(f.as_raw_fd(), cur.as_mut_ptr() as *mut libc::c_void, cur.len() as libc::size_t, (pos + sofar as u64) as libc::off_t)
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if n == 0 {
+                break;
+            }
+            sofar += n as usize;
+        }
+        Ok(sofar)
+    }
+
+    // seek_read moves the file position, but nobody sharing the
+    // handle depends on it.
+    #[cfg(windows)]
+    pub fn ReadAt(f: &File, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+
+        let mut sofar = 0;
+        let len = buf.len();
+        while sofar < len {
+            match f.seek_read(&mut buf[sofar..len], pos + sofar as u64) {
+                Ok(0) => break,
+                Ok(n) => sofar += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sofar)
+    }
+
+    pub fn ReadPageAt(f: &File, pgsz: usize, pageNumber: PageNum, buf: &mut [u8]) -> Result<usize> {
+        if 0==pageNumber { 
+            return Err(Error::InvalidPageNumber);
+        }
+        let pos = ((pageNumber as u64) - 1) * (pgsz as u64);
+        let got = try!(ReadAt(f, pos, buf));
+        Ok(got)
+    }
+
+}
+
+mod crc32 {
+    // the usual CRC-32 (IEEE 802.3), table driven.
+
+    static TABLE: [u32; 256] = [
+        0x00000000, 0x77073096, 0xee0e612c, 0x990951ba, 0x076dc419, 0x706af48f,
+        0xe963a535, 0x9e6495a3, 0x0edb8832, 0x79dcb8a4, 0xe0d5e91e, 0x97d2d988,
+        0x09b64c2b, 0x7eb17cbd, 0xe7b82d07, 0x90bf1d91, 0x1db71064, 0x6ab020f2,
+        0xf3b97148, 0x84be41de, 0x1adad47d, 0x6ddde4eb, 0xf4d4b551, 0x83d385c7,
+        0x136c9856, 0x646ba8c0, 0xfd62f97a, 0x8a65c9ec, 0x14015c4f, 0x63066cd9,
+        0xfa0f3d63, 0x8d080df5, 0x3b6e20c8, 0x4c69105e, 0xd56041e4, 0xa2677172,
+        0x3c03e4d1, 0x4b04d447, 0xd20d85fd, 0xa50ab56b, 0x35b5a8fa, 0x42b2986c,
+        0xdbbbc9d6, 0xacbcf940, 0x32d86ce3, 0x45df5c75, 0xdcd60dcf, 0xabd13d59,
+        0x26d930ac, 0x51de003a, 0xc8d75180, 0xbfd06116, 0x21b4f4b5, 0x56b3c423,
+        0xcfba9599, 0xb8bda50f, 0x2802b89e, 0x5f058808, 0xc60cd9b2, 0xb10be924,
+        0x2f6f7c87, 0x58684c11, 0xc1611dab, 0xb6662d3d, 0x76dc4190, 0x01db7106,
+        0x98d220bc, 0xefd5102a, 0x71b18589, 0x06b6b51f, 0x9fbfe4a5, 0xe8b8d433,
+        0x7807c9a2, 0x0f00f934, 0x9609a88e, 0xe10e9818, 0x7f6a0dbb, 0x086d3d2d,
+        0x91646c97, 0xe6635c01, 0x6b6b51f4, 0x1c6c6162, 0x856530d8, 0xf262004e,
+        0x6c0695ed, 0x1b01a57b, 0x8208f4c1, 0xf50fc457, 0x65b0d9c6, 0x12b7e950,
+        0x8bbeb8ea, 0xfcb9887c, 0x62dd1ddf, 0x15da2d49, 0x8cd37cf3, 0xfbd44c65,
+        0x4db26158, 0x3ab551ce, 0xa3bc0074, 0xd4bb30e2, 0x4adfa541, 0x3dd895d7,
+        0xa4d1c46d, 0xd3d6f4fb, 0x4369e96a, 0x346ed9fc, 0xad678846, 0xda60b8d0,
+        0x44042d73, 0x33031de5, 0xaa0a4c5f, 0xdd0d7cc9, 0x5005713c, 0x270241aa,
+        0xbe0b1010, 0xc90c2086, 0x5768b525, 0x206f85b3, 0xb966d409, 0xce61e49f,
+        0x5edef90e, 0x29d9c998, 0xb0d09822, 0xc7d7a8b4, 0x59b33d17, 0x2eb40d81,
+        0xb7bd5c3b, 0xc0ba6cad, 0xedb88320, 0x9abfb3b6, 0x03b6e20c, 0x74b1d29a,
+        0xead54739, 0x9dd277af, 0x04db2615, 0x73dc1683, 0xe3630b12, 0x94643b84,
+        0x0d6d6a3e, 0x7a6a5aa8, 0xe40ecf0b, 0x9309ff9d, 0x0a00ae27, 0x7d079eb1,
+        0xf00f9344, 0x8708a3d2, 0x1e01f268, 0x6906c2fe, 0xf762575d, 0x806567cb,
+        0x196c3671, 0x6e6b06e7, 0xfed41b76, 0x89d32be0, 0x10da7a5a, 0x67dd4acc,
+        0xf9b9df6f, 0x8ebeeff9, 0x17b7be43, 0x60b08ed5, 0xd6d6a3e8, 0xa1d1937e,
+        0x38d8c2c4, 0x4fdff252, 0xd1bb67f1, 0xa6bc5767, 0x3fb506dd, 0x48b2364b,
+        0xd80d2bda, 0xaf0a1b4c, 0x36034af6, 0x41047a60, 0xdf60efc3, 0xa867df55,
+        0x316e8eef, 0x4669be79, 0xcb61b38c, 0xbc66831a, 0x256fd2a0, 0x5268e236,
+        0xcc0c7795, 0xbb0b4703, 0x220216b9, 0x5505262f, 0xc5ba3bbe, 0xb2bd0b28,
+        0x2bb45a92, 0x5cb36a04, 0xc2d7ffa7, 0xb5d0cf31, 0x2cd99e8b, 0x5bdeae1d,
+        0x9b64c2b0, 0xec63f226, 0x756aa39c, 0x026d930a, 0x9c0906a9, 0xeb0e363f,
+        0x72076785, 0x05005713, 0x95bf4a82, 0xe2b87a14, 0x7bb12bae, 0x0cb61b38,
+        0x92d28e9b, 0xe5d5be0d, 0x7cdcefb7, 0x0bdbdf21, 0x86d3d2d4, 0xf1d4e242,
+        0x68ddb3f8, 0x1fda836e, 0x81be16cd, 0xf6b9265b, 0x6fb077e1, 0x18b74777,
+        0x88085ae6, 0xff0f6a70, 0x66063bca, 0x11010b5c, 0x8f659eff, 0xf862ae69,
+        0x616bffd3, 0x166ccf45, 0xa00ae278, 0xd70dd2ee, 0x4e048354, 0x3903b3c2,
+        0xa7672661, 0xd06016f7, 0x4969474d, 0x3e6e77db, 0xaed16a4a, 0xd9d65adc,
+        0x40df0b66, 0x37d83bf0, 0xa9bcae53, 0xdebb9ec5, 0x47b2cf7f, 0x30b5ffe9,
+        0xbdbdf21c, 0xcabac28a, 0x53b39330, 0x24b4a3a6, 0xbad03605, 0xcdd70693,
+        0x54de5729, 0x23d967bf, 0xb3667a2e, 0xc4614ab8, 0x5d681b02, 0x2a6f2b94,
+        0xb40bbe37, 0xc30c8ea1, 0x5a05df1b, 0x2d02ef8d,
+    ];
+
+    pub fn update(crc: u32, buf: &[u8]) -> u32 {
+        let mut c = !crc;
+        for b in buf {
+            c = TABLE[((c ^ (*b as u32)) & 0xff) as usize] ^ (c >> 8);
+        }
+        !c
+    }
+
+    pub fn checksum(buf: &[u8]) -> u32 {
+        update(0, buf)
+    }
+}
+
+mod lz4 {
+    // the LZ4 block format.  the compressor is the simple greedy
+    // one, with a single hash table and no search for better matches.
+
+    const MIN_MATCH: usize = 4;
+    // the format says the last 5 bytes are always literals, and the
+    // last match starts at least 12 bytes before the end
+    const LAST_LITERALS: usize = 5;
+    const MF_LIMIT: usize = 12;
+    const MAX_OFFSET: usize = 65535;
+    const HASH_LOG: usize = 12;
+
+    fn read32(a: &[u8], i: usize) -> u32 {
+        (a[i] as u32) 
+            | ((a[i + 1] as u32) << 8) 
+            | ((a[i + 2] as u32) << 16) 
+            | ((a[i + 3] as u32) << 24)
+    }
+
+    fn hash(x: u32) -> usize {
+        (x.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+    }
+
+    fn putLength(out: &mut Vec<u8>, len: usize) {
+        let mut len = len;
+        while len >= 255 {
+            out.push(255);
+            len = len - 255;
+        }
+        out.push(len as u8);
+    }
+
+    fn getLength(a: &[u8], i: &mut usize, len: usize) -> Option<usize> {
+        let mut len = len;
+        loop {
+            if *i >= a.len() {
+                return None;
+            }
+            let b = a[*i] as usize;
+            *i = *i + 1;
+            len = len + b;
+            if b != 255 {
+                return Some(len);
+            }
+        }
+    }
+
+    fn putSequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize,usize)>) {
+        let litLen = literals.len();
+        let matchLen = match m {
+            Some((_, len)) => len - MIN_MATCH,
+            None => 0,
+        };
+        let token = 
+            ((if litLen >= 15 { 15 } else { litLen }) << 4) 
+            | (if matchLen >= 15 { 15 } else { matchLen });
+        out.push(token as u8);
+        if litLen >= 15 {
+            putLength(out, litLen - 15);
+        }
+        out.push_all(literals);
+        match m {
+            Some((offset, _)) => {
+                out.push((offset & 0xff) as u8);
+                out.push((offset >> 8) as u8);
+                if matchLen >= 15 {
+                    putLength(out, matchLen - 15);
+                }
+            },
+            None => {
+            },
+        }
+    }
+
+    pub fn compress(a: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(a.len() / 2 + 16);
+        let mut anchor = 0;
+        if a.len() > MF_LIMIT {
+            // positions plus one, so zero can mean empty
+            let mut table = vec![0usize; 1 << HASH_LOG];
+            let limit = a.len() - MF_LIMIT;
+            let matchLimit = a.len() - LAST_LITERALS;
+            let mut i = 0;
+            while i < limit {
+                let x = read32(a, i);
+                let h = hash(x);
+                let cand = table[h];
+                table[h] = i + 1;
+                if cand > 0 && (i - (cand - 1)) <= MAX_OFFSET && read32(a, cand - 1) == x {
+                    let c = cand - 1;
+                    let mut len = MIN_MATCH;
+                    while i + len < matchLimit && a[c + len] == a[i + len] {
+                        len = len + 1;
+                    }
+                    putSequence(&mut out, &a[anchor .. i], Some((i - c, len)));
+                    i = i + len;
+                    anchor = i;
+                } else {
+                    i = i + 1;
+                }
+            }
+        }
+        putSequence(&mut out, &a[anchor ..], None);
+        out
+    }
+
+    // returns None unless a is valid and decompresses to exactly len bytes
+    pub fn decompress(a: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        let mut i = 0;
+        loop {
+            if i >= a.len() {
+                return None;
+            }
+            let token = a[i] as usize;
+            i = i + 1;
+            let mut litLen = token >> 4;
+            if litLen == 15 {
+                match getLength(a, &mut i, litLen) {
+                    Some(n) => litLen = n,
+                    None => return None,
+                }
+            }
+            if litLen > a.len() - i || litLen > len - out.len() {
+                return None;
+            }
+            out.push_all(&a[i .. i + litLen]);
+            i = i + litLen;
+            if i == a.len() {
+                // the last sequence has no match
+                break;
+            }
+
+            if i + 2 > a.len() {
+                return None;
+            }
+            let offset = (a[i] as usize) | ((a[i + 1] as usize) << 8);
+            i = i + 2;
+            let mut matchLen = token & 15;
+            if matchLen == 15 {
+                match getLength(a, &mut i, matchLen) {
+                    Some(n) => matchLen = n,
+                    None => return None,
+                }
+            }
+            let matchLen = matchLen + MIN_MATCH;
+            if offset == 0 || offset > out.len() || matchLen > len - out.len() {
+                return None;
+            }
+            // the match may overlap the bytes it is producing,
+            // so this has to go one byte at a time
+            let start = out.len() - offset;
+            for k in 0 .. matchLen {
+                let b = out[start + k];
+                out.push(b);
+            }
+        }
+        if out.len() == len {
+            Some(out)
+        } else {
+            None
+        }
+    }
 }
 
 mod bcmp {
@@ -624,7 +1564,12 @@ mod bcmp {
 
 struct PageBuilder {
     cur : usize,
+    // the checksum goes at the very end of a page, after the bytes
+    // reserved for the page transform, if any.  for a buffer which
+    // is not a page, there is no checksum, and end is buf.len().
+    end : usize,
     buf : Box<[u8]>,
+    transform : Option<&'static IPageTransform>,
 }
 
 // TODO bundling cur with the buf almost seems sad, because there are
@@ -633,14 +1578,45 @@ struct PageBuilder {
 impl PageBuilder {
     fn new(pgsz : usize) -> PageBuilder { 
         let ba = vec![0;pgsz as usize].into_boxed_slice();
-        PageBuilder { cur: 0, buf:ba } 
+        PageBuilder { cur: 0, end: pgsz, buf:ba, transform: None } 
     }
 
-    fn Reset(&mut self) {
+    fn new_page(pgsz : usize, transform: Option<&'static IPageTransform>) -> PageBuilder { 
+        let ba = vec![0;pgsz as usize].into_boxed_slice();
+        let end = pgsz - PAGE_CHECKSUM_SIZE - reservedBytes(transform);
+        PageBuilder { cur: 0, end: end, buf:ba, transform: transform } 
+    }
+
+    fn Reset(&mut self) {
         self.cur = 0;
     }
 
-    fn Write(&self, strm: &mut Write) -> io::Result<()> {
+    fn Write<W>(&mut self, strm: &mut W) -> io::Result<()> where W : Write+Seek {
+        fn putChecksum(buf: &mut [u8]) {
+            let at = buf.len() - PAGE_CHECKSUM_SIZE;
+            let crc = crc32::checksum(&buf[0 .. at]);
+            misc::bytes::copy_into(&endian::u32_to_bytes_be(crc), &mut buf[at .. at + SIZE_32]);
+        }
+
+        if self.end < self.buf.len() {
+            match self.transform {
+                Some(t) => {
+                    // the page number is wherever the page is going.
+                    // the builder keeps its plain bytes, since pages
+                    // sometimes get fixed up and written again.
+                    let pos = try!(strm.seek(SeekFrom::Current(0)));
+                    let page = (pos / self.buf.len() as u64) as PageNum + 1;
+                    let mut a = self.buf.clone();
+                    let at = a.len() - PAGE_CHECKSUM_SIZE;
+                    t.Encode(page, &mut a[0 .. at]);
+                    putChecksum(&mut a);
+                    return strm.write_all(&*a);
+                },
+                None => {
+                    putChecksum(&mut self.buf);
+                },
+            }
+        }
         strm.write_all(&*self.buf)
     }
 
@@ -659,7 +1635,7 @@ impl PageBuilder {
     }
 
     fn Available(&self) -> usize {
-        self.buf.len() - self.cur
+        self.end - self.cur
     }
 
     fn SetPageFlag(&mut self, x: u8) {
@@ -699,16 +1675,14 @@ impl PageBuilder {
     }
 
     fn SetSecondToLastInt32(&mut self, page: u32) {
-        let len = self.buf.len();
-        let at = len - 2 * SIZE_32;
+        let at = self.end - 2 * SIZE_32;
         if self.cur > at { panic!("SetSecondToLastInt32 is squashing data"); }
         // TODO just self.buf?  instead of making 4-byte slice.
         misc::bytes::copy_into(&endian::u32_to_bytes_be(page), &mut self.buf[at .. at + SIZE_32]);
     }
 
     fn SetLastInt32(&mut self, page: u32) {
-        let len = self.buf.len();
-        let at = len - 1 * SIZE_32;
+        let at = self.end - 1 * SIZE_32;
         if self.cur > at { panic!("SetLastInt32 is squashing data"); }
         // TODO just self.buf?  instead of making 4-byte slice.
         misc::bytes::copy_into(&endian::u32_to_bytes_be(page), &mut self.buf[at .. at + SIZE_32]);
@@ -732,15 +1706,141 @@ impl PageBuilder {
 
 }
 
+// a read-only mapping of the whole file, as long as it was when
+// the mapping was made.  the pages of a segment never change while
+// a cursor can see them, so slices into the mapping stay good as
+// long as the mapping does.
+struct FileMap {
+    ptr: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for FileMap {}
+unsafe impl Sync for FileMap {}
+
+impl FileMap {
+    // touching a mapping past the end of the file is a SIGBUS,
+    // not an error, so it never goes past the end.  None where
+    // there is no mmap.
+    #[cfg(unix)]
+    fn new(f: &File) -> Result<Option<FileMap>> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = try!(f.metadata()).len() as usize;
+        if len == 0 {
+            return Err(Error::InvalidPageNumber);
+        }
+        let p = unsafe {
+            libc::mmap(std::ptr::null_mut(), len as libc::size_t, libc::PROT_READ, libc::MAP_SHARED, f.as_raw_fd(), 0)
+        };
+        if p == libc::MAP_FAILED {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        Ok(Some(FileMap { ptr: p as *const u8, len: len }))
+    }
+
+    #[cfg(not(unix))]
+    fn new(_f: &File) -> Result<Option<FileMap>> {
+        Ok(None)
+    }
+
+    fn get_slice(&self, off: usize, len: usize) -> &[u8] {
+        assert!(off + len <= self.len);
+        unsafe { std::slice::from_raw_parts(self.ptr.offset(off as isize), len) }
+    }
+}
+
+impl Drop for FileMap {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len as libc::size_t);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {
+    }
+}
+
+// the bytes of a page, which are either ours or somebody's page
+// in a FileMap.  writing to a mapped page copies it first.
+enum PageBytes {
+    Owned(Box<[u8]>),
+    Mapped(Arc<FileMap>, usize, usize),
+}
+
+impl std::ops::Deref for PageBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            PageBytes::Owned(ref a) => a,
+            PageBytes::Mapped(ref m, off, len) => m.get_slice(off, len),
+        }
+    }
+}
+
+impl std::ops::DerefMut for PageBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let a = match *self {
+            PageBytes::Owned(ref mut a) => return a,
+            PageBytes::Mapped(ref m, off, len) => {
+                let mut a = vec![0; len].into_boxed_slice();
+                a.clone_from_slice(m.get_slice(off, len));
+                a
+            },
+        };
+        *self = PageBytes::Owned(a);
+        match *self {
+            PageBytes::Owned(ref mut a) => a,
+            PageBytes::Mapped(..) => unreachable!(),
+        }
+    }
+}
+
 // TODO this struct should just go away.  just use the buf.
 struct PageBuffer {
-    buf : Box<[u8]>,
+    // same as end in PageBuilder
+    end : usize,
+    buf : PageBytes,
+    transform : Option<&'static IPageTransform>,
 }
 
 impl PageBuffer {
     fn new(pgsz: usize) -> PageBuffer { 
         let ba = vec![0;pgsz as usize].into_boxed_slice();
-        PageBuffer { buf:ba } 
+        PageBuffer { end: pgsz, buf:PageBytes::Owned(ba), transform: None } 
+    }
+
+    fn new_page(pgsz: usize, transform: Option<&'static IPageTransform>) -> PageBuffer { 
+        let ba = vec![0;pgsz as usize].into_boxed_slice();
+        let end = pgsz - PAGE_CHECKSUM_SIZE - reservedBytes(transform);
+        PageBuffer { end: end, buf:PageBytes::Owned(ba), transform: transform } 
+    }
+
+    fn HasChecksum(&self) -> bool {
+        self.end < self.buf.len()
+    }
+
+    fn VerifyChecksum(&self) -> bool {
+        if self.HasChecksum() {
+            let at = self.buf.len() - PAGE_CHECKSUM_SIZE;
+            crc32::checksum(&self.buf[0 .. at]) == self.GetInt32At(at)
+        } else {
+            true
+        }
+    }
+
+    // undoes the page transform, after the checksum has been verified
+    fn Decode(&mut self, page: PageNum) -> bool {
+        match self.transform {
+            Some(t) => {
+                let at = self.buf.len() - PAGE_CHECKSUM_SIZE;
+                t.Decode(page, &mut self.buf[0 .. at])
+            },
+            None => true,
+        }
     }
 
     fn PageSize(&self) -> usize {
@@ -751,8 +1851,9 @@ impl PageBuffer {
         misc::io::read_fully(strm, &mut self.buf)
     }
 
+    // reads len bytes into the buffer, starting at off
     fn ReadPart(&mut self, strm: &mut Read, off: usize, len: usize) -> io::Result<usize> {
-        misc::io::read_fully(strm, &mut self.buf[off .. len-off])
+        misc::io::read_fully(strm, &mut self.buf[off .. off + len])
     }
 
     #[cfg(remove_me)]
@@ -790,14 +1891,12 @@ impl PageBuffer {
     }
 
     fn GetSecondToLastInt32(&self) -> u32 {
-        let len = self.buf.len();
-        let at = len - 2 * SIZE_32;
+        let at = self.end - 2 * SIZE_32;
         self.GetInt32At(at)
     }
 
     fn GetLastInt32(&self) -> u32 {
-        let len = self.buf.len();
-        let at = len - 1 * SIZE_32;
+        let at = self.end - 1 * SIZE_32;
         self.GetInt32At(at)
     }
 
@@ -829,406 +1928,1140 @@ impl PageBuffer {
 
 }
 
-#[derive(PartialEq,Copy,Clone)]
-enum Direction {
-    FORWARD = 0,
-    BACKWARD = 1,
-    WANDERING = 2,
+// the memtable is the newest layer of the database.  it is the
+// only one which is mutable.  a value of None is a tombstone.
+//
+// a key in the memtable, in the order of the comparator
+#[derive(Clone)]
+struct MemtableKey {
+    k: Box<[u8]>,
+    cmp: Option<&'static IComparator>,
 }
 
-struct MultiCursor<'a> { 
-    subcursors: Box<[SegmentCursor<'a>]>, 
-    sorted: Box<[(usize,Option<Ordering>)]>,
-    cur: Option<usize>, 
-    dir: Direction,
+impl MemtableKey {
+    fn new(cmp: Option<&'static IComparator>, k: Box<[u8]>) -> MemtableKey {
+        MemtableKey {
+            k: k,
+            cmp: cmp,
+        }
+    }
 }
 
-impl<'a> MultiCursor<'a> {
-    fn sort(&mut self, want_max: bool) -> Result<()> {
-        if self.subcursors.is_empty() {
-            return Ok(())
-        }
+impl PartialEq for MemtableKey {
+    fn eq(&self, other: &MemtableKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
 
-        // TODO this memory allocation is expensive.
+impl Eq for MemtableKey {
+}
 
-        // get a KeyRef for all the cursors
-        let mut ka = Vec::with_capacity(self.subcursors.len());
-        for c in self.subcursors.iter() {
-            if c.IsValid() {
-                ka.push(Some(try!(c.KeyRef())));
-            } else {
-                ka.push(None);
-            }
-        }
+impl PartialOrd for MemtableKey {
+    fn partial_cmp(&self, other: &MemtableKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        // TODO consider converting ka to a boxed slice here?
+impl Ord for MemtableKey {
+    fn cmp(&self, other: &MemtableKey) -> Ordering {
+        compareKeys(self.cmp, &self.k, &other.k)
+    }
+}
 
-        // init the orderings to None.
-        // the invalid cursors will stay that way.
-        for i in 0 .. self.sorted.len() {
-            self.sorted[i].1 = None;
-        }
+type MemtablePairs = BTreeMap<MemtableKey, Option<Box<[u8]>>>;
 
-        for i in 1 .. self.sorted.len() {
-            let mut j = i;
-            while j > 0 {
-                let nj = self.sorted[j].0;
-                let nprev = self.sorted[j - 1].0;
-                match (&ka[nj], &ka[nprev]) {
-                    (&Some(ref kj), &Some(ref kprev)) => {
-                        let c = {
-                            if want_max {
-                                KeyRef::cmp(kprev, kj)
-                            } else {
-                                KeyRef::cmp(kj, kprev)
-                            }
-                        };
-                        match c {
-                            Ordering::Greater => {
-                                self.sorted[j].1 = Some(Ordering::Greater);
-                                break;
-                            },
-                            Ordering::Equal => {
-                                match nj.cmp(&nprev) {
-                                    Ordering::Equal => {
-                                        unreachable!();
-                                    },
-                                    Ordering::Greater => {
-                                        self.sorted[j].1 = Some(Ordering::Equal);
-                                        break;
-                                    },
-                                    Ordering::Less => {
-                                        self.sorted[j - 1].1 = Some(Ordering::Equal);
-                                        // keep going
-                                    },
-                                }
-                            },
-                            Ordering::Less => {
-                                // keep going
-                                self.sorted[j - 1].1 = Some(Ordering::Greater);
-                            },
-                        }
-                    },
-                    (&Some(_), &None) => {
-                        // keep going
-                    },
-                    (&None, &Some(_)) => {
-                        break;
-                    },
-                    (&None, &None) => {
-                        match nj.cmp(&nprev) {
-                            Ordering::Equal => {
-                                unreachable!();
-                            },
-                            Ordering::Greater => {
-                                break;
-                            },
-                            Ordering::Less => {
-                                // keep going
-                            },
-                        }
-                    }
-                };
-                self.sorted.swap(j, j - 1);
-                j = j - 1;
-            }
-        }
+// the pairs of one write batch, in no particular order
+type MemtableBatch = Vec<(Box<[u8]>, Option<Box<[u8]>>)>;
 
-        // fix the first one
-        if self.sorted.len() > 0 {
-            let n = self.sorted[0].0;
-            if ka[n].is_some() {
-                self.sorted[0].1 = Some(Ordering::Equal);
-            }
-        }
+// the pairs and range deletes in a layer are in an Arc so that a
+// cursor can hold on to a snapshot of them.  a layer which a cursor
+// has is never changed.
+#[derive(Clone)]
+struct MemtableLayer {
+    pairs: Arc<MemtablePairs>,
+    // range deletes.  a pair written before a range delete which
+    // covers it is removed from the pairs, so these ranges only
+    // hide pairs in older layers and in the segments.
+    graveyard: Arc<Graveyard>,
+}
 
-        /*
-        println!("{:?} : {}", self.sorted, if want_max { "backward" } else {"forward"} );
-        for i in 0 .. self.sorted.len() {
-            let (n, ord) = self.sorted[i];
-            println!("    {:?}", ka[n]);
+impl MemtableLayer {
+    fn new() -> MemtableLayer {
+        MemtableLayer {
+            pairs: Arc::new(BTreeMap::new()),
+            graveyard: Arc::new(Graveyard::new()),
         }
-        */
-        Ok(())
     }
 
-    fn sorted_first(&self) -> Option<usize> {
-        let n = self.sorted[0].0;
-        if self.sorted[0].1.is_some() {
-            Some(n)
-        } else {
-            None
-        }
+    fn is_empty(&self) -> bool {
+        self.pairs.is_empty() && self.graveyard.is_empty()
     }
+}
 
-    fn findMin(&mut self) -> Result<Option<usize>> {
-        self.dir = Direction::FORWARD;
-        if self.subcursors.is_empty() {
-            Ok(None)
-        } else {
-            try!(self.sort(false));
-            Ok(self.sorted_first())
+// writes only go into the first layer.  when a cursor still has
+// that one, a new layer goes in front of it instead, so a write
+// never has to copy the memtable.  the layers are merged into one
+// when there get to be too many, and before a flush.
+struct Memtable {
+    // newest first
+    layers: Vec<MemtableLayer>,
+    cmp: Option<&'static IComparator>,
+    // approximate number of bytes used by the pairs and ranges
+    size: usize,
+}
+
+impl Memtable {
+    const MAX_LAYERS: usize = 8;
+
+    fn new(cmp: Option<&'static IComparator>) -> Memtable {
+        Memtable {
+            layers: Vec::new(),
+            cmp: cmp,
+            size: 0,
         }
     }
 
-    fn findMax(&mut self) -> Result<Option<usize>> {
-        self.dir = Direction::BACKWARD; 
-        if self.subcursors.is_empty() {
-            Ok(None)
-        } else {
-            try!(self.sort(true));
-            Ok(self.sorted_first())
-        }
+    fn pair_size(klen: usize, v: &Option<Box<[u8]>>) -> usize {
+        // TODO the 16 is a rough guess at the per-pair overhead
+        let vlen = match *v {
+            Some(ref a) => a.len(),
+            None => 0,
+        };
+        klen + vlen + 16
     }
 
-    fn Create(subs: Vec<SegmentCursor>) -> MultiCursor {
-        let s = subs.into_boxed_slice();
-        let mut sorted = Vec::with_capacity(s.len());
-        for i in 0 .. s.len() {
-            sorted.push((i, None));
-        }
-        MultiCursor { 
-            subcursors: s, 
-            sorted: sorted.into_boxed_slice(), 
-            cur: None, 
-            dir: Direction::WANDERING,
+    fn is_empty(&self) -> bool {
+        self.layers.iter().all(|layer| layer.is_empty())
+    }
+
+    // the layer which writes go into
+    fn top(&mut self) -> &mut MemtableLayer {
+        let shared = match self.layers.first_mut() {
+            Some(layer) => Arc::get_mut(&mut layer.pairs).is_none() || Arc::get_mut(&mut layer.graveyard).is_none(),
+            None => true,
+        };
+        if shared {
+            if self.layers.len() >= Self::MAX_LAYERS {
+                self.collapse();
+            }
+            self.layers.insert(0, MemtableLayer::new());
         }
+        &mut self.layers[0]
     }
 
-}
+    fn insert(&mut self, k: Box<[u8]>, v: Option<Box<[u8]>>) {
+        let klen = k.len();
+        let newSize = Self::pair_size(klen, &v);
+        let k = MemtableKey::new(self.cmp, k);
+        // the same key in an older layer stays counted until the
+        // layers are merged
+        let oldSize = match Arc::make_mut(&mut self.top().pairs).insert(k, v) {
+            Some(old) => Self::pair_size(klen, &old),
+            None => 0,
+        };
+        self.size = self.size + newSize - oldSize;
+    }
 
-impl<'a> ICursor<'a> for MultiCursor<'a> {
-    fn IsValid(&self) -> bool {
-        match self.cur {
-            Some(i) => self.subcursors[i].IsValid(),
-            None => false
+    fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        let c = self.cmp;
+        if compareKeys(c, start, end) != Ordering::Less {
+            return;
         }
+        let first = MemtableKey::new(c, start.to_vec().into_boxed_slice());
+        let last = MemtableKey::new(c, end.to_vec().into_boxed_slice());
+        let mut freed = 0;
+        {
+            let top = self.top();
+            let pairs = Arc::make_mut(&mut top.pairs);
+            let doomed: Vec<MemtableKey> = pairs.range(Bound::Included(&first), Bound::Excluded(&last)).map(|(k,_)| k.clone()).collect();
+            for k in doomed {
+                match pairs.remove(&k) {
+                    Some(v) => freed = freed + Self::pair_size(k.k.len(), &v),
+                    None => (),
+                }
+            }
+            Arc::make_mut(&mut top.graveyard).add(c, start, end);
+        }
+        self.size = self.size - freed + Graveyard::range_size(start, end);
     }
 
-    fn First(&mut self) -> Result<()> {
-        for i in 0 .. self.subcursors.len() {
-            try!(self.subcursors[i].First());
+    // replaces all the layers with one new one.  cursors may still
+    // have the old ones, so those are left as they are.
+    fn collapse(&mut self) {
+        if self.layers.len() < 2 {
+            return;
         }
-        self.cur = try!(self.findMin());
-        Ok(())
+        let c = self.cmp;
+        let mut pairs = BTreeMap::new();
+        let mut graveyard = Graveyard::new();
+        for layer in self.layers.iter().rev() {
+            if !layer.graveyard.is_empty() {
+                let buried: Vec<MemtableKey> = pairs.keys().filter(|k: &&MemtableKey| layer.graveyard.covers(c, &KeyRef::Array(&k.k))).map(|k| k.clone()).collect();
+                for k in buried {
+                    pairs.remove(&k);
+                }
+                graveyard.add_all(c, &layer.graveyard);
+            }
+            for (k,v) in layer.pairs.iter() {
+                pairs.insert(k.clone(), v.clone());
+            }
+        }
+        let mut size = 0;
+        for (k,v) in pairs.iter() {
+            size = size + Self::pair_size(k.k.len(), v);
+        }
+        for &(ref start, ref end) in graveyard.ranges.iter() {
+            size = size + Graveyard::range_size(start, end);
+        }
+        self.size = size;
+        self.layers = vec![MemtableLayer { pairs: Arc::new(pairs), graveyard: Arc::new(graveyard) }];
     }
 
-    fn Last(&mut self) -> Result<()> {
-        for i in 0 .. self.subcursors.len() {
-            try!(self.subcursors[i].Last());
-        }
-        self.cur = try!(self.findMax());
-        Ok(())
+    fn clear(&mut self) {
+        self.layers.clear();
+        self.size = 0;
     }
+}
 
-    fn KeyRef(&'a self) -> Result<KeyRef<'a>> {
-        match self.cur {
-            None => Err(Error::CursorNotValid),
-            Some(icur) => self.subcursors[icur].KeyRef(),
-        }
+// the write-ahead log holds the writes which are in the memtable
+// but which have not yet been flushed out to a segment.  it lives
+// in a separate file next to the database file.  each write batch
+// is appended as one record:
+//
+//     length of the payload (u32)
+//     crc32 of the payload (u32)
+//     the payload:
+//         number of pairs (varint)
+//         for each pair:
+//             flags (FLAG_TOMBSTONE or 0)
+//             key length (varint), key
+//             value length (varint), value (omitted for a tombstone)
+//
+// a range delete is a record with one pair, flagged with
+// FLAG_RANGE_TOMBSTONE, whose key and value are the start and
+// end of the range.
+//
+// before a flush commits the memtable, it appends a commit marker,
+// a record with one pair, flagged with FLAG_COMMIT_MARKER, which
+// holds (as a varint) the change counter the commit will produce.
+// if the header has reached that counter, everything before the
+// marker is already in a segment, even if we crashed before the
+// log could be truncated.  if it has not, the commit never
+// happened, and the marker itself means nothing.
+//
+// with a page transform, the payload goes through the transform
+// as if it were a page, and the top bit of the length is set.
+// what gets written in place of the payload (and checksummed) is
+//
+//     a record number (u32), given to the transform as the page
+//     the transformed payload, followed by the reserved bytes
+//
+// the record number is stored because replay may move records
+// around.  a log written before records were transformed has no
+// such records, and its plain ones are still read.
+//
+// a record which is incomplete or which fails its checksum is
+// assumed to be the result of a crash during the append.  it
+// (and anything after it) is discarded during replay.  a record
+// which passes its checksum but cannot be decoded is an error.
+struct WriteAheadLog {
+    path: String,
+    fs: File,
+    transform: Option<&'static IPageTransform>,
+    nextRecord: PageNum,
+    syncs: u64,
+}
+
+enum LogRecord {
+    Batch(MemtableBatch),
+    DeleteRange(Box<[u8]>, Box<[u8]>),
+    Flushed(u64),
+}
+
+impl WriteAheadLog {
+    const RECORD_HEADER_SIZE: usize = 2 * SIZE_32;
+    const TRANSFORMED: u32 = 0x80000000;
+
+    fn path_for(dbPath: &str) -> String {
+        format!("{}-wal", dbPath)
     }
 
-    fn ValueRef(&'a self) -> Result<ValueRef<'a>> {
-        match self.cur {
-            None => Err(Error::CursorNotValid),
-            Some(icur) => self.subcursors[icur].ValueRef(),
-        }
+    fn open(dbPath: &str, transform: Option<&'static IPageTransform>) -> Result<WriteAheadLog> {
+        let path = Self::path_for(dbPath);
+        let f = try!(Self::open_file(&path));
+        Ok(WriteAheadLog { path: path, fs: f, transform: transform, nextRecord: 0, syncs: 0 })
     }
 
-    fn KeyCompare(&self, k: &KeyRef) -> Result<Ordering> {
-        match self.cur {
-            None => Err(Error::CursorNotValid),
-            Some(icur) => self.subcursors[icur].KeyCompare(k),
+    fn open_file(path: &str) -> Result<File> {
+        let f = try!(OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path));
+        Ok(f)
+    }
+
+    fn record(&mut self, payload: &PageBuilder) -> PageBuilder {
+        let payload = payload.Buffer();
+        match self.transform {
+            Some(t) => {
+                let n = self.nextRecord;
+                self.nextRecord = self.nextRecord.wrapping_add(1);
+                let mut a = vec![0; SIZE_32 + payload.len() + t.ReservedBytes()].into_boxed_slice();
+                misc::bytes::copy_into(&endian::u32_to_bytes_be(n), &mut a[0 .. SIZE_32]);
+                misc::bytes::copy_into(payload, &mut a[SIZE_32 .. SIZE_32 + payload.len()]);
+                t.Encode(n, &mut a[SIZE_32 ..]);
+                let mut rec = PageBuilder::new(Self::RECORD_HEADER_SIZE + a.len());
+                rec.PutInt32((a.len() as u32) | Self::TRANSFORMED);
+                rec.PutInt32(crc32::checksum(&a));
+                rec.PutArray(&a);
+                rec
+            },
+            None => {
+                let mut rec = PageBuilder::new(Self::RECORD_HEADER_SIZE + payload.len());
+                rec.PutInt32(payload.len() as u32);
+                rec.PutInt32(crc32::checksum(payload));
+                rec.PutArray(payload);
+                rec
+            },
         }
     }
 
-    fn ValueLength(&self) -> Result<Option<usize>> {
-        match self.cur {
-            None => Err(Error::CursorNotValid),
-            Some(icur) => self.subcursors[icur].ValueLength(),
+    fn encode(pairs: &MemtableBatch) -> PageBuilder {
+        let mut len = varint::space_needed_for(pairs.len() as u64);
+        for &(ref k, ref v) in pairs.iter() {
+            len = len + 1 + varint::space_needed_for(k.len() as u64) + k.len();
+            match *v {
+                Some(ref v) => {
+                    len = len + varint::space_needed_for(v.len() as u64) + v.len();
+                },
+                None => {
+                },
+            }
+        }
+        let mut pb = PageBuilder::new(len);
+        pb.PutVarint(pairs.len() as u64);
+        for &(ref k, ref v) in pairs.iter() {
+            match *v {
+                Some(ref v) => {
+                    pb.PutByte(0u8);
+                    pb.PutVarint(k.len() as u64);
+                    pb.PutArray(k);
+                    pb.PutVarint(v.len() as u64);
+                    pb.PutArray(v);
+                },
+                None => {
+                    pb.PutByte(ValueFlag::FLAG_TOMBSTONE);
+                    pb.PutVarint(k.len() as u64);
+                    pb.PutArray(k);
+                },
+            }
         }
+        assert!(0 == pb.Available());
+        pb
+    }
+
+    fn encode_range(start: &[u8], end: &[u8]) -> PageBuilder {
+        let len = 
+            varint::space_needed_for(1)
+            + 1
+            + varint::space_needed_for(start.len() as u64) + start.len()
+            + varint::space_needed_for(end.len() as u64) + end.len();
+        let mut pb = PageBuilder::new(len);
+        pb.PutVarint(1);
+        pb.PutByte(ValueFlag::FLAG_RANGE_TOMBSTONE);
+        pb.PutVarint(start.len() as u64);
+        pb.PutArray(start);
+        pb.PutVarint(end.len() as u64);
+        pb.PutArray(end);
+        assert!(0 == pb.Available());
+        pb
+    }
+
+    fn encode_marker(changeCounter: u64) -> PageBuilder {
+        let len = 
+            varint::space_needed_for(1)
+            + 1
+            + varint::space_needed_for(changeCounter);
+        let mut pb = PageBuilder::new(len);
+        pb.PutVarint(1);
+        pb.PutByte(ValueFlag::FLAG_COMMIT_MARKER);
+        pb.PutVarint(changeCounter);
+        assert!(0 == pb.Available());
+        pb
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        try!(self.fs.sync_all());
+        self.syncs = self.syncs + 1;
+        Ok(())
     }
 
-    fn Next(&mut self) -> Result<()> {
-        match self.cur {
-            None => Err(Error::CursorNotValid),
-            Some(icur) => {
-                // we need to fix every cursor to point to its min
-                // value > icur.
-
-                // if perf didn't matter, this would be simple.
-                // call Next on icur.  and call Seek(GE) (and maybe Next)
-                // on every other cursor.
+    fn write(&mut self, pb: &mut PageBuilder, sync: bool) -> Result<()> {
+        try!(pb.Write(&mut self.fs));
+        if sync {
+            try!(self.fs.sync_data());
+            self.syncs = self.syncs + 1;
+        }
+        Ok(())
+    }
 
-                // but there are several cases where we can do a lot
-                // less work than a Seek.  And we have the information
-                // to identify those cases.  So, this function is
-                // pretty complicated, but it's fast.
+    fn append(&mut self, pairs: &MemtableBatch, sync: bool) -> Result<()> {
+        let mut pb = self.record(&Self::encode(pairs));
+        self.write(&mut pb, sync)
+    }
 
-                // --------
+    fn append_range(&mut self, start: &[u8], end: &[u8], sync: bool) -> Result<()> {
+        let mut pb = self.record(&Self::encode_range(start, end));
+        self.write(&mut pb, sync)
+    }
 
-                // the current cursor (icur) is easy.  it just needs Next().
-                // we'll do it last, so we can use it for comparisons.
-                // for now we deal with all the others.
+    // returns the length of the log before the marker, so that
+    // the marker can be taken back if the commit fails.
+    fn append_marker(&mut self, changeCounter: u64, sync: bool) -> Result<u64> {
+        let len = try!(self.fs.metadata()).len();
+        let mut pb = self.record(&Self::encode_marker(changeCounter));
+        try!(self.write(&mut pb, sync));
+        Ok(len)
+    }
 
-                // the current direction of the multicursor tells us
-                // something about the state of all the others.
+    fn take_back_marker(&mut self, len: u64) -> Result<()> {
+        try!(self.fs.set_len(len));
+        Ok(())
+    }
 
-                if (self.dir == Direction::FORWARD) {
-                    // this is the happy case.  each cursor is at most
-                    // one step away.
+    // returns the complete records in the log which have not been
+    // committed as of the given change counter, oldest first.
+    // commit markers are not returned.
+    fn replay(&mut self, changeCounter: u64) -> Result<Vec<LogRecord>> {
+        // a record which passed its checksum was written whole, so
+        // anything wrong inside it is corruption, not a torn append.
+        fn malformed() -> Error {
+            Error::CorruptFile("malformed record in the write-ahead log")
+        }
 
-                    // direction is FORWARD, so we know that every valid cursor
-                    // is pointing at a key which is either == to icur, or
-                    // it is already the min key > icur.
+        fn getByte(rec: &[u8], cur: &mut usize) -> Result<u8> {
+            if *cur >= rec.len() {
+                return Err(malformed());
+            }
+            let b = rec[*cur];
+            *cur = *cur + 1;
+            Ok(b)
+        }
 
-                    assert!(icur == self.sorted[0].0);
-                    // immediately after that, there may (or may not be) some
-                    // entries which were Ordering:Equal to cur.  call Next on
-                    // each of these.
+        fn getVarint(rec: &[u8], cur: &mut usize) -> Result<u64> {
+            match readVarint(rec, cur) {
+                Some(v) => Ok(v),
+                None => Err(malformed()),
+            }
+        }
 
-                    for i in 1 .. self.sorted.len() {
-                        //println!("sorted[{}] : {:?}", i, self.sorted[i]);
-                        let (n,c) = self.sorted[i];
-                        match c {
-                            None => {
-                                break;
-                            },
-                            Some(c) => {
-                                if c == Ordering::Equal {
-                                    try!(self.subcursors[n].Next());
-                                } else {
-                                    break;
-                                }
-                            },
-                        }
+        fn getArray(rec: &[u8], cur: &mut usize) -> Result<Box<[u8]>> {
+            let len = try!(getVarint(rec, cur));
+            if len > (rec.len() - *cur) as u64 {
+                return Err(malformed());
+            }
+            let len = len as usize;
+            let a = rec[*cur .. *cur + len].to_vec().into_boxed_slice();
+            *cur = *cur + len;
+            Ok(a)
+        }
+
+        fn decode(rec: &[u8]) -> Result<LogRecord> {
+            let mut cur = 0;
+            let count = try!(getVarint(rec, &mut cur));
+            // every pair takes at least two bytes
+            if count > (rec.len() / 2) as u64 {
+                return Err(malformed());
+            }
+            let count = count as usize;
+            let mut pairs = Vec::with_capacity(count);
+            for _ in 0 .. count {
+                let flags = try!(getByte(rec, &mut cur));
+                if 0 != (flags & ValueFlag::FLAG_COMMIT_MARKER) {
+                    let c = try!(getVarint(rec, &mut cur));
+                    if count != 1 || cur != rec.len() {
+                        return Err(malformed());
                     }
-
+                    return Ok(LogRecord::Flushed(c));
+                }
+                let k = try!(getArray(rec, &mut cur));
+                if 0 != (flags & ValueFlag::FLAG_TOMBSTONE) {
+                    pairs.push((k, None));
                 } else {
-                    // TODO consider simplifying all the stuff below.
-                    // all this complexity may not be worth it.
-
-                    fn half(dir: Direction, ki: &KeyRef, subs: &mut [SegmentCursor]) -> Result<()> {
-                        match dir {
-                            Direction::FORWARD => {
-                                unreachable!();
-                            },
-                            Direction::BACKWARD => {
-                                // this case isn't too bad.  each cursor is either
-                                // one step away or two.
-                                
-                                // every other cursor is either == icur or it is the
-                                // max value < icur.
-
-                                for csr in subs {
-                                    if csr.IsValid() {
-                                        let cmp = {
-                                            let k = try!(csr.KeyRef());
-                                            let cmp = KeyRef::cmp(&k, ki);
-                                            cmp
-                                        };
-                                        match cmp {
-                                            Ordering::Less => {
-                                                try!(csr.Next());
-                                                // we moved one step.  let's see if we need to move one more.
-                                                if csr.IsValid() {
-                                                    let cmp = {
-                                                        let k = try!(csr.KeyRef());
-                                                        let cmp = KeyRef::cmp(&k, ki);
-                                                        cmp
-                                                    };
-                                                    match cmp {
-                                                        Ordering::Less => {
-                                                            // should never happen.  we should not have
-                                                            // been more than one step away from icur.
-                                                            unreachable!();
-                                                        },
-                                                        Ordering::Greater => {
-                                                            // done
-                                                        },
-                                                        Ordering::Equal => {
-                                                            // and one more step
-                                                            try!(csr.Next());
-                                                        },
-                                                    }
-                                                }
-                                            },
-                                            Ordering::Greater => {
-                                                // should never happen, because BACKWARD
-                                                unreachable!();
-                                            },
-                                            Ordering::Equal => {
-                                                // one step away
-                                                try!(csr.Next());
-                                            },
-                                        }
-                                    } else {
-                                        let sr = try!(csr.SeekRef(&ki, SeekOp::SEEK_GE));
-                                        if sr.is_valid_and_equal() {
-                                            try!(csr.Next());
-                                        }
-                                    }
-                                }
-
-                                Ok(())
-                            },
-                            Direction::WANDERING => {
-                                // we have no idea where all the other cursors are.
-                                // so we have to do a seek on each one.
-
-                                for j in 0 .. subs.len() {
-                                    let csr = &mut subs[j];
-                                    let sr = try!(csr.SeekRef(&ki, SeekOp::SEEK_GE));
-                                    if sr.is_valid_and_equal() {
-                                        try!(csr.Next());
-                                    }
-                                }
-                                Ok(())
-                            },
+                    let v = try!(getArray(rec, &mut cur));
+                    if 0 != (flags & ValueFlag::FLAG_RANGE_TOMBSTONE) {
+                        if count != 1 || cur != rec.len() {
+                            return Err(malformed());
                         }
+                        return Ok(LogRecord::DeleteRange(k, v));
                     }
-
-                    {
-                        let (before, middle, after) = split3(&mut *self.subcursors, icur);
-                        let icsr = &middle[0];
-                        let ki = try!(icsr.KeyRef());
-                        half(self.dir, &ki, before);
-                        half(self.dir, &ki, after);
-                    }
+                    pairs.push((k, Some(v)));
                 }
+            }
+            if cur != rec.len() {
+                return Err(malformed());
+            }
+            Ok(LogRecord::Batch(pairs))
+        }
 
-                // now the current cursor
-                try!(self.subcursors[icur].Next());
-
-                // now re-sort
-                self.cur = try!(self.findMin());
-                Ok(())
-            },
+        let len = try!(misc::io::seek_len(&mut self.fs)) as usize;
+        try!(self.fs.seek(SeekFrom::Start(0)));
+        let mut pr = PageBuffer::new(len);
+        let got = try!(pr.Read(&mut self.fs));
+        if got < len {
+            return Err(Error::CorruptFile("write-ahead log is shorter than expected"));
         }
-    }
 
-    // TODO fix Prev like Next
-    fn Prev(&mut self) -> Result<()> {
+        // each record is kept along with where it is in the log
+        let mut records = Vec::new();
+        let mut sawMarker = false;
+        let mut cur = 0;
+        while cur + Self::RECORD_HEADER_SIZE <= len {
+            let start = cur;
+            let lenFlags = pr.GetInt32(&mut cur);
+            let payloadLen = (lenFlags & !Self::TRANSFORMED) as usize;
+            let crc = pr.GetInt32(&mut cur);
+            if payloadLen > len - cur || crc != crc32::checksum(pr.get_slice(cur, payloadLen)) {
+                cur = start;
+                break;
+            }
+            let rec =
+                if 0 != (lenFlags & Self::TRANSFORMED) {
+                    let t = match self.transform {
+                        Some(t) => t,
+                        None => return Err(Error::CorruptFile("write-ahead log record needs a page transform")),
+                    };
+                    if payloadLen < SIZE_32 + t.ReservedBytes() {
+                        return Err(malformed());
+                    }
+                    let mut a = pr.get_slice(cur, payloadLen).to_vec().into_boxed_slice();
+                    let n = endian::u32_from_bytes_be(misc::bytes::extract_4(&a[0 .. SIZE_32]));
+                    if !t.Decode(n, &mut a[SIZE_32 ..]) {
+                        return Err(Error::CorruptFile("write-ahead log record fails its page transform"));
+                    }
+                    if n >= self.nextRecord {
+                        self.nextRecord = n.wrapping_add(1);
+                    }
+                    try!(decode(&a[SIZE_32 .. payloadLen - t.ReservedBytes()]))
+                } else {
+                    try!(decode(pr.get_slice(cur, payloadLen)))
+                };
+            match rec {
+                LogRecord::Flushed(c) => {
+                    sawMarker = true;
+                    if c <= changeCounter {
+                        // already in a segment
+                        records.clear();
+                    }
+                },
+                rec => {
+                    records.push((rec, start, cur + payloadLen));
+                },
+            }
+            cur = cur + payloadLen;
+        }
+
+        if sawMarker {
+            // a marker must not outlive this replay.  one whose commit
+            // never happened would seem to have happened as soon as
+            // some later commit reached its counter.
+            let keep = records.iter().map(|&(_, start, end)| (start, end)).collect::<Vec<_>>();
+            try!(self.rewrite(&pr, &keep));
+        } else if cur < len {
+            // throw away the torn record at the end
+            try!(self.fs.set_len(cur as u64));
+        }
+        Ok(records.into_iter().map(|(rec, _, _)| rec).collect())
+    }
+
+    // replaces the log with the given pieces of its old contents.
+    // the new log is written next to it and renamed over it, so a
+    // crash leaves either one or the other.
+    fn rewrite(&mut self, pr: &PageBuffer, keep: &[(usize, usize)]) -> Result<()> {
+        let tmp = format!("{}.tmp", self.path);
+        {
+            let mut f = try!(OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&tmp));
+            for &(start, end) in keep {
+                try!(f.write_all(pr.get_slice(start, end - start)));
+            }
+            try!(f.sync_all());
+            self.syncs = self.syncs + 1;
+        }
+        try!(std::fs::rename(&tmp, &self.path));
+        self.fs = try!(Self::open_file(&self.path));
+        Ok(())
+    }
+
+    // called after everything in the log has been committed as a segment
+    fn truncate(&mut self) -> Result<()> {
+        try!(self.fs.set_len(0));
+        Ok(())
+    }
+}
+
+struct SafeMemtable {
+    memtable: Memtable,
+    // None when the db is read-only
+    wal: Option<WriteAheadLog>,
+}
+
+impl SafeMemtable {
+    fn wal(&mut self) -> Result<&mut WriteAheadLog> {
+        match self.wal {
+            Some(ref mut wal) => Ok(wal),
+            None => Err(Error::ReadOnly),
+        }
+    }
+}
+
+// a cursor on one layer of the memtable
+struct MemtableCursor {
+    pairs: Arc<MemtablePairs>,
+    graveyard: Arc<Graveyard>,
+    cmp: Option<&'static IComparator>,
+    // a copy of the key the cursor is on, since the map cannot
+    // be borrowed from one call to the next
+    cur: Option<MemtableKey>,
+}
+
+impl MemtableCursor {
+    fn new(layer: &MemtableLayer, cmp: Option<&'static IComparator>) -> MemtableCursor {
+        MemtableCursor {
+            pairs: layer.pairs.clone(),
+            graveyard: layer.graveyard.clone(),
+            cmp: cmp,
+            cur: None,
+        }
+    }
+
+    fn key(&self, k: &KeyRef) -> MemtableKey {
+        MemtableKey::new(self.cmp, k.as_bytes().into_owned().into_boxed_slice())
+    }
+
+    fn value(&self) -> Result<&Option<Box<[u8]>>> {
+        match self.cur {
+            Some(ref k) => Ok(self.pairs.get(k).expect("the cursor is on a key in the map")),
+            None => Err(Error::CursorNotValid),
+        }
+    }
+}
+
+impl<'a> ICursor<'a> for MemtableCursor {
+    fn IsValid(&self) -> bool {
+        self.cur.is_some()
+    }
+
+    fn SeekRef(&mut self, k: &KeyRef, sop: SeekOp) -> Result<SeekResult> {
+        let k = self.key(k);
+        if self.pairs.contains_key(&k) {
+            self.cur = Some(k);
+            return Ok(SeekResult::Equal);
+        }
+        self.cur =
+            match sop {
+                SeekOp::SEEK_EQ => None,
+                SeekOp::SEEK_LE => self.pairs.range(Bound::Unbounded, Bound::Excluded(&k)).next_back().map(|(k,_)| k.clone()),
+                SeekOp::SEEK_GE => self.pairs.range(Bound::Excluded(&k), Bound::Unbounded).next().map(|(k,_)| k.clone()),
+            };
+        if self.cur.is_some() {
+            Ok(SeekResult::Unequal)
+        } else {
+            Ok(SeekResult::Invalid)
+        }
+    }
+
+    fn First(&mut self) -> Result<()> {
+        self.cur = self.pairs.keys().next().map(|k| k.clone());
+        Ok(())
+    }
+
+    fn Last(&mut self) -> Result<()> {
+        self.cur = self.pairs.keys().next_back().map(|k| k.clone());
+        Ok(())
+    }
+
+    fn Next(&mut self) -> Result<()> {
+        match self.cur.take() {
+            Some(k) => {
+                self.cur = self.pairs.range(Bound::Excluded(&k), Bound::Unbounded).next().map(|(k,_)| k.clone());
+                Ok(())
+            },
+            None => Err(Error::CursorNotValid),
+        }
+    }
+
+    fn Prev(&mut self) -> Result<()> {
+        match self.cur.take() {
+            Some(k) => {
+                self.cur = self.pairs.range(Bound::Unbounded, Bound::Excluded(&k)).next_back().map(|(k,_)| k.clone());
+                Ok(())
+            },
+            None => Err(Error::CursorNotValid),
+        }
+    }
+
+    fn KeyRef(&'a self) -> Result<KeyRef<'a>> {
+        match self.cur {
+            Some(ref k) => Ok(KeyRef::Array(&k.k)),
+            None => Err(Error::CursorNotValid),
+        }
+    }
+
+    fn ValueRef(&'a self) -> Result<ValueRef<'a>> {
+        match *try!(self.value()) {
+            Some(ref v) => Ok(ValueRef::Array(v)),
+            None => Ok(ValueRef::Tombstone),
+        }
+    }
+
+    fn ValueLength(&self) -> Result<Option<usize>> {
+        match *try!(self.value()) {
+            Some(ref v) => Ok(Some(v.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn KeyCompare(&self, k: &KeyRef) -> Result<Ordering> {
+        match self.cur {
+            Some(ref cur) => Ok(KeyRef::compare_by(self.cmp, &KeyRef::Array(&cur.k), k)),
+            None => Err(Error::CursorNotValid),
+        }
+    }
+}
+
+// the subcursors of a MultiCursor are usually segments, but
+// the layers of the memtable (if it is not empty) show up first.
+// this is an enum rather than a trait object to avoid dynamic
+// dispatch in the MultiCursor, which is perf sensitive.
+enum SubCursor<'a> {
+    Segment(SegmentCursor<'a>),
+    Memtable(MemtableCursor),
+}
+
+impl<'a> SubCursor<'a> {
+    // false if k is certainly not here
+    fn might_contain(&self, k: &KeyRef) -> Result<bool> {
+        match *self {
+            SubCursor::Segment(ref c) => {
+                match c.bloom {
+                    Some(ref b) => Ok(try!(c.inner.bloomFilter(c.segnum, b)).might_contain(k)),
+                    None => Ok(true),
+                }
+            },
+            SubCursor::Memtable(_) => Ok(true),
+        }
+    }
+
+    // the range tombstones which hide keys in the subcursors after this one
+    fn graveyard(&self) -> Option<&Graveyard> {
+        match *self {
+            SubCursor::Segment(ref c) => c.graveyard.as_ref().map(|g| &**g),
+            SubCursor::Memtable(ref c) => if c.graveyard.is_empty() { None } else { Some(&*c.graveyard) },
+        }
+    }
+}
+
+impl<'a> ICursor<'a> for SubCursor<'a> {
+    fn IsValid(&self) -> bool {
+        match *self {
+            SubCursor::Segment(ref c) => c.IsValid(),
+            SubCursor::Memtable(ref c) => c.IsValid(),
+        }
+    }
+
+    fn SeekRef(&mut self, k: &KeyRef, sop: SeekOp) -> Result<SeekResult> {
+        match *self {
+            SubCursor::Segment(ref mut c) => c.SeekRef(k, sop),
+            SubCursor::Memtable(ref mut c) => c.SeekRef(k, sop),
+        }
+    }
+
+    fn First(&mut self) -> Result<()> {
+        match *self {
+            SubCursor::Segment(ref mut c) => c.First(),
+            SubCursor::Memtable(ref mut c) => c.First(),
+        }
+    }
+
+    fn Last(&mut self) -> Result<()> {
+        match *self {
+            SubCursor::Segment(ref mut c) => c.Last(),
+            SubCursor::Memtable(ref mut c) => c.Last(),
+        }
+    }
+
+    fn Next(&mut self) -> Result<()> {
+        match *self {
+            SubCursor::Segment(ref mut c) => c.Next(),
+            SubCursor::Memtable(ref mut c) => c.Next(),
+        }
+    }
+
+    fn Prev(&mut self) -> Result<()> {
+        match *self {
+            SubCursor::Segment(ref mut c) => c.Prev(),
+            SubCursor::Memtable(ref mut c) => c.Prev(),
+        }
+    }
+
+    fn KeyRef(&'a self) -> Result<KeyRef<'a>> {
+        match *self {
+            SubCursor::Segment(ref c) => c.KeyRef(),
+            SubCursor::Memtable(ref c) => c.KeyRef(),
+        }
+    }
+
+    fn ValueRef(&'a self) -> Result<ValueRef<'a>> {
+        match *self {
+            SubCursor::Segment(ref c) => c.ValueRef(),
+            SubCursor::Memtable(ref c) => c.ValueRef(),
+        }
+    }
+
+    fn ValueLength(&self) -> Result<Option<usize>> {
+        match *self {
+            SubCursor::Segment(ref c) => c.ValueLength(),
+            SubCursor::Memtable(ref c) => c.ValueLength(),
+        }
+    }
+
+    fn KeyCompare(&self, k: &KeyRef) -> Result<Ordering> {
+        match *self {
+            SubCursor::Segment(ref c) => c.KeyCompare(k),
+            SubCursor::Memtable(ref c) => c.KeyCompare(k),
+        }
+    }
+}
+
+#[derive(PartialEq,Copy,Clone)]
+enum Direction {
+    FORWARD = 0,
+    BACKWARD = 1,
+    WANDERING = 2,
+}
+
+// the subcursors are in order, newest first.  when more than one
+// of them has the same key, the newest one wins.  a key which is
+// covered by a range tombstone in a newer subcursor shows up here
+// as a tombstone.
+//
+// when the direction is FORWARD, every subcursor is on its smallest
+// key >= the current one, and heap has every valid subcursor, as a
+// binary heap with the smallest key on top.  BACKWARD is the mirror
+// image.  either way, ties go to the newest subcursor, so the top
+// of the heap is the current one, and a step only has to move (and
+// sift) the subcursors which are on the current key.  after a seek
+// which stopped early, the direction is WANDERING, and nothing is
+// known about the other subcursors.
+struct MultiCursor<'a> { 
+    subcursors: Box<[SubCursor<'a>]>, 
+    heap: Vec<usize>,
+    cur: Option<usize>, 
+    dir: Direction,
+    // whether any of the subcursors has range tombstones
+    graves: bool,
+    cmp: Option<&'static IComparator>,
+}
+
+impl<'a> MultiCursor<'a> {
+    fn Create(subs: Vec<SubCursor<'a>>, cmp: Option<&'static IComparator>) -> MultiCursor<'a> {
+        let s = subs.into_boxed_slice();
+        let heap = Vec::with_capacity(s.len());
+        let graves = s.iter().any(|c| c.graveyard().is_some());
+        MultiCursor { 
+            subcursors: s, 
+            heap: heap,
+            cur: None, 
+            dir: Direction::WANDERING,
+            graves: graves,
+            cmp: cmp,
+        }
+    }
+
+    // the order of the heap, which depends on the direction:  true
+    // if subcursor a comes out before subcursor b
+    fn before(&self, a: usize, b: usize) -> Result<bool> {
+        let c = {
+            let ka = try!(self.subcursors[a].KeyRef());
+            let kb = try!(self.subcursors[b].KeyRef());
+            KeyRef::compare_by(self.cmp, &ka, &kb)
+        };
+        let c = if self.dir == Direction::BACKWARD { c.reverse() } else { c };
+        match c {
+            Ordering::Less => Ok(true),
+            Ordering::Greater => Ok(false),
+            Ordering::Equal => Ok(a < b),
+        }
+    }
+
+    fn sameKey(&self, a: usize, b: usize) -> Result<bool> {
+        let ka = try!(self.subcursors[a].KeyRef());
+        let kb = try!(self.subcursors[b].KeyRef());
+        Ok(Ordering::Equal == KeyRef::compare_by(self.cmp, &ka, &kb))
+    }
+
+    fn siftUp(&mut self, mut i: usize) -> Result<()> {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if try!(self.before(self.heap[i], self.heap[parent])) {
+                self.heap.swap(i, parent);
+                i = parent;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn siftDown(&mut self, mut i: usize) -> Result<()> {
+        let len = self.heap.len();
+        loop {
+            let left = 2 * i + 1;
+            let right = left + 1;
+            let mut first = i;
+            if left < len && try!(self.before(self.heap[left], self.heap[first])) {
+                first = left;
+            }
+            if right < len && try!(self.before(self.heap[right], self.heap[first])) {
+                first = right;
+            }
+            if first == i {
+                return Ok(());
+            }
+            self.heap.swap(i, first);
+            i = first;
+        }
+    }
+
+    fn push(&mut self, n: usize) -> Result<()> {
+        self.heap.push(n);
+        let i = self.heap.len() - 1;
+        self.siftUp(i)
+    }
+
+    fn pop(&mut self) -> Result<usize> {
+        let n = self.heap.swap_remove(0);
+        if !self.heap.is_empty() {
+            try!(self.siftDown(0));
+        }
+        Ok(n)
+    }
+
+    // rebuilds the heap from scratch, for when all the subcursors
+    // have moved
+    fn heapify(&mut self, dir: Direction) -> Result<()> {
+        self.dir = dir;
+        self.heap.clear();
+        for i in 0 .. self.subcursors.len() {
+            if self.subcursors[i].IsValid() {
+                self.heap.push(i);
+            }
+        }
+        let len = self.heap.len();
+        for i in (0 .. len / 2).rev() {
+            try!(self.siftDown(i));
+        }
+        self.cur = self.heap.first().map(|n| *n);
+        Ok(())
+    }
+
+    // one step in the direction of the heap, for every subcursor
+    // on the current key.  the current one is moved last, so that
+    // its key is still there to compare with.
+    fn step(&mut self) -> Result<()> {
+        let icur = try!(self.pop());
+        while !self.heap.is_empty() && try!(self.sameKey(self.heap[0], icur)) {
+            let n = try!(self.pop());
+            try!(self.move1(n));
+        }
+        try!(self.move1(icur));
+        self.cur = self.heap.first().map(|n| *n);
+        Ok(())
+    }
+
+    fn move1(&mut self, n: usize) -> Result<()> {
+        if self.dir == Direction::BACKWARD {
+            try!(self.subcursors[n].Prev());
+        } else {
+            try!(self.subcursors[n].Next());
+        }
+        if self.subcursors[n].IsValid() {
+            try!(self.push(n));
+        }
+        Ok(())
+    }
+
+    // true if the current key is covered by a range tombstone
+    // in a newer subcursor
+    fn isBuried(&self) -> Result<bool> {
         match self.cur {
             None => Err(Error::CursorNotValid),
             Some(icur) => {
-                let k = {
-                    let k = try!(self.subcursors[icur].KeyRef());
-                    let k = k.into_boxed_slice();
-                    let k = KeyRef::from_boxed_slice(k);
-                    k
-                };
+                if !self.graves {
+                    return Ok(false);
+                }
+                let k = try!(self.subcursors[icur].KeyRef());
+                for j in 0 .. icur {
+                    match self.subcursors[j].graveyard() {
+                        Some(g) => {
+                            if g.covers(self.cmp, &k) {
+                                return Ok(true);
+                            }
+                        },
+                        None => {
+                        },
+                    }
+                }
+                Ok(false)
+            },
+        }
+    }
+
+}
+
+impl<'a> ICursor<'a> for MultiCursor<'a> {
+    fn IsValid(&self) -> bool {
+        match self.cur {
+            Some(i) => self.subcursors[i].IsValid(),
+            None => false
+        }
+    }
+
+    fn First(&mut self) -> Result<()> {
+        for i in 0 .. self.subcursors.len() {
+            try!(self.subcursors[i].First());
+        }
+        self.heapify(Direction::FORWARD)
+    }
+
+    fn Last(&mut self) -> Result<()> {
+        for i in 0 .. self.subcursors.len() {
+            try!(self.subcursors[i].Last());
+        }
+        self.heapify(Direction::BACKWARD)
+    }
+
+    fn KeyRef(&'a self) -> Result<KeyRef<'a>> {
+        match self.cur {
+            None => Err(Error::CursorNotValid),
+            Some(icur) => self.subcursors[icur].KeyRef(),
+        }
+    }
+
+    fn ValueRef(&'a self) -> Result<ValueRef<'a>> {
+        match self.cur {
+            None => Err(Error::CursorNotValid),
+            Some(icur) => {
+                if try!(self.isBuried()) {
+                    Ok(ValueRef::Tombstone)
+                } else {
+                    self.subcursors[icur].ValueRef()
+                }
+            },
+        }
+    }
+
+    fn KeyCompare(&self, k: &KeyRef) -> Result<Ordering> {
+        match self.cur {
+            None => Err(Error::CursorNotValid),
+            Some(icur) => self.subcursors[icur].KeyCompare(k),
+        }
+    }
+
+    fn ValueLength(&self) -> Result<Option<usize>> {
+        match self.cur {
+            None => Err(Error::CursorNotValid),
+            Some(icur) => {
+                if try!(self.isBuried()) {
+                    Ok(None)
+                } else {
+                    self.subcursors[icur].ValueLength()
+                }
+            },
+        }
+    }
+
+    fn Next(&mut self) -> Result<()> {
+        let icur = match self.cur {
+            None => return Err(Error::CursorNotValid),
+            Some(icur) => icur,
+        };
+        match self.dir {
+            Direction::FORWARD => {
+                // the happy case
+                self.step()
+            },
+            Direction::BACKWARD => {
+                // every subcursor is on its biggest key <= the current
+                // one, so one step forward puts it on its smallest key
+                // > the current one.  the ones which ran off the front
+                // have nothing <= the current key.
                 for j in 0 .. self.subcursors.len() {
-                    let csr = &mut self.subcursors[j];
-                    if (self.dir != Direction::BACKWARD) && (icur != j) { 
-                        try!(csr.SeekRef(&k, SeekOp::SEEK_LE));
+                    if self.subcursors[j].IsValid() {
+                        try!(self.subcursors[j].Next());
+                    } else {
+                        try!(self.subcursors[j].First());
                     }
-                    if csr.IsValid() && (Ordering::Equal == try!(csr.KeyCompare(&k))) { 
-                        try!(csr.Prev());
+                }
+                self.heapify(Direction::FORWARD)
+            },
+            Direction::WANDERING => {
+                // we have no idea where the other subcursors are, so
+                // each one needs a seek
+                let k = KeyRef::from_boxed_slice(try!(self.subcursors[icur].KeyRef()).into_boxed_slice());
+                for j in 0 .. self.subcursors.len() {
+                    if j == icur {
+                        try!(self.subcursors[j].Next());
+                    } else {
+                        let sr = try!(self.subcursors[j].SeekRef(&k, SeekOp::SEEK_GE));
+                        if sr.is_valid_and_equal() {
+                            try!(self.subcursors[j].Next());
+                        }
                     }
                 }
-                self.cur = try!(self.findMax());
-                Ok(())
+                self.heapify(Direction::FORWARD)
+            },
+        }
+    }
+
+    fn Prev(&mut self) -> Result<()> {
+        let icur = match self.cur {
+            None => return Err(Error::CursorNotValid),
+            Some(icur) => icur,
+        };
+        match self.dir {
+            Direction::BACKWARD => {
+                self.step()
+            },
+            Direction::FORWARD => {
+                // the mirror image of Next when BACKWARD
+                for j in 0 .. self.subcursors.len() {
+                    if self.subcursors[j].IsValid() {
+                        try!(self.subcursors[j].Prev());
+                    } else {
+                        try!(self.subcursors[j].Last());
+                    }
+                }
+                self.heapify(Direction::BACKWARD)
+            },
+            Direction::WANDERING => {
+                let k = KeyRef::from_boxed_slice(try!(self.subcursors[icur].KeyRef()).into_boxed_slice());
+                for j in 0 .. self.subcursors.len() {
+                    if j == icur {
+                        try!(self.subcursors[j].Prev());
+                    } else {
+                        let sr = try!(self.subcursors[j].SeekRef(&k, SeekOp::SEEK_LE));
+                        if sr.is_valid_and_equal() {
+                            try!(self.subcursors[j].Prev());
+                        }
+                    }
+                }
+                self.heapify(Direction::BACKWARD)
             },
         }
     }
@@ -1237,36 +3070,36 @@ impl<'a> ICursor<'a> for MultiCursor<'a> {
         self.cur = None;
         self.dir = Direction::WANDERING;
         for j in 0 .. self.subcursors.len() {
+            // for an exact match, a bloom filter can save us the seek.
+            // but not with a comparator, whose idea of equal may not
+            // be the same bytes.  segments written before there was
+            // such a check may still have filters.
+            if sop == SeekOp::SEEK_EQ && self.cmp.is_none() && !try!(self.subcursors[j].might_contain(k)) {
+                continue;
+            }
             let sr = try!(self.subcursors[j].SeekRef(k, sop));
             if sr.is_valid_and_equal() { 
                 self.cur = Some(j);
                 return Ok(sr);
             }
         }
+        // every subcursor got the seek, so the heap can be built
         match sop {
             SeekOp::SEEK_GE => {
-                self.cur = try!(self.findMin());
-                match self.cur {
-                    Some(i) => {
-                        SeekResult::from_cursor(&self.subcursors[i], k)
-                    },
-                    None => {
-                        Ok(SeekResult::Invalid)
-                    },
-                }
+                try!(self.heapify(Direction::FORWARD));
             },
             SeekOp::SEEK_LE => {
-                self.cur = try!(self.findMax());
-                match self.cur {
-                    Some(i) => {
-                        SeekResult::from_cursor(&self.subcursors[i], k)
-                    },
-                    None => {
-                        Ok(SeekResult::Invalid)
-                    },
-                }
+                try!(self.heapify(Direction::BACKWARD));
             },
             SeekOp::SEEK_EQ => {
+                return Ok(SeekResult::Invalid);
+            },
+        }
+        match self.cur {
+            Some(i) => {
+                SeekResult::from_cursor(&self.subcursors[i], k)
+            },
+            None => {
                 Ok(SeekResult::Invalid)
             },
         }
@@ -1376,9 +3209,257 @@ impl<'a> ICursor<'a> for LivingCursor<'a> {
 
 }
 
+// the keys covered by a range scan.  this is implemented for the
+// ranges made with .., and for a pair of Bounds, which is how to
+// get an inclusive end.
+pub trait IKeyRange<'b> {
+    fn start(&self) -> Bound<&'b [u8]>;
+    fn end(&self) -> Bound<&'b [u8]>;
+}
+
+impl<'b> IKeyRange<'b> for std::ops::Range<&'b [u8]> {
+    fn start(&self) -> Bound<&'b [u8]> {
+        Bound::Included(self.start)
+    }
+
+    fn end(&self) -> Bound<&'b [u8]> {
+        Bound::Excluded(self.end)
+    }
+}
+
+impl<'b> IKeyRange<'b> for std::ops::RangeFrom<&'b [u8]> {
+    fn start(&self) -> Bound<&'b [u8]> {
+        Bound::Included(self.start)
+    }
+
+    fn end(&self) -> Bound<&'b [u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<'b> IKeyRange<'b> for std::ops::RangeTo<&'b [u8]> {
+    fn start(&self) -> Bound<&'b [u8]> {
+        Bound::Unbounded
+    }
+
+    fn end(&self) -> Bound<&'b [u8]> {
+        Bound::Excluded(self.end)
+    }
+}
+
+impl<'b> IKeyRange<'b> for std::ops::RangeFull {
+    fn start(&self) -> Bound<&'b [u8]> {
+        Bound::Unbounded
+    }
+
+    fn end(&self) -> Bound<&'b [u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<'b> IKeyRange<'b> for (Bound<&'b [u8]>, Bound<&'b [u8]>) {
+    fn start(&self) -> Bound<&'b [u8]> {
+        self.0
+    }
+
+    fn end(&self) -> Bound<&'b [u8]> {
+        self.1
+    }
+}
+
+fn ownBound(b: Bound<&[u8]>) -> Bound<Box<[u8]>> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.to_vec().into_boxed_slice()),
+        Bound::Excluded(k) => Bound::Excluded(k.to_vec().into_boxed_slice()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// the live pairs in a range, in order, from either end.  each end
+// seeks to where it left off, unless the cursor is still there
+// from the last step at that end, and it stops as soon as the
+// cursor goes past the bound or past the key last returned by the
+// other end.
+pub struct RangeIterator<'a> {
+    csr: LivingCursor<'a>,
+    lo: Bound<Box<[u8]>>,
+    hi: Bound<Box<[u8]>>,
+    // the last key returned from each end
+    front: Option<Box<[u8]>>,
+    back: Option<Box<[u8]>>,
+    // which of those the cursor is sitting on, if either
+    onFront: bool,
+    onBack: bool,
+    done: bool,
+}
+
+impl<'a> RangeIterator<'a> {
+    fn new(csr: LivingCursor<'a>, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> RangeIterator<'a> {
+        RangeIterator {
+            csr: csr,
+            lo: ownBound(lo),
+            hi: ownBound(hi),
+            front: None,
+            back: None,
+            onFront: false,
+            onBack: false,
+            done: false,
+        }
+    }
+
+    fn pair(&mut self) -> Result<(Box<[u8]>, Blob)> {
+        let k = try!(self.csr.KeyRef()).into_boxed_slice();
+        let v = try!(self.csr.ValueRef()).into_blob();
+        Ok((k, v))
+    }
+
+    fn stepForward(&mut self) -> Result<Option<(Box<[u8]>, Blob)>> {
+        if self.onFront {
+            try!(self.csr.Next());
+        } else {
+            let (k, inclusive) =
+                match (&self.front, &self.lo) {
+                    (&Some(ref k), _) => (Some(k), false),
+                    (&None, &Bound::Included(ref k)) => (Some(k), true),
+                    (&None, &Bound::Excluded(ref k)) => (Some(k), false),
+                    (&None, &Bound::Unbounded) => (None, true),
+                };
+            match k {
+                Some(k) => {
+                    let sr = try!(self.csr.SeekRef(&KeyRef::for_slice(k), SeekOp::SEEK_GE));
+                    if !inclusive && sr.is_valid_and_equal() {
+                        try!(self.csr.Next());
+                    }
+                },
+                None => {
+                    try!(self.csr.First());
+                },
+            }
+        }
+        self.onFront = true;
+        self.onBack = false;
+        if !self.csr.IsValid() {
+            return Ok(None);
+        }
+        let past =
+            match self.hi {
+                Bound::Included(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) == Ordering::Greater,
+                Bound::Excluded(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) != Ordering::Less,
+                Bound::Unbounded => false,
+            }
+            || match self.back {
+                Some(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) != Ordering::Less,
+                None => false,
+            };
+        if past {
+            return Ok(None);
+        }
+        let (k, v) = try!(self.pair());
+        self.front = Some(k.clone());
+        Ok(Some((k, v)))
+    }
+
+    fn stepBackward(&mut self) -> Result<Option<(Box<[u8]>, Blob)>> {
+        if self.onBack {
+            try!(self.csr.Prev());
+        } else {
+            let (k, inclusive) =
+                match (&self.back, &self.hi) {
+                    (&Some(ref k), _) => (Some(k), false),
+                    (&None, &Bound::Included(ref k)) => (Some(k), true),
+                    (&None, &Bound::Excluded(ref k)) => (Some(k), false),
+                    (&None, &Bound::Unbounded) => (None, true),
+                };
+            match k {
+                Some(k) => {
+                    let sr = try!(self.csr.SeekRef(&KeyRef::for_slice(k), SeekOp::SEEK_LE));
+                    if !inclusive && sr.is_valid_and_equal() {
+                        try!(self.csr.Prev());
+                    }
+                },
+                None => {
+                    try!(self.csr.Last());
+                },
+            }
+        }
+        self.onBack = true;
+        self.onFront = false;
+        if !self.csr.IsValid() {
+            return Ok(None);
+        }
+        let past =
+            match self.lo {
+                Bound::Included(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) == Ordering::Less,
+                Bound::Excluded(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) != Ordering::Greater,
+                Bound::Unbounded => false,
+            }
+            || match self.front {
+                Some(ref k) => try!(self.csr.KeyCompare(&KeyRef::for_slice(k))) != Ordering::Greater,
+                None => false,
+            };
+        if past {
+            return Ok(None);
+        }
+        let (k, v) = try!(self.pair());
+        self.back = Some(k.clone());
+        Ok(Some((k, v)))
+    }
+
+    // once an end runs out, or anything fails, there is nothing
+    // more from either end
+    fn finish(&mut self, r: Result<Option<(Box<[u8]>, Blob)>>) -> Option<Result<(Box<[u8]>, Blob)>> {
+        match r {
+            Ok(Some(p)) => Some(Ok(p)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for RangeIterator<'a> {
+    type Item = Result<(Box<[u8]>, Blob)>;
+    fn next(&mut self) -> Option<Result<(Box<[u8]>, Blob)>> {
+        if self.done {
+            return None;
+        }
+        let r = self.stepForward();
+        self.finish(r)
+    }
+}
+
+impl<'a> DoubleEndedIterator for RangeIterator<'a> {
+    fn next_back(&mut self) -> Option<Result<(Box<[u8]>, Blob)>> {
+        if self.done {
+            return None;
+        }
+        let r = self.stepBackward();
+        self.finish(r)
+    }
+}
+
+// the smallest key which is bigger than every key starting with
+// p, in byte order.  None if there is no such key, because p is
+// all 0xff.
+fn prefixEnd(p: &[u8]) -> Option<Box<[u8]>> {
+    match p.iter().rposition(|b| *b != 0xff) {
+        Some(i) => {
+            let mut k = p[0 .. i + 1].to_vec();
+            k[i] = k[i] + 1;
+            Some(k.into_boxed_slice())
+        },
+        None => None,
+    }
+}
+
 #[derive(Hash,PartialEq,Eq,Copy,Clone,Debug)]
 #[repr(u8)]
-enum PageType {
+pub enum PageType {
     LEAF_NODE,
     PARENT_NODE,
     OVERFLOW_NODE,
@@ -1409,12 +3490,24 @@ impl PageType {
 mod ValueFlag {
     pub const FLAG_OVERFLOW: u8 = 1;
     pub const FLAG_TOMBSTONE: u8 = 2;
+    // only in the write-ahead log.  the key is the start of the
+    // range, and the value is the end.
+    pub const FLAG_RANGE_TOMBSTONE: u8 = 4;
+    // only in the write-ahead log.  the pair has no key or value,
+    // just the change counter of a flush.
+    pub const FLAG_COMMIT_MARKER: u8 = 8;
 }
 
+// these are bits, tested one at a time, so no flag may overlap
+// another.  FLAG_ENDS_ON_BOUNDARY was 3 in format 1.  see
+// FORMAT_VERSION.
 mod PageFlag {
     pub const FLAG_ROOT_NODE: u8 = 1;
     pub const FLAG_BOUNDARY_NODE: u8 = 2;
-    pub const FLAG_ENDS_ON_BOUNDARY: u8 = 3;
+    pub const FLAG_ENDS_ON_BOUNDARY: u8 = 4;
+    // a leaf, or the first page of an overflow, whose contents
+    // went through a codec
+    pub const FLAG_COMPRESSED: u8 = 8;
 }
 
 #[derive(Debug)]
@@ -1469,37 +3562,44 @@ struct LeafState {
     blk : PageBlock,
 }
 
-fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite, 
-                                                            pageManager: &IPages, 
-                                                            source: I,
-                                                           ) -> Result<(SegmentNum,PageNum)> where I:Iterator<Item=Result<kvp>>, SeekWrite : Seek+Write {
+fn CreateFromSortedSequenceOfKeyValuePairs<I>(fs: &mut File, 
+                                                 pageManager: &IPages, 
+                                                 source: I,
+                                                 graveyard: Option<Graveyard>,
+                                                 settings: &DbSettings,
+                                                ) -> Result<(SegmentNum,PageNum)> where I:Iterator<Item=Result<kvp>> {
 
     fn writeOverflow<SeekWrite>(startingBlock: PageBlock, 
                                 ba: &mut Read, 
                                 pageManager: &IPages, 
-                                fs: &mut SeekWrite
+                                fs: &mut SeekWrite,
+                                token: &mut PendingSegment,
+                                flags: u8
                                ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
 
-        fn buildFirstPage(ba: &mut Read, pbFirstOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        // the room on each page comes from the builder, which knows
+        // about the checksum and anything reserved by the page transform.
+
+        fn buildFirstPage(ba: &mut Read, pbFirstOverflow : &mut PageBuilder, flags: u8) -> Result<(usize,bool)> {
             pbFirstOverflow.Reset();
             pbFirstOverflow.PutByte(PageType::OVERFLOW_NODE.to_u8());
-            pbFirstOverflow.PutByte(0u8); // starts 0, may be changed later
-            let room = pgsz - (2 + SIZE_32);
+            pbFirstOverflow.PutByte(flags); // more may be added later
+            let room = pbFirstOverflow.Available() - SIZE_32;
             // something will be put in lastInt32 later
             let put = try!(pbFirstOverflow.PutStream2(ba, room));
             Ok((put, put<room))
         };
 
-        fn buildRegularPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        fn buildRegularPage(ba: &mut Read, pbOverflow : &mut PageBuilder) -> Result<(usize,bool)> {
             pbOverflow.Reset();
-            let room = pgsz;
+            let room = pbOverflow.Available();
             let put = try!(pbOverflow.PutStream2(ba, room));
             Ok((put, put<room))
         };
 
-        fn buildBoundaryPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        fn buildBoundaryPage(ba: &mut Read, pbOverflow : &mut PageBuilder) -> Result<(usize,bool)> {
             pbOverflow.Reset();
-            let room = pgsz - SIZE_32;
+            let room = pbOverflow.Available() - SIZE_32;
             // something will be put in lastInt32 before the page is written
             let put = try!(pbOverflow.PutStream2(ba, room));
             Ok((put, put<room))
@@ -1509,14 +3609,13 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                         sofar: usize, 
                                         pb: &mut PageBuilder, 
                                         fs: &mut SeekWrite, 
-                                        ba: &mut Read, 
-                                        pgsz: usize
+                                        ba: &mut Read
                                        ) -> Result<(PageNum,usize,bool)> where SeekWrite : Seek+Write {
             let mut i = 0;
             let mut sofar = sofar;
             loop {
                 if i < max {
-                    let (put, finished) = try!(buildRegularPage(ba, pb, pgsz));
+                    let (put, finished) = try!(buildRegularPage(ba, pb));
                     if put==0 {
                         return Ok((i, sofar, true));
                     } else {
@@ -1543,12 +3642,14 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                     pbOverflow: &mut PageBuilder,
                                     pbFirstOverflow: &mut PageBuilder,
                                     pageManager: &IPages,
-                                    token: &mut PendingSegment
+                                    token: &mut PendingSegment,
+                                    flags: u8
                                    ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
             // each trip through this loop will write out one
             // block, starting with the overflow first page,
             // followed by zero-or-more "regular" overflow pages,
-            // which have no header.  we'll stop at the block boundary,
+            // which have no header (but they do have a checksum,
+            // like every page).  we'll stop at the block boundary,
             // either because we land there or because the whole overflow
             // won't fit and we have to continue into the next block.
             // the boundary page will be like a regular overflow page,
@@ -1558,7 +3659,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             loop {
                 let sofar = loop_sofar;
                 let firstBlk = loop_firstBlk;
-                let (putFirst,finished) = try!(buildFirstPage (ba, pbFirstOverflow, pgsz));
+                let (putFirst,finished) = try!(buildFirstPage (ba, pbFirstOverflow, flags));
                 if putFirst==0 { 
                     return Ok((sofar, firstBlk));
                 } else {
@@ -1606,7 +3707,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                 ;
 
                             let (numRegularPages, sofar, finished) = 
-                                try!(writeRegularPages(availableBeforeBoundary, sofar, pbOverflow, fs, ba, pgsz));
+                                try!(writeRegularPages(availableBeforeBoundary, sofar, pbOverflow, fs, ba));
 
                             if finished {
                                 // go back and fix the first page
@@ -1623,7 +3724,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                 // FLAG_ENDS_ON_BOUNDARY on the first
                                 // overflow page in this block.
 
-                                let (putBoundary,finished) = try!(buildBoundaryPage (ba, pbOverflow, pgsz));
+                                let (putBoundary,finished) = try!(buildBoundaryPage (ba, pbOverflow));
                                 if putBoundary==0 {
                                     // go back and fix the first page
                                     pbFirstOverflow.SetLastInt32(numRegularPages);
@@ -1649,7 +3750,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
 
                                     // now reset to the first page in the next block
                                     try!(utils::SeekPage(fs, pgsz, blk.firstPage));
-                                    if finished {
+                                    if !finished {
                                         loop_sofar = sofar;
                                         loop_firstBlk = blk;
                                     } else {
@@ -1664,11 +3765,45 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         }
 
         let pgsz = pageManager.PageSize();
-        let mut token = try!(pageManager.Begin());
-        let mut pbFirstOverflow = PageBuilder::new(pgsz);
-        let mut pbOverflow = PageBuilder::new(pgsz);
-
-        writeOneBlock(0, startingBlock, fs, ba, pgsz, &mut pbOverflow, &mut pbFirstOverflow, pageManager, &mut token)
+        let mut pbFirstOverflow = PageBuilder::new_page(pgsz, pageManager.PageTransform());
+        let mut pbOverflow = PageBuilder::new_page(pgsz, pageManager.PageTransform());
+
+        // any blocks the overflow needs beyond the first one belong
+        // to the segment being written
+        writeOneBlock(0, startingBlock, fs, ba, pgsz, &mut pbOverflow, &mut pbFirstOverflow, pageManager, token, flags)
+    }
+
+    // like writeOverflow, but for a value, which gets compressed
+    // first if there is a codec and it helps.  the whole value has
+    // to be read into memory for that.  returns the uncompressed
+    // length, which is what the leaf records.
+    fn writeValueOverflow<SeekWrite>(startingBlock: PageBlock, 
+                                     ba: &mut Read, 
+                                     pageManager: &IPages, 
+                                     fs: &mut SeekWrite,
+                                     token: &mut PendingSegment,
+                                     codec: Option<&'static ICodec>
+                                    ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
+        match codec {
+            None => writeOverflow(startingBlock, ba, pageManager, fs, token, 0),
+            Some(codec) => {
+                let mut a = Vec::new();
+                try!(ba.read_to_end(&mut a));
+                let c = codec.Compress(&a);
+                // the overflow starts with the codec id and the
+                // compressed length
+                if 1 + SIZE_32 + c.len() < a.len() {
+                    let mut pb = PageBuilder::new(1 + SIZE_32 + c.len());
+                    pb.PutByte(codec.Id());
+                    pb.PutInt32(c.len() as u32);
+                    pb.PutArray(&c);
+                    let (_, newBlk) = try!(writeOverflow(startingBlock, &mut pb.Buffer(), pageManager, fs, token, PageFlag::FLAG_COMPRESSED));
+                    Ok((a.len(), newBlk))
+                } else {
+                    writeOverflow(startingBlock, &mut &*a, pageManager, fs, token, 0)
+                }
+            },
+        }
     }
 
     fn writeLeaves<I,SeekWrite>(leavesBlk:PageBlock,
@@ -1678,27 +3813,33 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                 fs: &mut SeekWrite, 
                                 pb: &mut PageBuilder,
                                 token: &mut PendingSegment,
+                                codec: Option<&'static ICodec>,
                                 ) -> Result<(PageBlock,Vec<pgitem>,PageNum)> where I: Iterator<Item=Result<kvp>> , SeekWrite : Seek+Write {
         // 2 for the page type and flags
         // 4 for the prev page
         // 2 for the stored count
         // 4 for lastInt32 (which isn't in pb.Available)
-        const LEAF_PAGE_OVERHEAD: usize = 2 + 4 + 2 + 4;
+        // 4 for the checksum
+        const LEAF_PAGE_OVERHEAD: usize = 2 + 4 + 2 + 4 + 4;
+
+        // with a codec, keys are collected for a leaf this many
+        // times bigger than a page, in the hope that it compresses
+        // enough to fit in one.
+        const COMPRESSED_LEAF_SIZE_IN_PAGES: usize = 4;
 
-        fn buildLeaf(st: &mut LeafState, pb: &mut PageBuilder) -> Box<[u8]> {
+        fn buildLeaf(prevLeaf: PageNum, prefixLen: usize, keys: &[LeafPair], pb: &mut PageBuilder) {
             pb.Reset();
             pb.PutByte(PageType::LEAF_NODE.to_u8());
             pb.PutByte(0u8); // flags
-            pb.PutInt32 (st.prevLeaf); // prev page num.
+            pb.PutInt32 (prevLeaf); // prev page num.
             // TODO prefixLen is one byte.  should it be two?
-            pb.PutByte(st.prefixLen as u8);
-            if st.prefixLen > 0 {
-                pb.PutArray(&st.keys_in_this_leaf[0].key[0 .. st.prefixLen]);
+            pb.PutByte(prefixLen as u8);
+            if prefixLen > 0 {
+                pb.PutArray(&keys[0].key[0 .. prefixLen]);
             }
-            let count_keys_in_this_leaf = st.keys_in_this_leaf.len();
             // TODO should we support more than 64k keys in a leaf?
             // either way, overflow-check this cast.
-            pb.PutInt16 (count_keys_in_this_leaf as u16);
+            pb.PutInt16 (keys.len() as u16);
 
             fn f(pb: &mut PageBuilder, prefixLen: usize, lp: &LeafPair) {
                 match lp.kLoc {
@@ -1730,35 +3871,131 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                 }
             }
 
-            // deal with all the keys except the last one
-            for lp in st.keys_in_this_leaf.drain(0 .. count_keys_in_this_leaf-1) {
-                f(pb, st.prefixLen, &lp);
+            for lp in keys {
+                f(pb, prefixLen, lp);
             }
-            assert!(st.keys_in_this_leaf.len() == 1);
+        }
 
-            let lp = st.keys_in_this_leaf.remove(0); 
-            assert!(st.keys_in_this_leaf.is_empty());
+        // returns how many of the pending keys go into the next leaf,
+        // and, if that leaf has to be compressed to fit in a page, the
+        // codec id, the uncompressed length, and the compressed bytes.
+        // the first key always fits uncompressed.
+        fn chooseLeafKeys(st: &LeafState, 
+                          lb: &mut PageBuilder, 
+                          room: usize, 
+                          codec: Option<&'static ICodec>
+                         ) -> (usize, Option<(u8,usize,Vec<u8>)>) {
+            let keys = &st.keys_in_this_leaf;
+            if keys.is_empty() {
+                return (0, None);
+            }
+            let mut fits = 0;
+            let mut sofar = LEAF_PAGE_OVERHEAD + 1 + st.prefixLen;
+            while fits < keys.len() {
+                sofar = sofar + leafPairSize(st.prefixLen, &keys[fits]);
+                if sofar > room {
+                    break;
+                }
+                fits = fits + 1;
+            }
+            assert!(fits > 0);
+
+            fn tryCompress(st: &LeafState, n: usize, lb: &mut PageBuilder, room: usize, codec: &'static ICodec) -> Option<(u8,usize,Vec<u8>)> {
+                buildLeaf(st.prevLeaf, st.prefixLen, &st.keys_in_this_leaf[0 .. n], lb);
+                // the page type and flags stay outside the compressed part
+                let ulen = lb.cur - 2;
+                let c = codec.Compress(&lb.buf[2 .. lb.cur]);
+                let needed = 
+                    2 
+                    + 1 // codec id
+                    + varint::space_needed_for(ulen as u64)
+                    + varint::space_needed_for(c.len() as u64)
+                    + c.len()
+                    + SIZE_32 // lastInt32
+                    + PAGE_CHECKSUM_SIZE;
+                if needed <= room {
+                    Some((codec.Id(), ulen, c))
+                } else {
+                    None
+                }
+            }
 
-            f(pb, st.prefixLen, &lp);
-            lp.key
+            match codec {
+                Some(codec) if fits < keys.len() => {
+                    let count = std::cmp::min(keys.len(), u16::max_value() as usize);
+                    match tryCompress(st, count, lb, room, codec) {
+                        Some(c) => (count, Some(c)),
+                        None => {
+                            // find the most keys which do fit
+                            let mut lo = fits;
+                            let mut hi = count - 1;
+                            let mut best = None;
+                            while lo < hi {
+                                let mid = (lo + hi + 1) / 2;
+                                match tryCompress(st, mid, lb, room, codec) {
+                                    Some(c) => {
+                                        lo = mid;
+                                        best = Some(c);
+                                    },
+                                    None => {
+                                        hi = mid - 1;
+                                    },
+                                }
+                            }
+                            (lo, best)
+                        },
+                    }
+                },
+                _ => (fits, None),
+            }
         }
 
+        // writes the pending keys which fit, which is all of them
+        // unless there is a codec.  the rest stay pending.
         fn writeLeaf<SeekWrite>(st: &mut LeafState, 
-                                isRootPage: bool, 
+                                lb: &mut PageBuilder, 
                                 pb: &mut PageBuilder, 
                                 fs: &mut SeekWrite, 
                                 pgsz: usize,
+                                room: usize,
+                                codec: Option<&'static ICodec>,
                                 pageManager: &IPages,
                                 token: &mut PendingSegment,
                                ) -> Result<()> where SeekWrite : Seek+Write { 
-            let last_key = buildLeaf(st, pb);
-            assert!(st.keys_in_this_leaf.is_empty());
+            let (n, compressed) = chooseLeafKeys(st, lb, room, codec);
+            pb.Reset();
+            match compressed {
+                Some((id, ulen, c)) => {
+                    pb.PutByte(PageType::LEAF_NODE.to_u8());
+                    pb.PutByte(PageFlag::FLAG_COMPRESSED);
+                    pb.PutByte(id);
+                    pb.PutVarint(ulen as u64);
+                    pb.PutVarint(c.len() as u64);
+                    pb.PutArray(&c);
+                },
+                None => {
+                    buildLeaf(st.prevLeaf, st.prefixLen, &st.keys_in_this_leaf[0 .. n], lb);
+                    pb.PutArray(&lb.buf[0 .. lb.cur]);
+                },
+            }
+            let last_key = {
+                let mut written: Vec<LeafPair> = st.keys_in_this_leaf.drain(0 .. n).collect();
+                match written.pop() {
+                    Some(lp) => lp.key,
+                    None => vec![].into_boxed_slice(),
+                }
+            };
             let thisPageNumber = st.blk.firstPage;
             let firstLeaf = if st.leaves.is_empty() { thisPageNumber } else { st.firstLeaf };
+            // even when this is the only leaf, it is not the root.
+            // a parent page is always written above the leaves, and
+            // the bloom filter and range tombstones come before that,
+            // so a leaf on the last page of the block always needs
+            // to point to the next.  (there used to be an isRootPage
+            // case here for a lone leaf, which skipped this, and
+            // left its parent on a page outside the segment.)
             let nextBlk = 
-                if isRootPage {
-                    PageBlock::new(thisPageNumber + 1, st.blk.lastPage)
-                } else if thisPageNumber == st.blk.lastPage {
+                if thisPageNumber == st.blk.lastPage {
                     pb.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
                     let newBlk = try!(pageManager.GetBlock(&mut *token));
                     pb.SetLastInt32(newBlk.firstPage);
@@ -1772,9 +4009,16 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             }
             let pg = pgitem {page:thisPageNumber, key:last_key};
             st.leaves.push(pg);
-            st.sofarLeaf = 0;
+            if st.keys_in_this_leaf.is_empty() {
+                st.sofarLeaf = 0;
+                st.prefixLen = 0;
+            } else {
+                // the keys left over still share the prefix
+                let prefixLen = st.prefixLen;
+                let sum = st.keys_in_this_leaf.iter().map(|lp| leafPairSize(prefixLen, lp)).sum();
+                st.sofarLeaf = sum;
+            }
             st.prevLeaf = thisPageNumber;
-            st.prefixLen = 0;
             st.firstLeaf = firstLeaf;
             st.blk = nextBlk;
             Ok(())
@@ -1787,8 +4031,11 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         // one in the leaf, and its value is overflowed.
 
         let pgsz = pageManager.PageSize();
+        // how much of a page a leaf gets, not counting the bytes
+        // reserved by the page transform
+        let room = pgsz - reservedBytes(pageManager.PageTransform());
         let maxKeyInline = 
-            pgsz 
+            room 
             - LEAF_PAGE_OVERHEAD 
             - 1 // prefixLen
             - 1 // key flags
@@ -1836,6 +4083,10 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         }
 
         // this is the body of writeLeaves
+        let leafsz = if codec.is_some() { room * COMPRESSED_LEAF_SIZE_IN_PAGES } else { room };
+        // the keys pending for a leaf can go one pair over leafsz,
+        // when they did not all fit in the leaf before that pair.
+        let mut lb = PageBuilder::new(leafsz + pgsz);
         let mut st = LeafState {
             sofarLeaf: 0,
             firstLeaf: 0,
@@ -1858,7 +4109,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                     (st.blk, KeyLocation::Inline)
                 } else {
                     let vPage = st.blk.firstPage;
-                    let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*k, pageManager, fs));
+                    let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*k, pageManager, fs, &mut *token, 0));
                     (newBlk, KeyLocation::Overflowed(vPage))
                 };
 
@@ -1867,7 +4118,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
 
             // TODO this is a usize, so it might cause integer underflow.
             let availableOnNewPageAfterKey = 
-                pgsz 
+                room 
                 - LEAF_PAGE_OVERHEAD 
                 - 1 // prefixLen
                 - 1 // key flags
@@ -1902,7 +4153,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                     },
                                     Blob::Stream(ref mut strm) => {
                                         let valuePage = blkAfterKey.firstPage;
-                                        let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
+                                        let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut *strm, pageManager, fs, &mut *token, codec));
                                         (newBlk, ValueLocation::Overflowed(len,valuePage))
                                     },
                                     Blob::Array(a) => {
@@ -1912,7 +4163,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                         } else {
                                             let valuePage = blkAfterKey.firstPage;
                                             let strm = a; // TODO need a Read for this
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*strm, pageManager, fs));
+                                            let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut &*strm, pageManager, fs, &mut *token, codec));
                                             (newBlk, ValueLocation::Overflowed(len,valuePage))
                                         }
                                     },
@@ -1937,7 +4188,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                             (blkAfterKey, ValueLocation::Buffer(va.into_boxed_slice()))
                                         } else {
                                             let valuePage = blkAfterKey.firstPage;
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut (vbuf.chain(strm)), pageManager, fs));
+                                            let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut (vbuf.chain(strm)), pageManager, fs, &mut *token, codec));
                                             (newBlk, ValueLocation::Overflowed (len,valuePage))
                                         }
                                     },
@@ -1946,7 +4197,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                             (blkAfterKey, ValueLocation::Buffer(a))
                                         } else {
                                             let valuePage = blkAfterKey.firstPage;
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
+                                            let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut &*a, pageManager, fs, &mut *token, codec));
                                             (newBlk, ValueLocation::Overflowed(len,valuePage))
                                         }
                                     },
@@ -1961,7 +4212,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                 },
                                 Blob::Stream(ref mut strm) => {
                                     let valuePage = blkAfterKey.firstPage;
-                                    let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
+                                    let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut *strm, pageManager, fs, &mut *token, codec));
                                     (newBlk, ValueLocation::Overflowed(len,valuePage))
                                 },
                                 Blob::Array(a) => {
@@ -1970,7 +4221,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                                         (blkAfterKey, ValueLocation::Buffer(a))
                                     } else {
                                         let valuePage = blkAfterKey.firstPage;
-                                        let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
+                                        let (len,newBlk) = try!(writeValueOverflow(blkAfterKey, &mut &*a, pageManager, fs, &mut *token, codec));
                                         (newBlk, ValueLocation::Overflowed(len,valuePage))
                                     }
                                 }
@@ -2005,8 +4256,8 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             let fit = {
                 let needed = kLocNeed(&k, &kloc, newPrefixLen) + vLocNeed(&vloc);
                 let used = sofar + LEAF_PAGE_OVERHEAD + 1 + newPrefixLen;
-                if pgsz > used {
-                    let available = pgsz - used;
+                if leafsz > used {
+                    let available = leafsz - used;
                     (available >= needed)
                 } else {
                     false
@@ -2015,7 +4266,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             let writeThisPage = (! st.keys_in_this_leaf.is_empty()) && (! fit);
 
             if writeThisPage {
-                try!(writeLeaf(&mut st, false, pb, fs, pgsz, pageManager, &mut *token));
+                try!(writeLeaf(&mut st, &mut lb, pb, fs, pgsz, room, codec, pageManager, &mut *token));
             }
 
             // TODO ignore prefixLen for overflowed keys?
@@ -2047,9 +4298,10 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             st.prefixLen=newPrefixLen;
         }
 
-        if !st.keys_in_this_leaf.is_empty() {
-            let isRootNode = st.leaves.is_empty();
-            try!(writeLeaf(&mut st, isRootNode, pb, fs, pgsz, pageManager, &mut *token));
+        // a segment with no pairs (but maybe some range tombstones)
+        // still gets one leaf, which is empty
+        while !st.keys_in_this_leaf.is_empty() || st.leaves.is_empty() {
+            try!(writeLeaf(&mut st, &mut lb, pb, fs, pgsz, room, codec, pageManager, &mut *token));
         }
         Ok((st.blk,st.leaves,st.firstLeaf))
     }
@@ -2068,7 +4320,8 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         // 2 for the stored count
         // 5 for the extra ptr we will add at the end, a varint, 5 is worst case (page num < 4294967295L)
         // 4 for lastInt32
-        const PARENT_PAGE_OVERHEAD: usize = 2 + 2 + 5 + 4;
+        // 4 for the checksum
+        const PARENT_PAGE_OVERHEAD: usize = 2 + 2 + 5 + 4 + 4;
 
         fn calcAvailable(currentSize: usize, couldBeRoot: bool, pgsz: usize) -> usize {
             let basicSize = pgsz - currentSize;
@@ -2153,6 +4406,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         }
 
         // this is the body of writeParentNodes
+        let room = pgsz - reservedBytes(pageManager.PageTransform());
         let mut st = ParentState {nextGeneration:Vec::new(),sofar: 0,blk:startingBlk,};
         let mut items = Vec::new();
         let mut overflows = HashMap::new();
@@ -2166,9 +4420,9 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
             let neededForOverflow = neededEitherWay + SIZE_32;
             let couldBeRoot = st.nextGeneration.is_empty();
 
-            let available = calcAvailable(st.sofar, couldBeRoot, pgsz);
+            let available = calcAvailable(st.sofar, couldBeRoot, room);
             let fitsInline = available >= neededForInline;
-            let wouldFitInlineOnNextPage = (pgsz - PARENT_PAGE_OVERHEAD) >= neededForInline;
+            let wouldFitInlineOnNextPage = (room - PARENT_PAGE_OVERHEAD) >= neededForInline;
             let fitsOverflow = available >= neededForOverflow;
             let writeThisPage = (! fitsInline) && (wouldFitInlineOnNextPage || (! fitsOverflow));
 
@@ -2187,11 +4441,11 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                 assert!(items.is_empty());
             }
 
-            if calcAvailable(st.sofar, st.nextGeneration.is_empty(), pgsz) >= neededForInline {
+            if calcAvailable(st.sofar, st.nextGeneration.is_empty(), room) >= neededForInline {
                 st.sofar = st.sofar + neededForInline;
             } else {
                 let keyOverflowFirstPage = st.blk.firstPage;
-                let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*pair.key, pageManager, fs));
+                let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*pair.key, pageManager, fs, &mut *token, 0));
                 st.sofar = st.sofar + neededForOverflow;
                 st.blk = newBlk;
                 // items.len() is the index that this pair is about to get, just below
@@ -2210,7 +4464,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
 
     // this is the body of Create
     let pgsz = pageManager.PageSize();
-    let mut pb = PageBuilder::new(pgsz);
+    let mut pb = PageBuilder::new_page(pgsz, pageManager.PageTransform());
     let mut token = try!(pageManager.Begin());
     let startingBlk = try!(pageManager.GetBlock(&mut token));
     try!(utils::SeekPage(fs, pgsz, startingBlk.firstPage));
@@ -2220,7 +4474,61 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
     // read a bit of it to figure out if it might fit inline rather
     // than overflow.
     let mut vbuf = vec![0;pgsz].into_boxed_slice(); 
-    let (blkAfterLeaves, leaves, firstLeaf) = try!(writeLeaves(startingBlk, pageManager, source, &mut vbuf, fs, &mut pb, &mut token));
+
+    // the bloom filter cannot be sized until we know how many keys
+    // there are, so just remember their hashes for now.  it hashes
+    // the bytes of the key, which says nothing about keys that a
+    // comparator calls equal, so there is none with a comparator.
+    let wantBloom = settings.BloomBitsPerKey > 0 && settings.Comparator.is_none();
+    let mut keyHashes = Vec::new();
+    let (blkAfterLeaves, leaves, firstLeaf) = {
+        let source = source.map(|r| {
+            match r {
+                Ok(ref pair) if wantBloom => keyHashes.push(BloomFilter::hash(&KeyRef::for_slice(&pair.Key))),
+                _ => (),
+            }
+            r
+        });
+        try!(writeLeaves(startingBlk, pageManager, source, &mut vbuf, fs, &mut pb, &mut token, settings.Compression))
+    };
+
+    // the bloom filter goes between the leaves and the parent
+    // pages, so the root is still the last page of the segment.
+    let (blkAfterBloom, bloom) =
+        if keyHashes.is_empty() {
+            (blkAfterLeaves, None)
+        } else {
+            let filter = BloomFilter::new(&keyHashes, settings.BloomBitsPerKey);
+            let a = filter.encode();
+            try!(utils::SeekPage(fs, pgsz, blkAfterLeaves.firstPage));
+            let (len, newBlk) = try!(writeOverflow(blkAfterLeaves, &mut &*a, pageManager, fs, &mut token, 0));
+            let bloom = SegmentBloom {
+                firstPage: blkAfterLeaves.firstPage,
+                len: len,
+            };
+            (newBlk, Some(bloom))
+        };
+
+    // the range tombstones come next, also as an overflow
+    let (blkAfterGraveyard, graveyard) =
+        match graveyard {
+            Some(g) => {
+                if g.is_empty() {
+                    (blkAfterBloom, None)
+                } else {
+                    let a = g.encode();
+                    try!(utils::SeekPage(fs, pgsz, blkAfterBloom.firstPage));
+                    let (len, newBlk) = try!(writeOverflow(blkAfterBloom, &mut &*a, pageManager, fs, &mut token, 0));
+                    let graveyard = SegmentGraveyard {
+                        firstPage: blkAfterBloom.firstPage,
+                        len: len,
+                        ranges: Arc::new(g),
+                    };
+                    (newBlk, Some(graveyard))
+                }
+            },
+            None => (blkAfterBloom, None),
+        };
 
     // all the leaves are written.
     // now write the parent pages.
@@ -2231,7 +4539,7 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
     let lastLeaf = leaves[leaves.len()-1].page;
 
     let rootPage = {
-        let mut blk = blkAfterLeaves;
+        let mut blk = blkAfterGraveyard;
         let mut children = leaves;
         loop {
             let (newBlk, newChildren) = try!(writeParentNodes(blk, &mut children, pgsz, fs, pageManager, &mut token, lastLeaf, firstLeaf, &mut pb));
@@ -2245,15 +4553,20 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
         children[0].page
     };
 
-    let g = try!(pageManager.End(token, rootPage));
+    let g = try!(pageManager.End(token, rootPage, bloom, graveyard));
     Ok((g,rootPage))
 }
 
 struct myOverflowReadStream {
-    fs: File,
+    fs: Arc<File>,
     len: usize, // same type as ValueLength(), max len of a single value
     firstPage: PageNum, // TODO will be needed later for Seek trait
-    buf: Box<[u8]>,
+    segnum: SegmentNum, // just for error reporting
+    buf: PageBytes,
+    // with MmapReads, the pages come out of this instead of fs
+    map: Option<Arc<FileMap>>,
+    end: usize, // same as end in PageBuffer
+    transform: Option<&'static IPageTransform>,
     currentPage: PageNum,
     sofarOverall: usize,
     sofarThisPage: usize,
@@ -2263,23 +4576,31 @@ struct myOverflowReadStream {
     boundaryPageNumber: PageNum,
     bytesOnThisPage: usize,
     offsetOnThisPage: usize,
+    // a compressed overflow gets read and decompressed up front
+    inflated: Option<Vec<u8>>,
 }
     
 impl myOverflowReadStream {
-    fn new(path: &str, pgsz: usize, firstPage: PageNum, len: usize) -> Result<myOverflowReadStream> {
-        // TODO I wonder if maybe we should defer the opening of the file until
-        // somebody actually tries to read from it?  so that constructing a
-        // ValueRef object (which contains one of these) would be a lighter-weight
-        // operation.
-        let f = try!(OpenOptions::new()
-                .read(true)
-                .open(path));
+    fn new(fs: Arc<File>, 
+           pgsz: usize, 
+           checksums: bool, 
+           transform: Option<&'static IPageTransform>, 
+           codec: Option<&'static ICodec>, 
+           map: Option<Arc<FileMap>>,
+           firstPage: PageNum, 
+           len: usize, 
+           segnum: SegmentNum
+          ) -> Result<myOverflowReadStream> {
         let mut res = 
             myOverflowReadStream {
-                fs: f,
+                fs: fs,
                 len: len,
                 firstPage: firstPage,
-                buf: vec![0;pgsz].into_boxed_slice(),
+                segnum: segnum,
+                buf: PageBytes::Owned(vec![0;pgsz].into_boxed_slice()),
+                map: map,
+                end: if checksums { pgsz - PAGE_CHECKSUM_SIZE - reservedBytes(transform) } else { pgsz },
+                transform: transform,
                 currentPage: firstPage,
                 sofarOverall: 0,
                 sofarThisPage: 0,
@@ -2289,11 +4610,49 @@ impl myOverflowReadStream {
                 boundaryPageNumber: 0,
                 bytesOnThisPage: 0,
                 offsetOnThisPage: 0,
+                inflated: None,
             };
         try!(res.ReadFirstPage());
+        if res.CheckPageFlag(PageFlag::FLAG_COMPRESSED) {
+            try!(res.inflate(codec));
+        }
         Ok(res)
     }
 
+    // the overflow starts with the codec id and the compressed
+    // length.  len is the uncompressed length.
+    fn inflate(&mut self, codec: Option<&'static ICodec>) -> Result<()> {
+        let ulen = self.len;
+        self.len = 1 + SIZE_32;
+        let mut pre = [0u8; 1 + SIZE_32];
+        let got = try!(misc::io::read_fully(self, &mut pre));
+        let corrupt = Error::CorruptPage {
+            page: self.firstPage,
+            pageType: Some(PageType::OVERFLOW_NODE),
+            segment: Some(self.segnum),
+        };
+        if got < pre.len() {
+            return Err(corrupt);
+        }
+        let codec = try!(findCodec(pre[0], codec));
+        let clen = endian::u32_from_bytes_be(misc::bytes::extract_4(&pre[1 .. 1 + SIZE_32])) as usize;
+        self.len = 1 + SIZE_32 + clen;
+        let mut c = Vec::with_capacity(clen);
+        try!(self.read_to_end(&mut c));
+        if c.len() < clen {
+            return Err(corrupt);
+        }
+        match codec.Decompress(&c, ulen) {
+            Some(a) => {
+                self.inflated = Some(a);
+                self.len = ulen;
+                self.sofarOverall = 0;
+                Ok(())
+            },
+            None => Err(corrupt),
+        }
+    }
+
     fn len(&self) -> usize {
         self.len
     }
@@ -2301,27 +4660,59 @@ impl myOverflowReadStream {
     // TODO consider supporting Seek trait
 
     fn ReadPage(&mut self) -> Result<()> {
-        try!(utils::SeekPage(&mut self.fs, self.buf.len(), self.currentPage));
-        try!(misc::io::read_fully(&mut self.fs, &mut *self.buf));
+        let pgsz = self.buf.len();
+        match self.map {
+            Some(ref m) => {
+                if 0 == self.currentPage {
+                    return Err(Error::InvalidPageNumber);
+                }
+                let pos = (self.currentPage - 1) as usize * pgsz;
+                if pos + pgsz > m.len {
+                    return Err(Error::InvalidPageNumber);
+                }
+                self.buf = PageBytes::Mapped(m.clone(), pos, pgsz);
+            },
+            None => {
+                try!(utils::ReadPageAt(&self.fs, pgsz, self.currentPage, &mut *self.buf));
+            },
+        }
+        let end = self.end;
+        if end < self.buf.len() {
+            let at = self.buf.len() - PAGE_CHECKSUM_SIZE;
+            let crc = endian::u32_from_bytes_be(misc::bytes::extract_4(&self.buf[at .. at + SIZE_32]));
+            let ok = 
+                crc == crc32::checksum(&self.buf[0 .. at])
+                && match self.transform {
+                    Some(t) => t.Decode(self.currentPage, &mut self.buf[0 .. at]),
+                    None => true,
+                };
+            if !ok {
+                return Err(Error::CorruptPage {
+                    page: self.currentPage,
+                    pageType: Some(PageType::OVERFLOW_NODE),
+                    segment: Some(self.segnum),
+                });
+            }
+        }
         // assert PageType is OVERFLOW
         self.sofarThisPage = 0;
         if self.currentPage == self.firstPageInBlock {
-            self.bytesOnThisPage = self.buf.len() - (2 + SIZE_32);
+            self.bytesOnThisPage = end - (2 + SIZE_32);
             self.offsetOnThisPage = 2;
         } else if self.currentPage == self.boundaryPageNumber {
-            self.bytesOnThisPage = self.buf.len() - SIZE_32;
+            self.bytesOnThisPage = end - SIZE_32;
             self.offsetOnThisPage = 0;
         } else {
             // assert currentPage > firstPageInBlock
             // assert currentPage < boundaryPageNumber OR boundaryPageNumber = 0
-            self.bytesOnThisPage = self.buf.len();
+            self.bytesOnThisPage = end;
             self.offsetOnThisPage = 0;
         }
         Ok(())
     }
 
     fn GetLastInt32(&self) -> u32 {
-        let at = self.buf.len() - SIZE_32;
+        let at = self.end - SIZE_32;
         // TODO just self.buf?  instead of making 4-byte slice.
         let a = misc::bytes::extract_4(&self.buf[at .. at+4]);
         endian::u32_from_bytes_be(a)
@@ -2361,89 +4752,48 @@ impl myOverflowReadStream {
     }
 
     fn Read(&mut self, ba: &mut [u8], offset: usize, wanted: usize) -> Result<usize> {
+        match self.inflated {
+            Some(ref a) => {
+                let num = std::cmp::min(a.len() - self.sofarOverall, wanted);
+                ba[offset .. offset + num].clone_from_slice(&a[self.sofarOverall .. self.sofarOverall + num]);
+                self.sofarOverall = self.sofarOverall + num;
+                return Ok(num);
+            },
+            None => {
+            },
+        }
         if self.sofarOverall >= self.len {
             Ok(0)
         } else {
-            let mut direct = false;
             if self.sofarThisPage >= self.bytesOnThisPage {
                 if self.currentPage == self.boundaryPageNumber {
                     self.currentPage = self.GetLastInt32();
                     try!(self.ReadFirstPage());
                 } else {
-                    // we need a new page.  and if it's a full data page,
-                    // and if wanted is big enough to take all of it, then
-                    // we want to read (at least) it directly into the
-                    // buffer provided by the caller.  we already know
-                    // this candidate page cannot be the first page in a
-                    // block.
-                    let maybeDataPage = self.currentPage + 1;
-                    let isDataPage = 
-                        if self.boundaryPageNumber > 0 {
-                            ((self.len - self.sofarOverall) >= self.buf.len()) && (self.countRegularDataPagesInBlock > 0) && (maybeDataPage > self.firstPageInBlock) && (maybeDataPage < self.boundaryPageNumber)
-                        } else {
-                            ((self.len - self.sofarOverall) >= self.buf.len()) && (self.countRegularDataPagesInBlock > 0) && (maybeDataPage > self.firstPageInBlock) && (maybeDataPage <= (self.firstPageInBlock + self.countRegularDataPagesInBlock))
-                        };
-
-                    if isDataPage && (wanted >= self.buf.len()) {
-                        // assert (currentPage + 1) > firstPageInBlock
-                        //
-                        // don't increment currentPage here because below, we will
-                        // calculate how many pages we actually want to do.
-                        direct = true;
-                        self.bytesOnThisPage = self.buf.len();
-                        self.sofarThisPage = 0;
-                        self.offsetOnThisPage = 0;
-                    } else {
-                        self.currentPage = self.currentPage + 1;
-                        try!(self.ReadPage());
-                    }
+                    // there used to be a path which read runs of full
+                    // data pages directly into the caller's buffer.
+                    // that only worked because the data on those pages
+                    // was contiguous in the file.  now each page ends
+                    // with its checksum (and may have to go through the
+                    // page transform), so a run of pages read straight
+                    // into the caller's buffer would have checksums in
+                    // the middle of the value, and nothing would have
+                    // verified them.  every page goes through the
+                    // buffer instead.  with MmapReads, that is a copy
+                    // out of the mapping, not a read.
+                    self.currentPage = self.currentPage + 1;
+                    try!(self.ReadPage());
                 }
             }
 
-            if direct {
-                // currentPage has not been incremented yet
-                //
-                // skip the buffer.  note, therefore, that the contents of the
-                // buffer are "invalid" in that they do not correspond to currentPage
-                //
-                let numPagesWanted = (wanted / self.buf.len()) as PageNum;
-                // assert countRegularDataPagesInBlock > 0
-                let lastDataPageInThisBlock = self.firstPageInBlock + self.countRegularDataPagesInBlock;
-                let theDataPage = self.currentPage + 1;
-                let numPagesAvailable = 
-                    if self.boundaryPageNumber>0 { 
-                        self.boundaryPageNumber - theDataPage 
-                    } else {
-                        lastDataPageInThisBlock - theDataPage + 1
-                    };
-                let numPagesToFetch = std::cmp::min(numPagesWanted, numPagesAvailable) as PageNum;
-                let bytesToFetch = {
-                    let bytesToFetch = (numPagesToFetch as usize) * self.buf.len();
-                    let available = self.len - self.sofarOverall;
-                    if bytesToFetch > available {
-                        available
-                    } else {
-                        bytesToFetch
-                    }
-                };
-                // assert bytesToFetch <= wanted
-
-                try!(utils::SeekPage(&mut self.fs, self.buf.len(), theDataPage));
-                try!(misc::io::read_fully(&mut self.fs, &mut ba[offset .. offset + bytesToFetch]));
-                self.sofarOverall = self.sofarOverall + bytesToFetch;
-                self.currentPage = self.currentPage + numPagesToFetch;
-                self.sofarThisPage = self.buf.len();
-                Ok(bytesToFetch)
-            } else {
-                let available = std::cmp::min(self.bytesOnThisPage - self.sofarThisPage, self.len - self.sofarOverall);
-                let num = std::cmp::min(available, wanted);
-                for i in 0 .. num {
-                    ba[offset+i] = self.buf[self.offsetOnThisPage + self.sofarThisPage + i];
-                }
-                self.sofarOverall = self.sofarOverall + num;
-                self.sofarThisPage = self.sofarThisPage + num;
-                Ok(num)
+            let available = std::cmp::min(self.bytesOnThisPage - self.sofarThisPage, self.len - self.sofarOverall);
+            let num = std::cmp::min(available, wanted);
+            for i in 0 .. num {
+                ba[offset+i] = self.buf[self.offsetOnThisPage + self.sofarThisPage + i];
             }
+            self.sofarOverall = self.sofarOverall + num;
+            self.sofarThisPage = self.sofarThisPage + num;
+            Ok(num)
         }
     }
 }
@@ -2469,14 +4819,14 @@ impl Read for myOverflowReadStream {
 
 #[cfg(remove_me)]
 fn readOverflow(path: &str, pgsz: usize, firstPage: PageNum, buf: &mut [u8]) -> Result<usize> {
-    let mut ostrm = try!(myOverflowReadStream::new(path, pgsz, firstPage, buf.len()));
+    let mut ostrm = try!(myOverflowReadStream::new(path, pgsz, true, None, None, None, firstPage, buf.len(), 0));
     let res = try!(misc::io::read_fully(&mut ostrm, buf));
     Ok(res)
 }
 
-struct SegmentCursor<'a> {
-    path: String,
-
+// a cursor on one segment.  unlike LivingCursor, it shows
+// tombstones, and it ignores range tombstones.
+pub struct SegmentCursor<'a> {
     // TODO in the f# version, these three were a closure.
     // it would be nice to make it work that way again.
     // so that this code would not have specific knowledge
@@ -2485,11 +4835,23 @@ struct SegmentCursor<'a> {
     segnum: SegmentNum,
     csrnum: u64,
 
-    blocks: Vec<PageBlock>, // TODO will be needed later for stray checking
-    fs: File,
+    blocks: Vec<PageBlock>, // check uses these to catch strays
+    // shared with every other reader, see FilePool
+    fs: Arc<File>,
     len: u64,
     rootPage: PageNum,
+    pgsz: usize,
+    // usually one page, but a compressed leaf gets expanded into
+    // a buffer which can be bigger.  the page buffer is kept in
+    // spare meanwhile.
     pr: PageBuffer,
+    spare: Option<PageBuffer>,
+    // whether to go through the shared page cache.  check turns
+    // this off, because it wants to see what is on disk.
+    cached: bool,
+    // pages come from inner's FileMap instead.  the page cache
+    // would only be a copy of the mapping, so it is not used.
+    mapped: bool,
     currentPage: PageNum,
     leafKeys: Vec<usize>,
     previousLeaf: PageNum,
@@ -2497,40 +4859,42 @@ struct SegmentCursor<'a> {
     prefix: Option<Box<[u8]>>,
     firstLeaf: PageNum,
     lastLeaf: PageNum,
+    bloom: Option<SegmentBloom>,
+    graveyard: Option<Arc<Graveyard>>,
 }
 
 impl<'a> SegmentCursor<'a> {
-    fn new(path: &str, 
+    fn new(fs: Arc<File>, 
            pgsz: usize, 
+           checksums: bool,
            rootPage: PageNum, 
            blocks: Vec<PageBlock>,
+           bloom: Option<SegmentBloom>,
+           graveyard: Option<Arc<Graveyard>>,
            inner: &'a InnerPart, 
            segnum: SegmentNum, 
            csrnum: u64
           ) -> Result<SegmentCursor<'a>> {
 
-        // TODO consider not passsing in the path, and instead,
-        // making the cursor call back to inner.OpenForReading...
-        let mut f = try!(OpenOptions::new()
-                .read(true)
-                .open(path));
-
         // TODO the len is used for checking to make sure we don't stray
         // to far.  This should probably be done with the blocks provided
         // by the caller, not by looking at the full length of the file,
         // which this cursor shouldn't care about.
-        let len = try!(misc::io::seek_len(&mut f));
+        let len = try!(fs.metadata()).len();
 
         let mut res = SegmentCursor {
-            path: String::from_str(path),
-            fs: f,
+            fs: fs,
             blocks: blocks,
             inner: inner,
             segnum: segnum,
             csrnum: csrnum,
             len: len,
             rootPage: rootPage,
-            pr: PageBuffer::new(pgsz),
+            pgsz: pgsz,
+            pr: if checksums { PageBuffer::new_page(pgsz, inner.settings.PageTransform) } else { PageBuffer::new(pgsz) },
+            spare: None,
+            cached: true,
+            mapped: inner.settings.MmapReads && inner.settings.PageTransform.is_none(),
             currentPage: 0,
             leafKeys: Vec::new(),
             previousLeaf: 0,
@@ -2538,6 +4902,8 @@ impl<'a> SegmentCursor<'a> {
             prefix: None,
             firstLeaf: 0, // temporary
             lastLeaf: 0, // temporary
+            bloom: bloom,
+            graveyard: graveyard,
         };
         if ! try!(res.setCurrentPage(rootPage)) {
             // TODO fix this error.  or assert, because we previously verified
@@ -2561,6 +4927,18 @@ impl<'a> SegmentCursor<'a> {
         Ok(res)
     }
 
+    // with MmapReads, an overflow is read out of the mapping too.
+    // all of its pages were in the file before this cursor was.
+    fn overflowStream(&self, firstPage: PageNum, len: usize) -> Result<myOverflowReadStream> {
+        let map = 
+            if self.mapped {
+                try!(self.inner.fileMapping(self.len as usize))
+            } else {
+                None
+            };
+        myOverflowReadStream::new(self.fs.clone(), self.pgsz, self.pr.HasChecksum(), self.inner.settings.PageTransform, self.inner.settings.Compression, map, firstPage, len, self.segnum)
+    }
+
     fn resetLeaf(&mut self) {
         self.leafKeys.clear();
         self.previousLeaf = 0;
@@ -2585,10 +4963,57 @@ impl<'a> SegmentCursor<'a> {
         } else {
             // refuse to go to a page beyond the end of the stream
             // TODO is this the right place for this check?    
-            let pos = (self.currentPage - 1) as u64 * self.pr.PageSize() as u64;
-            if pos + self.pr.PageSize() as u64 <= self.len {
-                try!(utils::SeekPage(&mut self.fs, self.pr.PageSize(), self.currentPage));
-                try!(self.pr.Read(&mut self.fs));
+            let pos = (self.currentPage - 1) as u64 * self.pgsz as u64;
+            if pos + self.pgsz as u64 <= self.len {
+                match self.spare.take() {
+                    Some(pr) => {
+                        // the last page was a compressed leaf
+                        self.pr = pr;
+                    },
+                    None => {
+                    },
+                }
+                if self.mapped {
+                    match try!(self.inner.mappedPage(self.currentPage)) {
+                        Some(buf) => {
+                            self.pr.buf = buf;
+                        },
+                        None => {
+                            // no mmap here, so this cursor reads
+                            // pages the usual way from now on
+                            self.mapped = false;
+                        },
+                    }
+                }
+                if self.mapped {
+                    if !self.pr.VerifyChecksum() {
+                        return Err(Error::CorruptPage {
+                            page: self.currentPage,
+                            pageType: self.pr.PageType().ok(),
+                            segment: Some(self.segnum),
+                        });
+                    }
+                } else if !(self.cached && try!(self.inner.cachedPage(self.currentPage, &mut self.pr.buf))) {
+                    try!(utils::ReadPageAt(&self.fs, self.pgsz, self.currentPage, &mut self.pr.buf));
+                    if !self.pr.VerifyChecksum() || !self.pr.Decode(self.currentPage) {
+                        return Err(Error::CorruptPage {
+                            page: self.currentPage,
+                            pageType: self.pr.PageType().ok(),
+                            segment: Some(self.segnum),
+                        });
+                    }
+                    // a compressed leaf is cached before it gets inflated,
+                    // so the cache only ever holds pages of pgsz.
+                    match self.pr.PageType() {
+                        Ok(PageType::LEAF_NODE) | Ok(PageType::PARENT_NODE) if self.cached => {
+                            try!(self.inner.cachePage(self.currentPage, &self.pr.buf));
+                        },
+                        _ => (),
+                    }
+                }
+                if self.pr.PageType().ok() == Some(PageType::LEAF_NODE) && self.pr.CheckPageFlag(PageFlag::FLAG_COMPRESSED) {
+                    try!(self.inflateLeaf());
+                }
                 Ok(true)
             } else {
                 Err(Error::InvalidPageNumber)
@@ -2597,6 +5022,42 @@ impl<'a> SegmentCursor<'a> {
         }
     }
 
+    // replaces a compressed leaf in pr with one which looks just like
+    // an uncompressed leaf, except that it may be bigger than a page
+    fn inflateLeaf(&mut self) -> Result<()> {
+        let mut cur = 2;
+        let id = self.pr.GetByte(&mut cur);
+        let codec = try!(findCodec(id, self.inner.settings.Compression));
+        let ulen = self.pr.GetVarint(&mut cur) as usize;
+        let clen = self.pr.GetVarint(&mut cur) as usize;
+        let a = 
+            if cur + clen <= self.pr.end - SIZE_32 {
+                codec.Decompress(self.pr.get_slice(cur, clen), ulen)
+            } else {
+                None
+            };
+        match a {
+            Some(a) => {
+                let mut pr = PageBuffer::new_page(2 + ulen + SIZE_32 + PAGE_CHECKSUM_SIZE, None);
+                pr.buf[0] = self.pr.buf[0];
+                pr.buf[1] = self.pr.buf[1] & !PageFlag::FLAG_COMPRESSED;
+                pr.buf[2 .. 2 + ulen].clone_from_slice(&a);
+                // the lastInt32 (for a boundary) comes along
+                let (from, to) = (self.pr.end - SIZE_32, pr.end - SIZE_32);
+                pr.buf[to .. to + SIZE_32].clone_from_slice(&self.pr.buf[from .. from + SIZE_32]);
+                self.spare = Some(std::mem::replace(&mut self.pr, pr));
+                Ok(())
+            },
+            None => {
+                Err(Error::CorruptPage {
+                    page: self.currentPage,
+                    pageType: Some(PageType::LEAF_NODE),
+                    segment: Some(self.segnum),
+                })
+            },
+        }
+    }
+
     fn nextInLeaf(&mut self) -> bool {
         match self.currentKey {
             Some(cur) => {
@@ -2706,7 +5167,7 @@ impl<'a> SegmentCursor<'a> {
             }
         } else {
             let pgnum = self.pr.GetInt32(&mut cur) as PageNum;
-            let mut ostrm = try!(myOverflowReadStream::new(&self.path, self.pr.PageSize(), pgnum, klen));
+            let mut ostrm = try!(self.overflowStream(pgnum, klen));
             let mut x_k = Vec::with_capacity(klen);
             try!(ostrm.read_to_end(&mut x_k));
             let x_k = x_k.into_boxed_slice();
@@ -2737,7 +5198,7 @@ impl<'a> SegmentCursor<'a> {
             }
         } else {
             let pgnum = self.pr.GetInt32(&mut cur) as PageNum;
-            try!(readOverflow(&self.path, self.pr.PageSize(), pgnum, &mut res));
+            try!(readOverflow(&self.path, self.pgsz, pgnum, &mut res));
             Ok(res)
         }
     }
@@ -2765,7 +5226,7 @@ impl<'a> SegmentCursor<'a> {
             // TODO overflowed keys are not prefixed.  should they be?
             let pgnum = self.pr.GetInt32(&mut cur) as PageNum;
             let mut k = vec![0;klen].into_boxed_slice();
-            try!(readOverflow(&self.path, self.pr.PageSize(), pgnum, &mut k));
+            try!(readOverflow(&self.path, self.pgsz, pgnum, &mut k));
             let res = bcmp::Compare(&*k, other);
             Ok(res)
         }
@@ -2834,7 +5295,7 @@ impl<'a> SegmentCursor<'a> {
             // assert mid >= 0
             let cmp = {
                 let q = try!(self.keyInLeaf2(mid));
-                KeyRef::cmp(&q, k)
+                KeyRef::compare_by(self.inner.settings.Comparator, &q, k)
             };
             match cmp {
                 Ordering::Equal => Ok((Some(mid), true)),
@@ -2876,8 +5337,7 @@ impl<'a> SegmentCursor<'a> {
                 cur = cur + klen;
             } else {
                 let firstPage = self.pr.GetInt32(&mut cur) as PageNum;
-                let pgsz = self.pr.PageSize();
-                let mut ostrm = try!(myOverflowReadStream::new(&self.path, pgsz, firstPage, klen));
+                let mut ostrm = try!(self.overflowStream(firstPage, klen));
                 let mut x_k = Vec::with_capacity(klen);
                 try!(ostrm.read_to_end(&mut x_k));
                 let x_k = x_k.into_boxed_slice();
@@ -2966,6 +5426,10 @@ impl<'a> SegmentCursor<'a> {
             if PageType::LEAF_NODE == pt {
                 try!(self.readLeaf());
                 let tmp_countLeafKeys = self.leafKeys.len();
+                if tmp_countLeafKeys == 0 {
+                    // the only leaf of a segment with no pairs
+                    return Ok(SeekResult::Invalid);
+                }
                 let (newCur, equal) = try!(self.searchLeaf(k, 0, (tmp_countLeafKeys - 1), sop, None, None));
                 self.currentKey = newCur;
                 if SeekOp::SEEK_EQ != sop {
@@ -3000,9 +5464,10 @@ impl<'a> SegmentCursor<'a> {
                     Ok(SeekResult::Unequal)
                 }
             } else if PageType::PARENT_NODE == pt {
+                let cmp = self.inner.settings.Comparator;
                 let next = {
                     let (ptrs, keys) = try!(self.readParentPage());
-                    match Self::searchInParentPage(k, &ptrs, &keys, 0) {
+                    match Self::searchInParentPage(cmp, k, &ptrs, &keys, 0) {
                         Some(found) => found,
                         None => ptrs[ptrs.len() - 1],
                     }
@@ -3016,13 +5481,13 @@ impl<'a> SegmentCursor<'a> {
         }
     }
 
-    fn searchInParentPage(k: &KeyRef, ptrs: &Vec<PageNum>, keys: &Vec<KeyRef>, i: usize) -> Option<PageNum> {
+    fn searchInParentPage(c: Option<&'static IComparator>, k: &KeyRef, ptrs: &Vec<PageNum>, keys: &Vec<KeyRef>, i: usize) -> Option<PageNum> {
         // TODO linear search?  really?
         // TODO also, this doesn't need to be recursive
         if i < keys.len() {
-            let cmp = KeyRef::cmp(k, &keys[i]);
+            let cmp = KeyRef::compare_by(c, k, &keys[i]);
             if cmp==Ordering::Greater {
-                Self::searchInParentPage(k, ptrs, keys, i+1)
+                Self::searchInParentPage(c, k, ptrs, keys, i+1)
             } else {
                 Some(ptrs[i])
             }
@@ -3071,7 +5536,7 @@ impl<'a> ICursor<'a> for SegmentCursor<'a> {
                     let vlen = self.pr.GetVarint(&mut pos) as usize;
                     if 0 != (vflag & ValueFlag::FLAG_OVERFLOW) {
                         let pgnum = self.pr.GetInt32(&mut pos) as PageNum;
-                        let strm = try!(myOverflowReadStream::new(&self.path, self.pr.PageSize(), pgnum, vlen));
+                        let strm = try!(self.overflowStream(pgnum, vlen));
                         Ok(ValueRef::Overflowed(vlen, box strm))
                     } else {
                         Ok(ValueRef::Array(self.pr.get_slice(pos, vlen)))
@@ -3102,15 +5567,19 @@ impl<'a> ICursor<'a> for SegmentCursor<'a> {
 
     fn KeyCompare(&self, k_other: &KeyRef) -> Result<Ordering> {
         let k_me = try!(self.KeyRef());
-        let c = KeyRef::cmp(&k_me, &k_other);
+        let c = KeyRef::compare_by(self.inner.settings.Comparator, &k_me, &k_other);
         Ok(c)
     }
 
+    // a segment with no pairs has one leaf, which is empty
+
     fn First(&mut self) -> Result<()> {
         let firstLeaf = self.firstLeaf;
         if try!(self.setCurrentPage(firstLeaf)) {
             try!(self.readLeaf());
-            self.currentKey = Some(0);
+            if !self.leafKeys.is_empty() {
+                self.currentKey = Some(0);
+            }
         }
         Ok(())
     }
@@ -3119,7 +5588,9 @@ impl<'a> ICursor<'a> for SegmentCursor<'a> {
         let lastLeaf = self.lastLeaf;
         if try!(self.setCurrentPage(lastLeaf)) {
             try!(self.readLeaf());
-            self.currentKey = Some(self.leafKeys.len() - 1);
+            if !self.leafKeys.is_empty() {
+                self.currentKey = Some(self.leafKeys.len() - 1);
+            }
         }
         Ok(())
     }
@@ -3156,6 +5627,421 @@ impl<'a> ICursor<'a> for SegmentCursor<'a> {
 
 }
 
+// whatever claims a block of pages in the file
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BlockOwner {
+    Segment(SegmentNum),
+    Waiting(SegmentNum),
+    HeaderOverflow,
+    Free,
+}
+
+// the things check can find wrong.  each one comes with the
+// page where it was noticed.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Problem {
+    // the page could not be read, failed its checksum, or
+    // could not be decoded or decompressed
+    UnreadablePage(SegmentNum, PageNum),
+    WrongPageType(SegmentNum, PageNum),
+    BadPageFlags(SegmentNum, PageNum),
+    // within a leaf or across leaves
+    KeysOutOfOrder(SegmentNum, PageNum),
+    // a child does not agree with its parent about its keys or
+    // its depth, or the chain of leaves is broken
+    ParentChildMismatch(SegmentNum, PageNum),
+    // an overflow has more or fewer pages than its length needs
+    OverflowLength(SegmentNum, PageNum),
+    // a page used by a segment is not in its block list
+    PageOutsideSegment(SegmentNum, PageNum),
+    // two owners claim the same page
+    Overlap(BlockOwner, BlockOwner, PageNum),
+    // a block goes past the end of the allocated pages
+    BeyondEnd(BlockOwner, PageNum),
+}
+
+#[derive(Debug,Clone)]
+pub struct SegmentCheck {
+    pub segment: SegmentNum,
+    pub leaves: usize,
+    pub parents: usize,
+    // the bloom filter and the range tombstones are kept in
+    // overflows too, and are counted here
+    pub overflows: usize,
+    pub keys: usize,
+    // distinct pages reached from the root, the bloom filter and
+    // the range tombstones
+    pub pages: usize,
+}
+
+#[derive(Debug,Clone)]
+pub struct CheckReport {
+    // in currentState order, newest first
+    pub segments: Vec<SegmentCheck>,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// walks one segment for check.  the tree pages are read with a
+// SegmentCursor, which knows how to verify, decode and inflate
+// them.  the overflow pages have no header to speak of, so they
+// get read raw into pr.
+struct SegmentChecker<'a> {
+    pgsz: usize,
+    checksums: bool,
+    transform: Option<&'static IPageTransform>,
+    codec: Option<&'static ICodec>,
+    csr: SegmentCursor<'a>,
+    len: u64,
+    pr: PageBuffer,
+    touched: HashSet<PageNum>,
+    leafDepth: Option<usize>,
+    firstLeaf: PageNum,
+    prevLeaf: PageNum,
+    lastKey: Option<Box<[u8]>>,
+    result: SegmentCheck,
+    problems: Vec<Problem>,
+}
+
+impl<'a> SegmentChecker<'a> {
+    fn touch(&mut self, pgnum: PageNum) {
+        if self.touched.insert(pgnum) && !block_list_contains_page(&self.csr.blocks, pgnum) {
+            self.problems.push(Problem::PageOutsideSegment(self.result.segment, pgnum));
+        }
+    }
+
+    fn readRaw(&mut self, pgnum: PageNum) -> bool {
+        let ok = 
+            pgnum > 0
+            && (pgnum as u64) * (self.pgsz as u64) <= self.len
+            && utils::ReadPageAt(&self.csr.fs, self.pgsz, pgnum, &mut self.pr.buf).is_ok()
+            && self.pr.VerifyChecksum()
+            && self.pr.Decode(pgnum);
+        if !ok {
+            self.problems.push(Problem::UnreadablePage(self.result.segment, pgnum));
+        }
+        ok
+    }
+
+    // follows the pages of an overflow without looking at what is
+    // in them, other than the first page of each block.  len is the
+    // length recorded where the overflow is used.
+    fn overflowPages(&mut self, firstPage: PageNum, len: usize) {
+        let g = self.result.segment;
+        self.result.overflows = self.result.overflows + 1;
+        let end = self.pr.end;
+        let mut remaining = len;
+        let mut pgnum = firstPage;
+        loop {
+            self.touch(pgnum);
+            if !self.readRaw(pgnum) {
+                return;
+            }
+            if self.pr.PageType().ok() != Some(PageType::OVERFLOW_NODE) {
+                self.problems.push(Problem::WrongPageType(g, pgnum));
+                return;
+            }
+            if pgnum == firstPage && self.pr.CheckPageFlag(PageFlag::FLAG_COMPRESSED) {
+                // what is stored is the codec id, the compressed
+                // length, and the compressed data
+                let mut cur = 3;
+                remaining = 1 + SIZE_32 + self.pr.GetInt32(&mut cur) as usize;
+            }
+            let onFirst = end - 2 - SIZE_32;
+            let next = self.pr.GetLastInt32() as PageNum;
+            if self.pr.CheckPageFlag(PageFlag::FLAG_BOUNDARY_NODE) {
+                if remaining <= onFirst {
+                    return;
+                }
+                remaining = remaining - onFirst;
+                pgnum = next;
+            } else if self.pr.CheckPageFlag(PageFlag::FLAG_ENDS_ON_BOUNDARY) {
+                // next is the offset to the boundary page
+                if next == 0 {
+                    self.problems.push(Problem::BadPageFlags(g, pgnum));
+                    return;
+                }
+                let room = onFirst + (next as usize - 1) * end + (end - SIZE_32);
+                // the value may end on the boundary page, but not
+                // before it
+                if remaining <= room - (end - SIZE_32) {
+                    self.problems.push(Problem::OverflowLength(g, firstPage));
+                    return;
+                }
+                for i in 1 .. next {
+                    self.touch(pgnum + i);
+                    if !self.readRaw(pgnum + i) {
+                        return;
+                    }
+                }
+                let boundary = pgnum + next;
+                self.touch(boundary);
+                if !self.readRaw(boundary) {
+                    return;
+                }
+                if remaining <= room {
+                    return;
+                }
+                remaining = remaining - room;
+                pgnum = self.pr.GetLastInt32() as PageNum;
+            } else {
+                // next is the number of regular pages which follow
+                let room = onFirst + next as usize * end;
+                let lastPageRoom = if next == 0 { onFirst } else { end };
+                if remaining > room || remaining + lastPageRoom <= room {
+                    self.problems.push(Problem::OverflowLength(g, firstPage));
+                    return;
+                }
+                for i in 1 .. next + 1 {
+                    self.touch(pgnum + i);
+                    if !self.readRaw(pgnum + i) {
+                        return;
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    // reads a whole overflow the way a cursor would, which checks
+    // the decompression too
+    fn overflowContents(&mut self, firstPage: PageNum, len: usize) -> Option<Box<[u8]>> {
+        let mut a = Vec::with_capacity(len);
+        let ok = 
+            match myOverflowReadStream::new(self.csr.fs.clone(), self.pgsz, self.checksums, self.transform, self.codec, None, firstPage, len, self.result.segment) {
+                Ok(mut strm) => strm.read_to_end(&mut a).is_ok(),
+                Err(_) => false,
+            };
+        if !ok {
+            self.problems.push(Problem::UnreadablePage(self.result.segment, firstPage));
+            None
+        } else if a.len() != len {
+            self.problems.push(Problem::OverflowLength(self.result.segment, firstPage));
+            None
+        } else {
+            Some(a.into_boxed_slice())
+        }
+    }
+
+    fn overflow(&mut self, firstPage: PageNum, len: usize) -> Option<Box<[u8]>> {
+        self.overflowPages(firstPage, len);
+        self.overflowContents(firstPage, len)
+    }
+
+    fn readPage(&mut self, pgnum: PageNum) -> bool {
+        self.touch(pgnum);
+        match self.csr.setCurrentPage(pgnum) {
+            Ok(true) => true,
+            _ => {
+                self.problems.push(Problem::UnreadablePage(self.result.segment, pgnum));
+                false
+            },
+        }
+    }
+
+    // the next block, for a page on a boundary, must be ours too
+    fn checkBoundary(&mut self) {
+        if self.csr.pr.CheckPageFlag(PageFlag::FLAG_BOUNDARY_NODE) {
+            let next = self.csr.pr.GetLastInt32() as PageNum;
+            if !block_list_contains_page(&self.csr.blocks, next) {
+                self.problems.push(Problem::PageOutsideSegment(self.result.segment, next));
+            }
+        }
+    }
+
+    // every key in the subtree must be > lo and <= hi, and the
+    // last one must be hi, since that is where the key in the
+    // parent came from.
+    fn node(&mut self, pgnum: PageNum, depth: usize, lo: Option<&[u8]>, hi: Option<&[u8]>) {
+        let g = self.result.segment;
+        if !self.readPage(pgnum) {
+            return;
+        }
+        let isRoot = depth == 0;
+        match self.csr.pr.PageType() {
+            Ok(PageType::LEAF_NODE) => {
+                match self.leafDepth {
+                    Some(d) if d != depth => self.problems.push(Problem::ParentChildMismatch(g, pgnum)),
+                    _ => self.leafDepth = Some(depth),
+                }
+                if 0 != (self.csr.pr.buf[1] & !PageFlag::FLAG_BOUNDARY_NODE) {
+                    self.problems.push(Problem::BadPageFlags(g, pgnum));
+                }
+                self.checkBoundary();
+                self.leaf(pgnum, lo, hi);
+            },
+            Ok(PageType::PARENT_NODE) => {
+                let flags = self.csr.pr.buf[1];
+                let expected = if isRoot { PageFlag::FLAG_ROOT_NODE } else { 0 };
+                if (flags & PageFlag::FLAG_ROOT_NODE) != expected || 0 != (flags & !(PageFlag::FLAG_ROOT_NODE | PageFlag::FLAG_BOUNDARY_NODE)) {
+                    self.problems.push(Problem::BadPageFlags(g, pgnum));
+                }
+                if !isRoot {
+                    self.checkBoundary();
+                }
+                self.parent(pgnum, depth, lo, hi);
+            },
+            _ => {
+                self.problems.push(Problem::WrongPageType(g, pgnum));
+            },
+        }
+    }
+
+    fn leaf(&mut self, pgnum: PageNum, lo: Option<&[u8]>, hi: Option<&[u8]>) {
+        let g = self.result.segment;
+        let cmp = self.csr.inner.settings.Comparator;
+        self.result.leaves = self.result.leaves + 1;
+        if self.csr.readLeaf().is_err() {
+            self.problems.push(Problem::WrongPageType(g, pgnum));
+            return;
+        }
+        if self.csr.previousLeaf != self.prevLeaf {
+            self.problems.push(Problem::ParentChildMismatch(g, pgnum));
+        }
+        if self.prevLeaf == 0 {
+            self.firstLeaf = pgnum;
+        }
+        self.prevLeaf = pgnum;
+
+        // pull everything out of the leaf first, because reading
+        // the overflows does not use the cursor
+        let mut pairs = Vec::with_capacity(self.csr.leafKeys.len());
+        for i in 0 .. self.csr.leafKeys.len() {
+            let pr = &self.csr.pr;
+            let mut cur = self.csr.leafKeys[i];
+            let kflag = pr.GetByte(&mut cur);
+            let klen = pr.GetVarint(&mut cur) as usize;
+            let k = 
+                if 0 == (kflag & ValueFlag::FLAG_OVERFLOW) {
+                    let mut k = Vec::with_capacity(klen);
+                    let inline = match self.csr.prefix {
+                        Some(ref a) => {
+                            k.push_all(a);
+                            klen - a.len()
+                        },
+                        None => klen,
+                    };
+                    k.push_all(pr.get_slice(cur, inline));
+                    cur = cur + inline;
+                    Ok(k.into_boxed_slice())
+                } else {
+                    Err((pr.GetInt32(&mut cur) as PageNum, klen))
+                };
+            let vflag = pr.GetByte(&mut cur);
+            let v = 
+                if 0 == (vflag & ValueFlag::FLAG_TOMBSTONE) && 0 != (vflag & ValueFlag::FLAG_OVERFLOW) {
+                    let vlen = pr.GetVarint(&mut cur) as usize;
+                    Some((pr.GetInt32(&mut cur) as PageNum, vlen))
+                } else {
+                    None
+                };
+            pairs.push((k, v));
+        }
+
+        let count = pairs.len();
+        for (i, (k, v)) in pairs.into_iter().enumerate() {
+            self.result.keys = self.result.keys + 1;
+            let k = match k {
+                Ok(k) => Some(k),
+                Err((kpage, klen)) => self.overflow(kpage, klen),
+            };
+            match v {
+                Some((vpage, vlen)) => {
+                    self.overflow(vpage, vlen);
+                },
+                None => {
+                },
+            }
+            match k {
+                Some(k) => {
+                    let inOrder = match self.lastKey {
+                        Some(ref prev) => compareKeys(cmp, prev, &k) == Ordering::Less,
+                        None => true,
+                    };
+                    if !inOrder {
+                        self.problems.push(Problem::KeysOutOfOrder(g, pgnum));
+                    }
+                    let aboveLo = lo.map_or(true, |lo| compareKeys(cmp, &k, lo) == Ordering::Greater);
+                    let belowHi = hi.map_or(true, |hi| compareKeys(cmp, &k, hi) != Ordering::Greater);
+                    let lastIsHi = i + 1 < count || hi.map_or(true, |hi| compareKeys(cmp, &k, hi) == Ordering::Equal);
+                    if !(aboveLo && belowHi && lastIsHi) {
+                        self.problems.push(Problem::ParentChildMismatch(g, pgnum));
+                    }
+                    self.lastKey = Some(k);
+                },
+                None => {
+                },
+            }
+        }
+    }
+
+    fn parent(&mut self, pgnum: PageNum, depth: usize, lo: Option<&[u8]>, hi: Option<&[u8]>) {
+        let g = self.result.segment;
+        self.result.parents = self.result.parents + 1;
+        let (ptrs, keys) = {
+            let pr = &self.csr.pr;
+            let mut cur = 2;
+            let count = pr.GetInt16(&mut cur) as usize;
+            let mut ptrs = Vec::with_capacity(count + 1);
+            for _ in 0 .. count + 1 {
+                ptrs.push(pr.GetVarint(&mut cur) as PageNum);
+            }
+            let mut keys = Vec::with_capacity(count);
+            for _ in 0 .. count {
+                let kflag = pr.GetByte(&mut cur);
+                let klen = pr.GetVarint(&mut cur) as usize;
+                if 0 == (kflag & ValueFlag::FLAG_OVERFLOW) {
+                    keys.push(Ok(pr.get_slice(cur, klen).to_vec().into_boxed_slice()));
+                    cur = cur + klen;
+                } else {
+                    keys.push(Err((pr.GetInt32(&mut cur) as PageNum, klen)));
+                }
+            }
+            (ptrs, keys)
+        };
+        let (firstLeaf, lastLeaf) = 
+            if depth == 0 {
+                (self.csr.pr.GetSecondToLastInt32() as PageNum, self.csr.pr.GetLastInt32() as PageNum)
+            } else {
+                (0, 0)
+            };
+
+        let mut bounds = Vec::with_capacity(keys.len());
+        for k in keys {
+            let k = match k {
+                Ok(k) => Some(k),
+                Err((kpage, klen)) => self.overflow(kpage, klen),
+            };
+            match k {
+                Some(k) => bounds.push(k),
+                // without the key, the rest of this parent is a guess
+                None => return,
+            }
+        }
+        for i in 1 .. bounds.len() {
+            if compareKeys(self.csr.inner.settings.Comparator, &bounds[i - 1], &bounds[i]) != Ordering::Less {
+                self.problems.push(Problem::KeysOutOfOrder(g, pgnum));
+                return;
+            }
+        }
+
+        for (i, child) in ptrs.into_iter().enumerate() {
+            let clo = if i == 0 { lo } else { Some(&*bounds[i - 1]) };
+            let chi = if i < bounds.len() { Some(&*bounds[i]) } else { hi };
+            self.node(child, depth + 1, clo, chi);
+        }
+
+        if depth == 0 && (self.firstLeaf != firstLeaf || self.prevLeaf != lastLeaf) {
+            self.problems.push(Problem::ParentChildMismatch(g, pgnum));
+        }
+    }
+}
+
 #[derive(Clone)]
 struct HeaderData {
     // TODO currentState is an ordered copy of segments.Keys.  eliminate duplication?
@@ -3170,6 +6056,30 @@ struct HeaderData {
 
 const HEADER_SIZE_IN_BYTES: usize = 4096;
 
+// the header space is split into two slots.  each header write goes
+// into the slot which does not hold the current header, so a torn
+// write leaves the previous header intact.
+const HEADER_SLOT_SIZE: usize = HEADER_SIZE_IN_BYTES / 2;
+
+// the header starts with this, followed by the format version.
+// the first byte is not ASCII, so a text file will not match,
+// and the file cannot be mistaken for format 1, which started
+// with the page size.
+const FILE_MAGIC: &'static [u8] = b"\x89LSM\r\n\x1a\n";
+
+// 1: no magic, no page checksums
+// 2: magic, format version, page checksums, and FLAG_ENDS_ON_BOUNDARY
+//    is 4.  format 1 wrote it as 3, which has the bit of
+//    FLAG_BOUNDARY_NODE, so format 1 could not read back an overflow
+//    which ended on a boundary page, and neither can the upgrade.
+// 3: two header slots
+// 4: bloom filters
+// 5: compressed pages
+// 6: page transform id in the header
+// 7: range tombstones
+// 8: comparator name in the header
+const FORMAT_VERSION: u32 = 8;
+
 impl PendingSegment {
     fn new(num: SegmentNum) -> PendingSegment {
         // TODO maybe set capacity of the blocklist vec to something low
@@ -3210,19 +6120,125 @@ impl PendingSegment {
     }
 }
 
-fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> where R : Read+Seek {
-    fn read<R>(fs: &mut R) -> Result<PageBuffer> where R : Read {
-        let mut pr = PageBuffer::new(HEADER_SIZE_IN_BYTES);
-        let got = try!(pr.Read(fs));
+// fs is the handle db::new goes on to share with all the readers,
+// so everything in the header is read through it.
+fn readHeader(fs: &Arc<File>, transform: Option<&'static IPageTransform>, comparator: Option<&'static IComparator>) -> Result<(HeaderData,usize,PageNum,SegmentNum,u32)> {
+    fn read<R>(fs: &mut R) -> Result<Box<[u8]>> where R : Read {
+        let mut buf = vec![0; HEADER_SIZE_IN_BYTES].into_boxed_slice();
+        let got = try!(misc::io::read_fully(fs, &mut buf));
         if got < HEADER_SIZE_IN_BYTES {
-            Err(Error::CorruptFile("invalid header"))
+            if got >= FILE_MAGIC.len() && &buf[0 .. FILE_MAGIC.len()] == FILE_MAGIC {
+                Err(Error::CorruptFile("invalid header"))
+            } else {
+                Err(Error::NotADatabase)
+            }
+        } else {
+            Ok(buf)
+        }
+    }
+
+    // returns the header in buf, with its format version, if it
+    // has a valid checksum.  the version gets checked before the
+    // checksum, since a newer format might not have the same kind
+    // of checksum.
+    fn readSlot(buf: &[u8]) -> Result<Option<(PageBuffer,u32)>> {
+        if &buf[0 .. FILE_MAGIC.len()] != FILE_MAGIC {
+            return Ok(None);
+        }
+        let mut pr = PageBuffer::new_page(buf.len(), None);
+        pr.buf.clone_from_slice(buf);
+        let version = pr.GetInt32At(FILE_MAGIC.len());
+        if version > FORMAT_VERSION {
+            Err(Error::UnsupportedFormatVersion(version))
+        } else if pr.VerifyChecksum() {
+            Ok(Some((pr, version)))
         } else {
-            Ok(pr)
+            Ok(None)
+        }
+    }
+
+    // returns the header to be used, with its format version,
+    // leaving cur at the page size.
+    fn chooseHeader(buf: &[u8], cur: &mut usize) -> Result<(PageBuffer,u32)> {
+        // every header write increases either changeCounter or
+        // mergeCounter, so the newer of two valid slots is the one
+        // with the bigger pair.
+        let mut best: Option<(PageBuffer,u32,(u64,u64))> = None;
+        for slot in 0 .. 2 {
+            let at = slot * HEADER_SLOT_SIZE;
+            match try!(readSlot(&buf[at .. at + HEADER_SLOT_SIZE])) {
+                Some((pr, version)) => {
+                    let mut c = FILE_MAGIC.len() + 2 * SIZE_32;
+                    let changeCounter = pr.GetVarint(&mut c);
+                    let mergeCounter = pr.GetVarint(&mut c);
+                    let newer = match best {
+                        Some((_,_,counters)) => (changeCounter, mergeCounter) > counters,
+                        None => true,
+                    };
+                    if newer {
+                        best = Some((pr, version, (changeCounter, mergeCounter)));
+                    }
+                },
+                None => {
+                },
+            }
+        }
+        match best {
+            Some((pr, version, _)) => {
+                *cur = FILE_MAGIC.len() + SIZE_32;
+                return Ok((pr, version));
+            },
+            None => {
+            },
+        }
+
+        // format 2 had one header, which used all the space
+        match try!(readSlot(buf)) {
+            Some((pr, version)) => {
+                *cur = FILE_MAGIC.len() + SIZE_32;
+                return Ok((pr, version));
+            },
+            None => {
+            },
+        }
+
+        if &buf[0 .. FILE_MAGIC.len()] == FILE_MAGIC 
+            || &buf[HEADER_SLOT_SIZE .. HEADER_SLOT_SIZE + FILE_MAGIC.len()] == FILE_MAGIC {
+            Err(Error::CorruptPage {
+                page: 0,
+                pageType: None,
+                segment: None,
+            })
+        } else {
+            // format 1 had no magic.  it started with the page size.
+            let mut pr = PageBuffer::new(HEADER_SIZE_IN_BYTES);
+            pr.buf.clone_from_slice(buf);
+            let pgsz = pr.GetInt32At(0);
+            if pgsz.is_power_of_two() && pgsz >= 256 && pgsz <= 65536 {
+                *cur = 0;
+                Ok((pr, 1))
+            } else {
+                Err(Error::NotADatabase)
+            }
         }
     }
 
-    fn parse<R>(pr: &PageBuffer, cur: &mut usize, fs: &mut R) -> Result<(HeaderData, usize)> where R : Read+Seek {
-        fn readSegmentList(pr: &PageBuffer, cur: &mut usize) -> Result<(Vec<SegmentNum>,HashMap<SegmentNum,SegmentInfo>)> {
+    fn parse(pr: &PageBuffer, cur: &mut usize, fs: &Arc<File>, version: u32, transform: Option<&'static IPageTransform>, comparator: Option<&'static IComparator>) -> Result<(HeaderData, usize)> {
+        fn readGraveyard(fs: &Arc<File>, pgsz: usize, transform: Option<&'static IPageTransform>, firstPage: PageNum, len: usize, g: SegmentNum) -> Result<Graveyard> {
+            let mut strm = try!(myOverflowReadStream::new(fs.clone(), pgsz, true, transform, None, None, firstPage, len, g));
+            let mut a = Vec::with_capacity(len);
+            try!(strm.read_to_end(&mut a));
+            match Graveyard::decode(&a) {
+                Some(g) => Ok(g),
+                None => Err(Error::CorruptPage {
+                    page: firstPage,
+                    pageType: Some(PageType::OVERFLOW_NODE),
+                    segment: Some(g),
+                }),
+            }
+        }
+
+        fn readSegmentList(pr: &PageBuffer, cur: &mut usize, fs: &Arc<File>, pgsz: usize, version: u32, transform: Option<&'static IPageTransform>) -> Result<(Vec<SegmentNum>,HashMap<SegmentNum,SegmentInfo>)> {
             fn readBlockList(prBlocks: &PageBuffer, cur: &mut usize) -> Vec<PageBlock> {
                 let count = prBlocks.GetVarint(cur) as usize;
                 let mut a = Vec::with_capacity(count);
@@ -3249,7 +6265,26 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
                 if !block_list_contains_page(&blocks, root) {
                     return Err(Error::RootPageNotInSegmentBlockList);
                 }
-                let info = SegmentInfo {root:root,age:age,blocks:blocks};
+                // format 4 added bloom filters
+                let bloomPage = if version >= 4 { pr.GetVarint(cur) as PageNum } else { 0 };
+                let bloom = 
+                    if bloomPage != 0 {
+                        let len = pr.GetVarint(cur) as usize;
+                        Some(SegmentBloom {firstPage: bloomPage, len: len})
+                    } else {
+                        None
+                    };
+                // format 7 added range tombstones
+                let graveyardPage = if version >= 7 { pr.GetVarint(cur) as PageNum } else { 0 };
+                let graveyard = 
+                    if graveyardPage != 0 {
+                        let len = pr.GetVarint(cur) as usize;
+                        let ranges = try!(readGraveyard(&fs, pgsz, transform, graveyardPage, len, g));
+                        Some(SegmentGraveyard {firstPage: graveyardPage, len: len, ranges: Arc::new(ranges)})
+                    } else {
+                        None
+                    };
+                let info = SegmentInfo {root:root,age:age,blocks:blocks,bloom:bloom,graveyard:graveyard,committed:0};
                 m.insert(g,info);
             }
             Ok((a,m))
@@ -3260,6 +6295,24 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
         let pgsz = pr.GetInt32(cur) as usize;
         let changeCounter = pr.GetVarint(cur);
         let mergeCounter = pr.GetVarint(cur);
+        // format 6 added the page transform.  before that, there
+        // was never one.
+        let transformId = if version >= 6 { pr.GetByte(cur) } else { 0 };
+        if transformId != pageTransformId(transform) {
+            return Err(Error::WrongPageTransform(transformId));
+        }
+        // format 8 added the comparator name.  before that, keys
+        // were always in plain byte order.
+        if version >= 8 {
+            let len = pr.GetVarint(cur) as usize;
+            let name = pr.get_slice(*cur, len);
+            *cur = *cur + len;
+            if name != comparatorName(comparator).as_bytes() {
+                return Err(Error::WrongComparator(String::from_utf8_lossy(name).into_owned()));
+            }
+        } else if comparator.is_some() {
+            return Err(Error::WrongComparator(String::new()));
+        }
         let lenSegmentList = pr.GetVarint(cur) as usize;
 
         let overflowed = pr.GetByte(cur) != 0u8;
@@ -3268,21 +6321,37 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
                 let lenChunk1 = pr.GetInt32(cur) as usize;
                 let lenChunk2 = lenSegmentList - lenChunk1;
                 let firstPageChunk2 = pr.GetInt32(cur) as PageNum;
+                // chunk2 is not made of pages, so it has no checksums
+                // of its own.  instead, the header (which does have a
+                // checksum) has one for the whole segment list.
+                let crc = if version >= 2 { Some(pr.GetInt32(cur)) } else { None };
                 let extraPages = lenChunk2 / pgsz + if (lenChunk2 % pgsz) != 0 { 1 } else { 0 };
                 let extraPages = extraPages as PageNum;
                 let lastPageChunk2 = firstPageChunk2 + extraPages - 1;
                 let mut pr2 = PageBuffer::new(lenSegmentList);
                 // TODO chain?
-                // copy from chunk1 into pr2
-                try!(pr2.ReadPart(fs, 0, lenChunk1));
+                // chunk1 is the rest of the header page, already in pr
+                pr.GetIntoArray(cur, &mut pr2.buf[0 .. lenChunk1]);
                 // now get chunk2 and copy it in as well
-                try!(utils::SeekPage(fs, pgsz, firstPageChunk2));
-                try!(pr2.ReadPart(fs, lenChunk1, lenChunk2));
+                let mut strm = &**fs;
+                try!(utils::SeekPage(&mut strm, pgsz, firstPageChunk2));
+                let got = try!(pr2.ReadPart(&mut strm, lenChunk1, lenChunk2));
+                let ok = match crc {
+                    Some(crc) => crc == crc32::checksum(&pr2.buf),
+                    None => true,
+                };
+                if got < lenChunk2 || !ok {
+                    return Err(Error::CorruptPage {
+                        page: firstPageChunk2,
+                        pageType: None,
+                        segment: None,
+                    });
+                }
                 let mut cur2 = 0;
-                let (state, segments) = try!(readSegmentList(&pr2, &mut cur2));
+                let (state, segments) = try!(readSegmentList(&pr2, &mut cur2, fs, pgsz, version, transform));
                 (state, segments, Some (PageBlock::new(firstPageChunk2, lastPageChunk2)))
             } else {
-                let (state,segments) = try!(readSegmentList(pr, cur));
+                let (state,segments) = try!(readSegmentList(pr, cur, fs, pgsz, version, transform));
                 (state, segments, None)
             };
 
@@ -3307,18 +6376,20 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
 
     // --------
 
-    let len = try!(misc::io::seek_len(fs));
+    let mut strm = &**fs;
+    let len = try!(misc::io::seek_len(&mut strm));
     if len > 0 {
-        try!(fs.seek(SeekFrom::Start(0 as u64)));
-        let pr = try!(read(fs));
+        try!(strm.seek(SeekFrom::Start(0 as u64)));
+        let buf = try!(read(&mut strm));
         let mut cur = 0;
-        let (h, pgsz) = try!(parse(&pr, &mut cur, fs));
+        let (pr, version) = try!(chooseHeader(&buf, &mut cur));
+        let (h, pgsz) = try!(parse(&pr, &mut cur, fs, version, transform, comparator));
         let nextAvailablePage = calcNextPage(pgsz, len as usize);
         let nextAvailableSegmentNum = match h.currentState.iter().max() {
             Some(n) => n+1,
             None => 1,
         };
-        Ok((h, pgsz, nextAvailablePage, nextAvailableSegmentNum))
+        Ok((h, pgsz, nextAvailablePage, nextAvailableSegmentNum, version))
     } else {
         let defaultPageSize = DEFAULT_SETTINGS.DefaultPageSize;
         let h = 
@@ -3332,7 +6403,7 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
             };
         let nextAvailablePage = calcNextPage(defaultPageSize, HEADER_SIZE_IN_BYTES);
         let nextAvailableSegmentNum = 1;
-        Ok((h, defaultPageSize, nextAvailablePage, nextAvailableSegmentNum))
+        Ok((h, defaultPageSize, nextAvailablePage, nextAvailableSegmentNum, FORMAT_VERSION))
     }
 
 }
@@ -3396,6 +6467,12 @@ fn listAllBlocks(h: &HeaderData, segmentsInWaiting: &HashMap<SegmentNum,SegmentI
 }
 
 use std::sync::Mutex;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::mpsc;
+use std::sync::TryLockError;
+use std::thread;
+use std::time::Duration;
 
 struct NextSeg {
     nextSeg: SegmentNum,
@@ -3406,6 +6483,246 @@ struct Space {
     freeBlocks: Vec<PageBlock>,
 }
 
+// decoded parent and leaf pages, keyed by page number.  the least
+// recently used page goes first when the cache is full.  pages are
+// only cached while their segment is alive, and addFreeBlocks
+// throws them out before the space can be reused.
+struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<PageNum,(u64,Box<[u8]>)>,
+    lru: BTreeMap<u64,PageNum>,
+    hits: u64,
+    misses: u64,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity: capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn touch(&mut self, pgnum: PageNum) -> u64 {
+        self.tick = self.tick + 1;
+        self.lru.insert(self.tick, pgnum);
+        self.tick
+    }
+
+    // copies the page into buf, if we have it
+    fn get(&mut self, pgnum: PageNum, buf: &mut [u8]) -> bool {
+        match self.pages.get(&pgnum) {
+            Some(&(t, ref page)) => {
+                buf.clone_from_slice(page);
+                self.lru.remove(&t);
+            },
+            None => {
+                self.misses = self.misses + 1;
+                return false;
+            },
+        }
+        self.hits = self.hits + 1;
+        let t = self.touch(pgnum);
+        self.pages.get_mut(&pgnum).unwrap().0 = t;
+        true
+    }
+
+    fn put(&mut self, pgnum: PageNum, buf: &[u8]) {
+        if self.pages.contains_key(&pgnum) {
+            return;
+        }
+        while self.pages.len() >= self.capacity {
+            let (t, old) = match self.lru.iter().next() {
+                Some((t, old)) => (*t, *old),
+                None => break,
+            };
+            self.lru.remove(&t);
+            self.pages.remove(&old);
+        }
+        let t = self.touch(pgnum);
+        let mut page = vec![0; buf.len()].into_boxed_slice();
+        page.clone_from_slice(buf);
+        self.pages.insert(pgnum, (t, page));
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+    }
+
+    fn invalidate(&mut self, blocks: &Vec<PageBlock>) {
+        let gone: Vec<PageNum> = self.pages.keys().filter(|p| block_list_contains_page(blocks, **p)).map(|p| *p).collect();
+        for pgnum in gone {
+            let (t, _) = self.pages.remove(&pgnum).unwrap();
+            self.lru.remove(&t);
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub pages: usize,
+}
+
+// bloom filters, keyed by their first page.  like the page cache,
+// the least recently used filter goes first, and addFreeBlocks
+// throws out the ones whose pages are being freed.  unlike it,
+// the capacity is in bytes, since filters vary so much in size.
+struct BloomCache {
+    capacity: usize,
+    tick: u64,
+    filters: HashMap<PageNum,(u64,Arc<BloomFilter>)>,
+    lru: BTreeMap<u64,PageNum>,
+    bytes: usize,
+    reads: u64,
+}
+
+impl BloomCache {
+    fn new(capacity: usize) -> BloomCache {
+        BloomCache {
+            capacity: capacity,
+            tick: 0,
+            filters: HashMap::new(),
+            lru: BTreeMap::new(),
+            bytes: 0,
+            reads: 0,
+        }
+    }
+
+    fn touch(&mut self, pgnum: PageNum) -> u64 {
+        self.tick = self.tick + 1;
+        self.lru.insert(self.tick, pgnum);
+        self.tick
+    }
+
+    fn get(&mut self, pgnum: PageNum) -> Option<Arc<BloomFilter>> {
+        let (t, f) = match self.filters.get(&pgnum) {
+            Some(&(t, ref f)) => (t, f.clone()),
+            None => return None,
+        };
+        self.lru.remove(&t);
+        let t = self.touch(pgnum);
+        self.filters.get_mut(&pgnum).unwrap().0 = t;
+        Some(f)
+    }
+
+    fn remove(&mut self, pgnum: PageNum) {
+        match self.filters.remove(&pgnum) {
+            Some((t, f)) => {
+                self.lru.remove(&t);
+                self.bytes = self.bytes - f.bits.len();
+            },
+            None => {
+            },
+        }
+    }
+
+    fn put(&mut self, pgnum: PageNum, f: Arc<BloomFilter>) {
+        let len = f.bits.len();
+        if len > self.capacity || self.filters.contains_key(&pgnum) {
+            return;
+        }
+        while self.bytes + len > self.capacity {
+            let old = match self.lru.iter().next() {
+                Some((_, old)) => *old,
+                None => break,
+            };
+            self.remove(old);
+        }
+        let t = self.touch(pgnum);
+        self.filters.insert(pgnum, (t, f));
+        self.bytes = self.bytes + len;
+    }
+
+    fn invalidate(&mut self, blocks: &Vec<PageBlock>) {
+        let gone: Vec<PageNum> = self.filters.keys().filter(|p| block_list_contains_page(blocks, **p)).map(|p| *p).collect();
+        for pgnum in gone {
+            self.remove(pgnum);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.filters.clear();
+        self.lru.clear();
+        self.bytes = 0;
+    }
+}
+
+// how many times the file and the write-ahead log have been synced
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SyncStats {
+    pub file: u64,
+    pub log: u64,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct BloomCacheStats {
+    // how many times a filter had to be read from the file
+    pub reads: u64,
+    pub filters: usize,
+    pub bytes: usize,
+}
+
+// every handle on the file, once db::new is done reading the
+// header.  readers only use positional reads, so they all share
+// one handle.  a writer seeks, so it gets a handle to itself, which
+// goes back to the pool when it is dropped.  the pool keeps one of
+// those for the next writer and closes the rest.
+struct FilePool {
+    max: usize,
+    // counting the reader, the idle one, and the ones out with
+    // writers
+    open: usize,
+    reader: Arc<File>,
+    idle: Option<File>,
+}
+
+struct PooledFile<'a> {
+    fs: Option<File>,
+    pool: &'a Mutex<FilePool>,
+    returned: &'a Condvar,
+}
+
+impl<'a> std::ops::Deref for PooledFile<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.fs.as_ref().unwrap()
+    }
+}
+
+impl<'a> std::ops::DerefMut for PooledFile<'a> {
+    fn deref_mut(&mut self) -> &mut File {
+        self.fs.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledFile<'a> {
+    fn drop(&mut self) {
+        // even if the pool was poisoned, the handle has to be
+        // counted as returned, or a writer waiting for one would
+        // wait forever.
+        let mut pool = match self.pool.lock() {
+            Ok(pool) => pool,
+            Err(e) => e.into_inner(),
+        };
+        if pool.idle.is_none() {
+            pool.idle = self.fs.take();
+        } else {
+            // closed when it goes out of scope
+            pool.open = pool.open - 1;
+        }
+        self.returned.notify_one();
+    }
+}
+
 struct SafeSegmentsInWaiting {
     segmentsInWaiting: HashMap<SegmentNum,SegmentInfo>,
 }
@@ -3418,6 +6735,8 @@ struct SafeMergeStuff {
 struct SafeHeader {
     // TODO one level too much nesting
     header: HeaderData,
+    // how many times the file has been synced
+    syncs: u64,
 }
 
 struct SafeCursors {
@@ -3430,6 +6749,9 @@ struct InnerPart {
     path: String,
     pgsz: usize,
     settings: DbSettings,
+    // the format version of the segments in the file.  this is
+    // only older than FORMAT_VERSION while db::new is upgrading.
+    format: u32,
 
     nextSeg: Mutex<NextSeg>,
     space: Mutex<Space>,
@@ -3438,38 +6760,136 @@ struct InnerPart {
     segmentsInWaiting: Mutex<SafeSegmentsInWaiting>,
     mergeStuff: Mutex<SafeMergeStuff>,
     cursors: Mutex<SafeCursors>,
+    memtable: Mutex<SafeMemtable>,
+    pageCache: Mutex<PageCache>,
+    bloomCache: Mutex<BloomCache>,
+    // only used with MmapReads.  replaced by a bigger one when the
+    // file grows, but cursors keep the old one alive as long as
+    // they have pages in it.
+    fileMap: Mutex<Option<Arc<FileMap>>>,
+    files: Mutex<FilePool>,
+    // held by a WriteLock, and by anybody else who commits
+    // segments.  taken before the memtable lock.
+    writeLock: Mutex<()>,
+    // signalled whenever a writer gives its handle back
+    fileReturned: Condvar,
+    // wakes up the background merge thread.  None if
+    // AutoMergeEnabled is off, or if the db is shutting down.
+    merger: Mutex<Option<mpsc::Sender<()>>>,
+    // the first error from an automatic merge since anybody asked.
+    // automatic merges happen after a commit, or on the merge thread,
+    // where there is nobody to give the error to.  the same goes for
+    // cleaning up after a flush, once it has been committed or after
+    // it has already failed.
+    mergeError: Mutex<Option<Error>>,
+}
+
+// the state of the database at one point in time.  cursors opened
+// on a snapshot all see that state, no matter what has been committed
+// since.  the segments in the snapshot are pinned, so merges cannot
+// free them until the snapshot is dropped.
+pub struct Snapshot<'a> {
+    inner: &'a InnerPart,
+    changeCounter: u64,
+    currentState: Vec<SegmentNum>,
+    segments: HashMap<SegmentNum,SegmentInfo>,
+    // the layers of the memtable, newest first
+    memtable: Vec<MemtableLayer>,
+    pins: Vec<(SegmentNum,u64)>,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn changeCounter(&self) -> u64 {
+        self.changeCounter
+    }
+
+    pub fn segments(&self) -> &[SegmentNum] {
+        &self.currentState
+    }
+
+    pub fn OpenCursor(&self) -> Result<LivingCursor<'a>> {
+        let mut clist = Vec::with_capacity(self.currentState.len() + self.memtable.len());
+        for layer in self.memtable.iter() {
+            clist.push(SubCursor::Memtable(MemtableCursor::new(layer, self.inner.settings.Comparator)));
+        }
+        for g in self.currentState.iter() {
+            let info = self.segments.get(g).unwrap();
+            clist.push(SubCursor::Segment(try!(self.inner.getCursorOn(*g, info))));
+        }
+        let mc = MultiCursor::Create(clist, self.inner.settings.Comparator);
+        let lc = LivingCursor::Create(mc);
+        Ok(lc)
+    }
+
+    pub fn get(&self, k: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let mut csr = try!(self.OpenCursor());
+        getValue(&mut csr, k)
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        for &(g, csrnum) in self.pins.iter() {
+            self.inner.cursor_dropped(g, csrnum);
+        }
+    }
 }
 
+// only one of these at a time.  the lock is released when it
+// is dropped.
 pub struct WriteLock<'a> {
-    inner: Option<&'a InnerPart>
+    inner: &'a InnerPart,
+    _held: std::sync::MutexGuard<'a, ()>,
 }
 
 impl<'a> WriteLock<'a> {
     pub fn commitSegments(&self, newSegs: Vec<SegmentNum>) -> Result<()> {
-        self.inner.unwrap().commitSegments(newSegs)
+        let inner = self.inner;
+        try!(inner.commitSegmentsAfterMemtable(newSegs));
+        inner.autoMerge();
+        Ok(())
+    }
+
+    // for optimistic writes.  grab a snapshot, read from it, write
+    // segments, then commit them with this, passing the keys which
+    // were read.  if any of them was written since the snapshot,
+    // even back to the value it had, nothing is committed and the
+    // error is Conflict.  the segments stay in waiting.
+    pub fn commitSegmentsUnlessChanged(&self, snap: &Snapshot, keys: &[&[u8]], newSegs: Vec<SegmentNum>) -> Result<()> {
+        let inner = self.inner;
+        try!(inner.commitSegmentsUnlessChanged(snap, keys, newSegs));
+        inner.autoMerge();
+        Ok(())
     }
 
     pub fn commitMerge(&self, newSegNum:SegmentNum) -> Result<()> {
-        self.inner.unwrap().commitMerge(newSegNum)
+        self.inner.commitMerge(newSegNum)
     }
 }
 
 // TODO rename this
-pub struct db<'a> {
+pub struct db {
 
-    inner: InnerPart,
-    write_lock: Mutex<WriteLock<'a>>,
+    inner: Arc<InnerPart>,
+    mergeThread: Option<MergeThread>,
 }
 
-impl<'a> db<'a> {
-    pub fn new(path: String, settings : DbSettings) -> Result<db<'a>> {
+impl db {
+    pub fn new(path: String, settings : DbSettings) -> Result<db> {
+
+        // the reader takes one handle, and a writer needs another
+        if settings.MaxOpenFiles == 1 && !settings.ReadOnly {
+            return Err(Error::TooManyOpenFiles(settings.MaxOpenFiles));
+        }
 
-        let mut f = try!(OpenOptions::new()
+        // the handle which reads the header becomes the one all
+        // the readers share
+        let f = Arc::new(try!(OpenOptions::new()
                 .read(true)
-                .create(true)
-                .open(&path));
+                .create(!settings.ReadOnly)
+                .open(&path)));
 
-        let (header,pgsz,firstAvailablePage,nextAvailableSegmentNum) = try!(readHeader(&mut f));
+        let (header,pgsz,firstAvailablePage,nextAvailableSegmentNum,format) = try!(readHeader(&f, settings.PageTransform, settings.Comparator));
 
         let segmentsInWaiting = HashMap::new();
         let mut blocks = listAllBlocks(&header, &segmentsInWaiting, pgsz);
@@ -3497,6 +6917,7 @@ impl<'a> db<'a> {
 
         let header = SafeHeader {
             header: header, 
+            syncs: 0,
         };
 
         let cursors = SafeCursors {
@@ -3505,38 +6926,152 @@ impl<'a> db<'a> {
             zombies: HashMap::new(),
         };
 
-        let inner = InnerPart {
+        // anything in the write-ahead log was written to the memtable
+        // but never flushed, so it goes back into the memtable now,
+        // before anybody gets a chance to see this db.
+        let mut wal = if settings.ReadOnly { None } else { Some(try!(WriteAheadLog::open(&path, settings.PageTransform))) };
+        let mut mt = Memtable::new(settings.Comparator);
+        let recs = match wal {
+            Some(ref mut wal) => try!(wal.replay(header.header.changeCounter)),
+            None => Vec::new(),
+        };
+        for rec in recs {
+            match rec {
+                LogRecord::Batch(pairs) => {
+                    for (k,v) in pairs {
+                        mt.insert(k, v);
+                    }
+                },
+                LogRecord::DeleteRange(start, end) => {
+                    mt.delete_range(&start, &end);
+                },
+                LogRecord::Flushed(_) => {
+                    unreachable!();
+                },
+            }
+        }
+        let memtable = SafeMemtable {
+            memtable: mt,
+            wal: wal,
+        };
+
+        let (merger, rx) =
+            if settings.AutoMergeEnabled && !settings.ReadOnly {
+                let (tx, rx) = mpsc::channel();
+                (Some(tx), Some(rx))
+            } else {
+                (None, None)
+            };
+
+        let pageCache = PageCache::new(settings.PageCacheSize);
+        let bloomCache = BloomCache::new(settings.BloomCacheBytes);
+
+        let files = FilePool {
+            max: settings.MaxOpenFiles,
+            open: 1,
+            reader: f,
+            idle: None,
+        };
+
+        let mut inner = InnerPart {
             path: path,
             pgsz: pgsz,
             settings: settings, 
+            format: format,
             header: Mutex::new(header),
             nextSeg: Mutex::new(nextSeg),
             space: Mutex::new(space),
             segmentsInWaiting: Mutex::new(segmentsInWaiting),
             mergeStuff: Mutex::new(mergeStuff),
             cursors: Mutex::new(cursors),
+            memtable: Mutex::new(memtable),
+            pageCache: Mutex::new(pageCache),
+            bloomCache: Mutex::new(bloomCache),
+            fileMap: Mutex::new(None),
+            files: Mutex::new(files),
+            writeLock: Mutex::new(()),
+            fileReturned: Condvar::new(),
+            merger: Mutex::new(merger),
+            mergeError: Mutex::new(None),
         };
+        if inner.format < FORMAT_VERSION && !inner.settings.ReadOnly {
+            try!(inner.upgradeFormat());
+            inner.format = FORMAT_VERSION;
+        }
+        let inner = Arc::new(inner);
+
+        let mergeThread = rx.map(|rx| {
+            let bg = inner.clone();
+            let h = thread::spawn(move || {
+                // recv fails when the sender has been dropped,
+                // which is how the db tells us to stop.
+                while rx.recv().is_ok() {
+                    // several commits may have asked for a merge
+                    // since we last looked.  one pass is enough.
+                    while rx.try_recv().is_ok() {
+                    }
+                    bg.backgroundMerge();
+                }
+            });
+            MergeThread {
+                inner: inner.clone(),
+                handle: Some(h),
+            }
+        });
 
-        // WriteLock contains a reference to another part of
-        // the struct it is in.  So we wrap it in an option,
-        // and set it to null for now.  We set it later when
-        // somebody actually asks for the lock.
-
-        let lck = WriteLock { inner: None };
         let res = db {
             inner: inner,
-            write_lock: Mutex::new(lck),
+            mergeThread: mergeThread,
         };
         Ok(res)
     }
 
-    // TODO func to ask for the write lock without blocking?
+    // an automatic merge (or the cleanup after a flush) which failed
+    // since the last call is reported here (or by try_write_lock,
+    // write_lock_timeout or flush), instead of the lock.  the next
+    // call gets the lock.
+    pub fn GetWriteLock(&self) -> Result<WriteLock> {
+        try!(self.inner.takeMergeError());
+        let held = try!(self.inner.writeLock.lock());
+        Ok(WriteLock { inner: &*self.inner, _held: held })
+    }
+
+    // like GetWriteLock, but returns Busy instead of waiting
+    pub fn try_write_lock(&self) -> Result<WriteLock> {
+        try!(self.inner.takeMergeError());
+        match self.inner.writeLock.try_lock() {
+            Ok(held) => {
+                Ok(WriteLock { inner: &*self.inner, _held: held })
+            },
+            Err(TryLockError::WouldBlock) => Err(Error::Busy),
+            Err(TryLockError::Poisoned(_)) => Err(Error::Poisoned),
+        }
+    }
 
-    pub fn GetWriteLock(&'a self) -> Result<std::sync::MutexGuard<WriteLock<'a>>> {
-        let mut lck = try!(self.write_lock.lock());
-        // set the inner reference
-        lck.inner = Some(&self.inner);
-        Ok(lck)
+    // like GetWriteLock, but gives up with Busy after waiting
+    // for about the given time.  Mutex has no timed lock, so
+    // this polls, backing off a little more each time.
+    pub fn write_lock_timeout(&self, timeout: Duration) -> Result<WriteLock> {
+        let mut waited = Duration::new(0, 0);
+        let mut pause = Duration::from_millis(1);
+        loop {
+            match self.try_write_lock() {
+                Err(Error::Busy) => {
+                    if waited >= timeout {
+                        return Err(Error::Busy);
+                    }
+                    if pause > timeout - waited {
+                        pause = timeout - waited;
+                    }
+                    thread::sleep(pause);
+                    waited = waited + pause;
+                    if pause < Duration::from_millis(20) {
+                        pause = pause * 2;
+                    }
+                },
+                r => return r,
+            }
+        }
     }
 
     // the following methods are passthrus, exposing inner
@@ -3546,6 +7081,106 @@ impl<'a> db<'a> {
         self.inner.OpenCursor()
     }
 
+    // the live pairs in r, which can be walked from either end
+    pub fn range<'b, R: IKeyRange<'b>>(&self, r: R) -> Result<RangeIterator> {
+        let csr = try!(self.inner.OpenCursor());
+        Ok(RangeIterator::new(csr, r.start(), r.end()))
+    }
+
+    // the live pairs whose keys start with p.  they are only all
+    // next to each other if the keys are in byte order, so this
+    // needs the default comparator.
+    pub fn prefix(&self, p: &[u8]) -> Result<RangeIterator> {
+        if self.inner.settings.Comparator.is_some() {
+            return Err(Error::Misc("prefix needs keys in byte order"));
+        }
+        let csr = try!(self.inner.OpenCursor());
+        let end = prefixEnd(p);
+        let hi = match end {
+            Some(ref k) => Bound::Excluded(&**k),
+            None => Bound::Unbounded,
+        };
+        Ok(RangeIterator::new(csr, Bound::Included(p), hi))
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    pub fn open_segment_cursor(&self, g: SegmentNum) -> Result<SegmentCursor> {
+        self.inner.openSegmentCursor(g)
+    }
+
+    // reads every page of every committed segment, and makes sure
+    // that no page is claimed twice
+    pub fn check(&self) -> Result<CheckReport> {
+        self.inner.check()
+    }
+
+    // the following methods are for diagnostics.  they return
+    // copies, which may be out of date as soon as they return.
+
+    // the committed segments, newest first, and their infos
+    pub fn ListSegments(&self) -> Result<(Vec<SegmentNum>,HashMap<SegmentNum,SegmentInfo>)> {
+        let st = try!(self.inner.header.lock());
+        Ok((st.header.currentState.clone(), st.header.segments.clone()))
+    }
+
+    // sorted by size, largest first
+    pub fn GetFreeBlocks(&self) -> Result<Vec<PageBlock>> {
+        let space = try!(self.inner.space.lock());
+        Ok(space.freeBlocks.clone())
+    }
+
+    // the first page past the end of everything allocated so far
+    pub fn NextPage(&self) -> Result<PageNum> {
+        let space = try!(self.inner.space.lock());
+        Ok(space.nextPage)
+    }
+
+    pub fn PageSize(&self) -> usize {
+        self.inner.pgsz
+    }
+
+    pub fn ChangeCounter(&self) -> Result<u64> {
+        let st = try!(self.inner.header.lock());
+        Ok(st.header.changeCounter)
+    }
+
+    pub fn MergeCounter(&self) -> Result<u64> {
+        let st = try!(self.inner.header.lock());
+        Ok(st.header.mergeCounter)
+    }
+
+    pub fn PageCacheStats(&self) -> Result<PageCacheStats> {
+        self.inner.PageCacheStats()
+    }
+
+    pub fn BloomCacheStats(&self) -> Result<BloomCacheStats> {
+        let cache = try!(self.inner.bloomCache.lock());
+        let st = BloomCacheStats {
+            reads: cache.reads,
+            filters: cache.filters.len(),
+            bytes: cache.bytes,
+        };
+        Ok(st)
+    }
+
+    pub fn SyncStats(&self) -> Result<SyncStats> {
+        let mt = try!(self.inner.memtable.lock());
+        let log = match mt.wal {
+            Some(ref wal) => wal.syncs,
+            None => 0,
+        };
+        let st = try!(self.inner.header.lock());
+        Ok(SyncStats { file: st.syncs, log: log })
+    }
+
+    // handles on the file held by the pool, in use or not
+    pub fn OpenFileCount(&self) -> Result<usize> {
+        self.inner.OpenFileCount()
+    }
+
     pub fn WriteSegmentFromSortedSequence<I>(&self, source: I) -> Result<SegmentNum> where I:Iterator<Item=Result<kvp>> {
         self.inner.WriteSegmentFromSortedSequence(source)
     }
@@ -3561,6 +7196,248 @@ impl<'a> db<'a> {
     pub fn merge(&self, level: u32, min: usize, max: Option<usize>) -> Result<Option<SegmentNum>> {
         self.inner.merge(level, min, max)
     }
+
+    // gives back the pages of segments which were written, by
+    // WriteSegment or merge, but which are not going to be committed
+    pub fn forget_waiting_segments(&self, segs: &[SegmentNum]) -> Result<()> {
+        self.inner.forgetWaitingSegments(segs)
+    }
+
+    // put, delete, delete_range and write_batch append to the
+    // write-ahead log and then to the memtable.  if that leaves the
+    // memtable full, it is flushed before they return, and an error
+    // from the flush is returned.  such an error does not mean the
+    // write was lost.  it is in the log and the memtable, and the
+    // next flush will try again.
+    pub fn put(&self, k: &[u8], v: &[u8]) -> Result<()> {
+        self.inner.put(k, Some(v))
+    }
+
+    pub fn delete(&self, k: &[u8]) -> Result<()> {
+        self.inner.put(k, None)
+    }
+
+    // deletes every key k with start <= k < end
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.deleteRange(start, end)
+    }
+
+    // all the pairs in the batch go into the write-ahead log
+    // as one record.
+    pub fn write_batch(&self, pairs: HashMap<Box<[u8]>,Blob>) -> Result<()> {
+        let mut batch = Vec::with_capacity(pairs.len());
+        for (k,v) in pairs {
+            let v = match v {
+                Blob::Array(a) => Some(a),
+                Blob::Stream(mut strm) => {
+                    let mut a = Vec::new();
+                    try!(strm.read_to_end(&mut a));
+                    Some(a.into_boxed_slice())
+                },
+                Blob::Tombstone => None,
+            };
+            batch.push((k, v));
+        }
+        self.inner.writeBatch(batch)
+    }
+
+    pub fn get(&self, k: &[u8]) -> Result<Option<Box<[u8]>>> {
+        self.inner.get(k)
+    }
+
+    // write the memtable out as a segment and commit it,
+    // even if it has not reached MaxMemtableBytes yet.  this
+    // takes the write lock, so the caller must not hold it.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    // get everything written so far onto the disk, including the
+    // write-ahead log, regardless of SyncMode and SyncWal.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+// collects pairs, writing a segment whenever there are maxPairs
+// of them or they add up to maxBytes (a limit of 0 means no limit),
+// and then commits all of those segments at once.  a stream value
+// is read into memory when it is added, so that it counts toward
+// maxBytes like any other.  a later pair
+// for the same key replaces an earlier one, even after a flush,
+// because later segments are committed as newer.
+pub struct PairBuffer<'a> {
+    inner: &'a InnerPart,
+    maxPairs: usize,
+    maxBytes: usize,
+    // None is a tombstone
+    pairs: HashMap<Box<[u8]>,Option<Box<[u8]>>>,
+    bytes: usize,
+    // oldest first
+    segs: Vec<SegmentNum>,
+}
+
+impl<'a> PairBuffer<'a> {
+    pub fn new(db: &'a db, maxPairs: usize, maxBytes: usize) -> PairBuffer<'a> {
+        PairBuffer {
+            inner: &*db.inner,
+            maxPairs: maxPairs,
+            maxBytes: maxBytes,
+            pairs: HashMap::new(),
+            bytes: 0,
+            segs: Vec::new(),
+        }
+    }
+
+    fn size(klen: usize, v: &Option<Box<[u8]>>) -> usize {
+        match *v {
+            Some(ref a) => klen + a.len(),
+            None => klen,
+        }
+    }
+
+    pub fn AddPair(&mut self, k: Box<[u8]>, v: Blob) -> Result<()> {
+        let v = match v {
+            Blob::Stream(mut strm) => {
+                let mut a = Vec::new();
+                try!(strm.read_to_end(&mut a));
+                Some(a.into_boxed_slice())
+            },
+            Blob::Array(a) => Some(a),
+            Blob::Tombstone => None,
+        };
+        let klen = k.len();
+        let size = Self::size(klen, &v);
+        match self.pairs.insert(k, v) {
+            Some(old) => {
+                let oldSize = Self::size(klen, &old);
+                self.bytes = self.bytes + size - oldSize;
+            },
+            None => {
+                self.bytes = self.bytes + size;
+            },
+        }
+        let full = 
+            (self.maxPairs > 0 && self.pairs.len() >= self.maxPairs)
+            || (self.maxBytes > 0 && self.bytes >= self.maxBytes);
+        if full {
+            try!(self.Flush());
+        }
+        Ok(())
+    }
+
+    pub fn AddTombstone(&mut self, k: Box<[u8]>) -> Result<()> {
+        self.AddPair(k, Blob::Tombstone)
+    }
+
+    // the pairs are only copied as the segment gets written, and
+    // they stay until it has been, so a failed write loses none of
+    // them.
+    pub fn Flush(&mut self) -> Result<()> {
+        if !self.pairs.is_empty() {
+            let g = {
+                let c = self.inner.settings.Comparator;
+                let mut a = self.pairs.iter().collect::<Vec<_>>();
+                a.sort_by(|a,b| compareKeys(c, a.0, b.0));
+                let source = a.into_iter().map(|(k,v)| {
+                    let v = match *v {
+                        Some(ref v) => Blob::Array(v.clone()),
+                        None => Blob::Tombstone,
+                    };
+                    Ok(kvp {Key:k.clone(), Value:v})
+                });
+                let mut fs = try!(self.inner.OpenForWriting());
+                let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self.inner, source, None, &self.inner.settings));
+                g
+            };
+            self.pairs.clear();
+            self.bytes = 0;
+            self.segs.push(g);
+        }
+        Ok(())
+    }
+
+    // the segments written so far, oldest first
+    pub fn segments(&self) -> &[SegmentNum] {
+        &self.segs
+    }
+
+    pub fn Commit(&mut self, lck: &WriteLock) -> Result<()> {
+        try!(self.Flush());
+        if !self.segs.is_empty() {
+            let mut segs = self.segs.clone();
+            // commitSegments wants the newest first
+            segs.reverse();
+            try!(lck.commitSegments(segs));
+            self.segs.clear();
+        }
+        Ok(())
+    }
+
+    // throws away the pairs, and gives back the pages of the
+    // segments written so far
+    pub fn Abandon(&mut self) -> Result<()> {
+        self.pairs.clear();
+        self.bytes = 0;
+        if !self.segs.is_empty() {
+            try!(self.inner.forgetWaitingSegments(&self.segs));
+            self.segs.clear();
+        }
+        Ok(())
+    }
+}
+
+// a buffer dropped without Commit or Abandon is abandoned here.
+// drop has nobody to report an error to, so it is kept like the
+// error of an automatic merge.  the only harm is that the pages
+// stay in use until the db is opened again.
+impl<'a> Drop for PairBuffer<'a> {
+    fn drop(&mut self) {
+        match self.Abandon() {
+            Ok(()) => {
+            },
+            Err(e) => {
+                self.inner.keepMergeError(e);
+            },
+        }
+    }
+}
+
+// this is a separate struct, rather than a Drop impl on db
+// itself, because db lends out references to its inner part.
+struct MergeThread {
+    inner: Arc<InnerPart>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for MergeThread {
+    fn drop(&mut self) {
+        // dropping the sender ends the loop in the merge thread.
+        // then we wait for it to finish whatever it was doing.  the
+        // sender has to go even if the lock was poisoned, or the
+        // join would wait forever.
+        match self.inner.merger.lock() {
+            Ok(mut merger) => {
+                *merger = None;
+            },
+            Err(e) => {
+                *e.into_inner() = None;
+            },
+        }
+        match self.handle.take() {
+            Some(h) => {
+                match h.join() {
+                    Ok(()) => {
+                    },
+                    Err(_) => {
+                        self.inner.keepMergeError(Error::Misc("the merge thread panicked"));
+                    },
+                }
+            },
+            None => {
+            },
+        }
+    }
 }
 
 // TODO this could be generic
@@ -3580,6 +7457,28 @@ fn slice_within(sub: &[SegmentNum], within: &[SegmentNum]) -> Result<usize> {
     }
 }
 
+// the value of k, or None if it is not there or is a tombstone
+fn getValue(csr: &mut LivingCursor, k: &[u8]) -> Result<Option<Box<[u8]>>> {
+    try!(csr.SeekRef(&KeyRef::for_slice(k), SeekOp::SEEK_EQ));
+    if csr.IsValid() {
+        match try!(csr.ValueRef()) {
+            ValueRef::Array(a) => {
+                let mut v = Vec::with_capacity(a.len());
+                v.push_all(a);
+                Ok(Some(v.into_boxed_slice()))
+            },
+            ValueRef::Overflowed(len, mut strm) => {
+                let mut v = Vec::with_capacity(len);
+                try!(strm.read_to_end(&mut v));
+                Ok(Some(v.into_boxed_slice()))
+            },
+            ValueRef::Tombstone => Ok(None),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
 impl InnerPart {
 
     fn cursor_dropped(&self, segnum: SegmentNum, csrnum: u64) {
@@ -3587,6 +7486,10 @@ impl InnerPart {
         let mut cursors = self.cursors.lock().unwrap(); // gotta succeed
         let seg = cursors.cursors.remove(&csrnum).expect("gotta be there");
         assert_eq!(seg, segnum);
+        // a zombie can only be freed once its last cursor is gone
+        if cursors.cursors.values().any(|g| *g == segnum) {
+            return;
+        }
         match cursors.zombies.remove(&segnum) {
             Some(info) => {
                 // TODO maybe allow this lock to fail with try_lock.  the
@@ -3635,17 +7538,38 @@ impl InnerPart {
         }
     }
 
-    fn OpenForWriting(&self) -> io::Result<File> {
-        OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&self.path)
+    // nobody holds one of these while waiting for another, so a
+    // writer only ever waits for writers which are not waiting.
+    fn OpenForWriting(&self) -> Result<PooledFile> {
+        if self.settings.ReadOnly {
+            return Err(Error::ReadOnly);
+        }
+        let mut pool = try!(self.files.lock());
+        while pool.idle.is_none() && pool.max > 0 && pool.open >= pool.max {
+            pool = try!(self.fileReturned.wait(pool));
+        }
+        let fs = match pool.idle.take() {
+            Some(fs) => fs,
+            None => {
+                let fs = try!(OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(&self.path));
+                pool.open = pool.open + 1;
+                fs
+            },
+        };
+        Ok(PooledFile { fs: Some(fs), pool: &self.files, returned: &self.fileReturned })
     }
 
-    fn OpenForReading(&self) -> io::Result<File> {
-        OpenOptions::new()
-                .read(true)
-                .open(&self.path)
+    fn OpenForReading(&self) -> Result<Arc<File>> {
+        let pool = try!(self.files.lock());
+        Ok(pool.reader.clone())
+    }
+
+    fn OpenFileCount(&self) -> Result<usize> {
+        let pool = try!(self.files.lock());
+        Ok(pool.open)
     }
 
     // this code should not be called in a release build.  it helps
@@ -3653,19 +7577,93 @@ impl InnerPart {
     // have been freed.
     fn stomp(&self, blocks:Vec<PageBlock>) -> Result<()> {
         let bad = vec![0;self.pgsz as usize].into_boxed_slice();
-        let mut fs = try!(OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&self.path));
+        let mut fs = try!(self.OpenForWriting());
         for b in blocks {
             for x in b.firstPage .. b.lastPage+1 {
-                try!(utils::SeekPage(&mut fs, self.pgsz, x));
+                try!(utils::SeekPage(&mut *fs, self.pgsz, x));
                 try!(fs.write(&bad));
             }
         }
         Ok(())
     }
 
+    fn cachedPage(&self, pgnum: PageNum, buf: &mut [u8]) -> Result<bool> {
+        if 0 == self.settings.PageCacheSize {
+            return Ok(false);
+        }
+        let mut cache = try!(self.pageCache.lock());
+        Ok(cache.get(pgnum, buf))
+    }
+
+    fn cachePage(&self, pgnum: PageNum, buf: &[u8]) -> Result<()> {
+        if 0 == self.settings.PageCacheSize {
+            return Ok(());
+        }
+        let mut cache = try!(self.pageCache.lock());
+        cache.put(pgnum, buf);
+        Ok(())
+    }
+
+    // a mapping with at least len bytes of the file in it.  when
+    // the current one is too short, the whole file gets mapped
+    // again, which fails if the file is still too short.  None
+    // where the file cannot be mapped at all.
+    fn fileMapping(&self, len: usize) -> Result<Option<Arc<FileMap>>> {
+        let mut fm = try!(self.fileMap.lock());
+        match *fm {
+            Some(ref m) if m.len >= len => return Ok(Some(m.clone())),
+            _ => (),
+        }
+        let m = match try!(FileMap::new(&*try!(self.OpenForReading()))) {
+            Some(m) => Arc::new(m),
+            None => return Ok(None),
+        };
+        if m.len < len {
+            return Err(Error::InvalidPageNumber);
+        }
+        *fm = Some(m.clone());
+        Ok(Some(m))
+    }
+
+    fn mappedPage(&self, pgnum: PageNum) -> Result<Option<PageBytes>> {
+        if 0 == pgnum {
+            return Err(Error::InvalidPageNumber);
+        }
+        let pos = (pgnum - 1) as usize * self.pgsz;
+        match try!(self.fileMapping(pos + self.pgsz)) {
+            Some(m) => Ok(Some(PageBytes::Mapped(m, pos, self.pgsz))),
+            None => Ok(None),
+        }
+    }
+
+    // the caller has to make sure the segment cannot be freed
+    // while we are in here, as for getCursorOn.  the file is read
+    // without holding the cache lock.
+    fn bloomFilter(&self, g: SegmentNum, b: &SegmentBloom) -> Result<Arc<BloomFilter>> {
+        {
+            let mut cache = try!(self.bloomCache.lock());
+            match cache.get(b.firstPage) {
+                Some(f) => return Ok(f),
+                None => cache.reads = cache.reads + 1,
+            }
+        }
+        let fs = try!(self.OpenForReading());
+        let f = Arc::new(try!(b.read(&fs, self.pgsz, self.settings.PageTransform, g)));
+        let mut cache = try!(self.bloomCache.lock());
+        cache.put(b.firstPage, f.clone());
+        Ok(f)
+    }
+
+    fn PageCacheStats(&self) -> Result<PageCacheStats> {
+        let cache = try!(self.pageCache.lock());
+        let st = PageCacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            pages: cache.pages.len(),
+        };
+        Ok(st)
+    }
+
     fn addFreeBlocks(&self, space: &mut Space, blocks:Vec<PageBlock>) {
 
         // all additions to the freeBlocks list should happen here
@@ -3686,6 +7684,19 @@ impl InnerPart {
         // TODO if the last block of the file is free, consider just
         // moving nextPage back.
 
+        // nothing cached for these pages may outlive them.  the
+        // page cache lock is never held while taking another one,
+        // so taking it here, under space, is fine.  a cache whose
+        // lock was poisoned may be half updated, so all of it goes.
+        match self.pageCache.lock() {
+            Ok(mut cache) => cache.invalidate(&blocks),
+            Err(e) => e.into_inner().clear(),
+        }
+        match self.bloomCache.lock() {
+            Ok(mut cache) => cache.invalidate(&blocks),
+            Err(e) => e.into_inner().clear(),
+        }
+
         for b in blocks {
             space.freeBlocks.push(b);
         }
@@ -3712,126 +7723,799 @@ impl InnerPart {
                 a = a + varint::space_needed_for(t.firstPage as u64);
                 a = a + varint::space_needed_for(t.count_pages() as u64);
             }
-            a = a + varint::space_needed_for(info.root as u64);
-            a = a + varint::space_needed_for(info.age as u64);
-            a = a + varint::space_needed_for(info.blocks.len() as u64);
-            a
+            a = a + varint::space_needed_for(info.root as u64);
+            a = a + varint::space_needed_for(info.age as u64);
+            a = a + varint::space_needed_for(info.blocks.len() as u64);
+            match info.bloom {
+                Some(ref b) => {
+                    a = a + varint::space_needed_for(b.firstPage as u64);
+                    a = a + varint::space_needed_for(b.len as u64);
+                },
+                None => {
+                    a = a + varint::space_needed_for(0);
+                },
+            }
+            match info.graveyard {
+                Some(ref b) => {
+                    a = a + varint::space_needed_for(b.firstPage as u64);
+                    a = a + varint::space_needed_for(b.len as u64);
+                },
+                None => {
+                    a = a + varint::space_needed_for(0);
+                },
+            }
+            a
+        }
+
+        fn spaceForHeader(h: &HeaderData) -> usize {
+            let mut a = varint::space_needed_for(h.currentState.len() as u64);
+            // TODO use currentState with a lookup into h.segments instead?
+            // should be the same, right?
+            for (g,info) in h.segments.iter() {
+                a = a + spaceNeededForSegmentInfo(&info) + varint::space_needed_for(*g);
+            }
+            a
+        }
+
+        fn buildSegmentList(h: &HeaderData) -> PageBuilder {
+            let space = spaceForHeader(h);
+            let mut pb = PageBuilder::new(space);
+            pb.PutVarint(h.currentState.len() as u64);
+            for g in h.currentState.iter() {
+                pb.PutVarint(*g);
+                match h.segments.get(&g) {
+                    Some(info) => {
+                        pb.PutVarint(info.root as u64);
+                        pb.PutVarint(info.age as u64);
+                        pb.PutVarint(info.blocks.len() as u64);
+                        // we store PageBlock as first/count instead of first/last, since the
+                        // count will always compress better as a varint.
+                        for t in info.blocks.iter() {
+                            pb.PutVarint(t.firstPage as u64);
+                            pb.PutVarint(t.count_pages() as u64);
+                        }
+                        // page numbers start at 1, so 0 means no filter
+                        match info.bloom {
+                            Some(ref b) => {
+                                pb.PutVarint(b.firstPage as u64);
+                                pb.PutVarint(b.len as u64);
+                            },
+                            None => {
+                                pb.PutVarint(0);
+                            },
+                        }
+                        match info.graveyard {
+                            Some(ref b) => {
+                                pb.PutVarint(b.firstPage as u64);
+                                pb.PutVarint(b.len as u64);
+                            },
+                            None => {
+                                pb.PutVarint(0);
+                            },
+                        }
+                    },
+                    None => panic!("segment num in currentState but not in segments")
+                }
+            }
+            assert!(0 == pb.Available());
+            pb
+        }
+
+        let mut pb = PageBuilder::new_page(HEADER_SLOT_SIZE, None);
+        // the header is always written in the current format
+        pb.PutArray(FILE_MAGIC);
+        pb.PutInt32(FORMAT_VERSION);
+        pb.PutInt32(self.pgsz as u32);
+
+        pb.PutVarint(hdr.changeCounter);
+        pb.PutVarint(hdr.mergeCounter);
+        pb.PutByte(pageTransformId(self.settings.PageTransform));
+        let name = comparatorName(self.settings.Comparator).as_bytes();
+        pb.PutVarint(name.len() as u64);
+        pb.PutArray(name);
+
+        let pbSegList = buildSegmentList(&hdr);
+        let buf = pbSegList.Buffer();
+        pb.PutVarint(buf.len() as u64);
+
+        let headerOverflow =
+            if pb.Available() >= (buf.len() + 1) {
+                pb.PutByte(0u8);
+                pb.PutArray(buf);
+                None
+            } else {
+                pb.PutByte(1u8);
+                let fits = pb.Available() - 4 - 4 - 4;
+                let extra = buf.len() - fits;
+                let extraPages = extra / self.pgsz + if (extra % self.pgsz) != 0 { 1 } else { 0 };
+                //printfn "extra pages: %d" extraPages
+                let blk = self.getBlock(space, extraPages as PageNum);
+                try!(utils::SeekPage(fs, self.pgsz, blk.firstPage));
+                try!(fs.write_all(&buf[fits .. buf.len()]));
+                pb.PutInt32(fits as u32);
+                pb.PutInt32(blk.firstPage);
+                pb.PutInt32(crc32::checksum(buf));
+                pb.PutArray(&buf[0 .. fits]);
+                Some(blk)
+            };
+
+        // each header write increases changeCounter or mergeCounter
+        // by one, so this alternates between the slots, and the one
+        // being overwritten is never the current header.
+        let slot = ((hdr.changeCounter + hdr.mergeCounter) % 2) as usize;
+
+        // everything the new header refers to needs to be on disk
+        // before the header is.  this covers the pages of the new
+        // segments too, so writing them does not sync.  and the
+        // header needs to be on disk before anything it replaces
+        // gets reused.
+        if self.settings.SyncMode == SyncMode::Full {
+            try!(fs.sync_data());
+            st.syncs = st.syncs + 1;
+        }
+        try!(fs.seek(SeekFrom::Start((slot * HEADER_SLOT_SIZE) as u64)));
+        try!(pb.Write(fs));
+        try!(fs.flush());
+        if self.settings.SyncMode != SyncMode::Off {
+            try!(fs.sync_data());
+            st.syncs = st.syncs + 1;
+        }
+        let oldHeaderOverflow = hdr.headerOverflow;
+        hdr.headerOverflow = headerOverflow;
+        st.header = hdr;
+        Ok((oldHeaderOverflow))
+    }
+
+    // writes a new header, which is always in the current format.
+    // segments from format 1, which had no page checksums, get
+    // rewritten first, keeping the ages of the ones they replace.
+    // this is only called by db::new, before anybody else can see
+    // the db, and the caller is responsible for updating self.format
+    // afterward.
+    //
+    // if we crash before the header is written, any new segments
+    // are just garbage in space the old format considers free.
+    // but the header write itself is not safe when upgrading from
+    // a format with a single header slot.  a torn write there
+    // leaves neither the old header nor the new one.
+    fn upgradeFormat(&self) -> Result<()> {
+        if self.format >= 2 {
+            let mut fs = try!(self.OpenForWriting());
+            let mut st = try!(self.header.lock());
+            let mut space = try!(self.space.lock());
+            let mut newHeader = st.header.clone();
+            newHeader.changeCounter = newHeader.changeCounter + 1;
+            let oldHeaderOverflow = try!(self.writeHeader(&mut st, &mut space, &mut fs, newHeader));
+            match oldHeaderOverflow {
+                Some(blk) => self.addFreeBlocks(&mut space, vec![blk]),
+                None => (),
+            }
+            return Ok(());
+        }
+
+        let mut fs = try!(self.OpenForWriting());
+        let newSegs = {
+            let st = try!(self.header.lock());
+            let mut newSegs = Vec::with_capacity(st.header.currentState.len());
+            for g in st.header.currentState.iter() {
+                let csr = try!(self.getCursor(&st, *g));
+                let mut mc = MultiCursor::Create(vec![SubCursor::Segment(csr)], self.settings.Comparator);
+                try!(mc.First());
+                let (newg,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, CursorIterator::new(mc), None, &self.settings));
+                newSegs.push(newg);
+            }
+            newSegs
+        };
+
+        let mut st = try!(self.header.lock());
+        let mut waiting = try!(self.segmentsInWaiting.lock());
+        let mut space = try!(self.space.lock());
+
+        let mut newHeader = st.header.clone();
+        newHeader.currentState = newSegs.clone();
+        newHeader.segments = HashMap::with_capacity(newSegs.len());
+        let mut blocksToBeFreed = Vec::new();
+        for (oldg, newg) in st.header.currentState.iter().zip(newSegs.iter()) {
+            let old = st.header.segments.get(oldg).expect("old seg not found in header.segments");
+            let mut info = waiting.segmentsInWaiting.remove(newg).expect("new seg not found in segmentsInWaiting");
+            info.age = old.age;
+            info.committed = old.committed;
+            newHeader.segments.insert(*newg, info);
+            blocksToBeFreed.push_all(&old.blocks);
+        }
+        newHeader.changeCounter = newHeader.changeCounter + 1;
+
+        let oldHeaderOverflow = try!(self.writeHeader(&mut st, &mut space, &mut fs, newHeader));
+        match oldHeaderOverflow {
+            Some(blk) => blocksToBeFreed.push(blk),
+            None => (),
+        }
+        self.addFreeBlocks(&mut space, blocksToBeFreed);
+        Ok(())
+    }
+
+    // this only looks for the segment in the header.segments.
+    // see openSegmentCursor for segments in waiting.
+    fn getCursor(&self, 
+                 st: &SafeHeader,
+                 g: SegmentNum
+                ) -> Result<SegmentCursor> {
+        match st.header.segments.get(&g) {
+            None => Err(Error::Misc("getCursor: segment not found")),
+            Some(seg) => self.getCursorOn(g, seg),
+        }
+    }
+
+    // the caller has to make sure the segment cannot be freed
+    // while we are in here.
+    fn getCursorOn(&self, 
+                   g: SegmentNum,
+                   seg: &SegmentInfo
+                  ) -> Result<SegmentCursor> {
+        let rootPage = seg.root;
+        let fs = try!(self.OpenForReading());
+        // the cursor is registered before it is built, and without
+        // holding the cursors lock, because a cursor which fails
+        // partway through new gets dropped, and its Drop unregisters.
+        let csrnum = try!(self.pin(g));
+        // format 1 had no page checksums
+        let checksums = self.format >= 2;
+        let bloom = seg.bloom.clone();
+        let graveyard = seg.graveyard.as_ref().map(|b| b.ranges.clone());
+        match SegmentCursor::new(fs, self.pgsz, checksums, rootPage, seg.blocks.clone(), bloom, graveyard, &self, g, csrnum) {
+            Ok(csr) => Ok(csr),
+            Err(e) => {
+                // unless it failed before there was a cursor to drop
+                let registered = try!(self.cursors.lock()).cursors.contains_key(&csrnum);
+                if registered {
+                    self.cursor_dropped(g, csrnum);
+                }
+                Err(e)
+            },
+        }
+    }
+
+    // a pin keeps a segment from being freed, just like a cursor
+    // does, and gets released the same way, with cursor_dropped.
+    fn pin(&self, g: SegmentNum) -> Result<u64> {
+        let mut cursors = try!(self.cursors.lock());
+        let csrnum = cursors.nextCursorNum;
+        cursors.nextCursorNum = cursors.nextCursorNum + 1;
+        let was = cursors.cursors.insert(csrnum, g);
+        assert!(was.is_none());
+        Ok(csrnum)
+    }
+
+    // the segment can be committed or still in waiting.  either
+    // way, its lock is held while the cursor gets registered, so
+    // the segment cannot be freed out from under us.
+    fn openSegmentCursor(&self, g: SegmentNum) -> Result<SegmentCursor> {
+        let st = try!(self.header.lock());
+        if st.header.segments.contains_key(&g) {
+            return self.getCursor(&st, g);
+        }
+        let waiting = try!(self.segmentsInWaiting.lock());
+        match waiting.segmentsInWaiting.get(&g) {
+            Some(seg) => self.getCursorOn(g, seg),
+            None => Err(Error::Misc("openSegmentCursor: segment not found")),
+        }
+    }
+
+    // problems with the pages go in the report.  an Err means
+    // that check itself could not run.
+    fn check(&self) -> Result<CheckReport> {
+        let snap = try!(self.snapshot());
+        let mut problems = Vec::new();
+
+        // who owns which pages.  the snapshot keeps its segments
+        // from being freed, so they cannot turn up in the free
+        // list while we look.
+        let mut owners = Vec::new();
+        for g in snap.currentState.iter() {
+            for b in snap.segments.get(g).unwrap().blocks.iter() {
+                owners.push((*b, BlockOwner::Segment(*g)));
+            }
+        }
+        let nextPage = {
+            let st = try!(self.header.lock());
+            let waiting = try!(self.segmentsInWaiting.lock());
+            let space = try!(self.space.lock());
+            match st.header.headerOverflow {
+                Some(b) => owners.push((b, BlockOwner::HeaderOverflow)),
+                None => (),
+            }
+            for (g, info) in waiting.segmentsInWaiting.iter() {
+                for b in info.blocks.iter() {
+                    owners.push((*b, BlockOwner::Waiting(*g)));
+                }
+            }
+            for b in space.freeBlocks.iter() {
+                owners.push((*b, BlockOwner::Free));
+            }
+            space.nextPage
+        };
+        owners.sort_by(|a, b| a.0.firstPage.cmp(&b.0.firstPage));
+        // the furthest page claimed so far, and by whom
+        let mut reach: Option<(PageNum, BlockOwner)> = None;
+        for &(b, owner) in owners.iter() {
+            if b.lastPage >= nextPage {
+                problems.push(Problem::BeyondEnd(owner, b.lastPage));
+            }
+            match reach {
+                Some((last, other)) if b.firstPage <= last => {
+                    problems.push(Problem::Overlap(other, owner, b.firstPage));
+                },
+                _ => {
+                },
+            }
+            reach = match reach {
+                Some((last, other)) if last >= b.lastPage => Some((last, other)),
+                _ => Some((b.lastPage, owner)),
+            };
+        }
+
+        // format 1 had no page checksums
+        let checksums = self.format >= 2;
+        let mut segments = Vec::with_capacity(snap.currentState.len());
+        for g in snap.currentState.iter() {
+            let info = snap.segments.get(g).unwrap();
+            let mut csr = match self.getCursorOn(*g, info) {
+                Ok(csr) => csr,
+                Err(_) => {
+                    problems.push(Problem::UnreadablePage(*g, info.root));
+                    continue;
+                },
+            };
+            csr.cached = false;
+            let len = try!(csr.fs.metadata()).len();
+            let mut ck = SegmentChecker {
+                pgsz: self.pgsz,
+                checksums: checksums,
+                transform: self.settings.PageTransform,
+                codec: self.settings.Compression,
+                csr: csr,
+                len: len,
+                pr: if checksums { PageBuffer::new_page(self.pgsz, self.settings.PageTransform) } else { PageBuffer::new(self.pgsz) },
+                touched: HashSet::new(),
+                leafDepth: None,
+                firstLeaf: 0,
+                prevLeaf: 0,
+                lastKey: None,
+                result: SegmentCheck {
+                    segment: *g,
+                    leaves: 0,
+                    parents: 0,
+                    overflows: 0,
+                    keys: 0,
+                    pages: 0,
+                },
+                problems: Vec::new(),
+            };
+            ck.node(info.root, 0, None, None);
+            match info.bloom {
+                Some(ref b) => {
+                    ck.overflow(b.firstPage, b.len);
+                },
+                None => {
+                },
+            }
+            match info.graveyard {
+                Some(ref b) => {
+                    ck.overflow(b.firstPage, b.len);
+                },
+                None => {
+                },
+            }
+            ck.result.pages = ck.touched.len();
+            segments.push(ck.result.clone());
+            problems.push_all(&ck.problems);
+        }
+
+        Ok(CheckReport {
+            segments: segments,
+            problems: problems,
+        })
+    }
+
+    fn OpenCursor(&self) -> Result<LivingCursor> {
+        // the memtable lock is taken before the header lock, so that
+        // a flush (which holds the memtable lock while it commits)
+        // cannot leave us with a cursor that sees the same pairs
+        // twice or not at all.
+        let mt = try!(self.memtable.lock());
+        let st = try!(self.header.lock());
+        self.openCursorLocked(&mt, &st)
+    }
+
+    fn openCursorLocked(&self, mt: &SafeMemtable, st: &SafeHeader) -> Result<LivingCursor> {
+        let mut clist = Vec::with_capacity(st.header.currentState.len() + mt.memtable.layers.len());
+        for layer in mt.memtable.layers.iter().filter(|layer| !layer.is_empty()) {
+            clist.push(SubCursor::Memtable(MemtableCursor::new(layer, self.settings.Comparator)));
+        }
+        for g in st.header.currentState.iter() {
+            clist.push(SubCursor::Segment(try!(self.getCursor(st, *g))));
+        }
+        let mc = MultiCursor::Create(clist, self.settings.Comparator);
+        let lc = LivingCursor::Create(mc);
+        Ok(lc)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        let mt = try!(self.memtable.lock());
+        let st = try!(self.header.lock());
+        let memtable = mt.memtable.layers.iter().filter(|layer| !layer.is_empty()).map(|layer| layer.clone()).collect();
+        let mut segments = HashMap::with_capacity(st.header.currentState.len());
+        let mut pins = Vec::with_capacity(st.header.currentState.len());
+        for g in st.header.currentState.iter() {
+            let info = st.header.segments.get(g).unwrap();
+            segments.insert(*g, info.clone());
+            pins.push((*g, try!(self.pin(*g))));
+        }
+        Ok(Snapshot {
+            inner: self,
+            changeCounter: st.header.changeCounter,
+            currentState: st.header.currentState.clone(),
+            segments: segments,
+            memtable: memtable,
+            pins: pins,
+        })
+    }
+
+    // holding the memtable lock keeps any flush from committing
+    // between the check and our own commit.  merges can still
+    // commit in between, but they never change a value.
+    //
+    // a key counts as changed if anything written since the
+    // snapshot mentions it, even if it ends up with the value it
+    // had.  this looks only at what is newer than the snapshot:
+    // the memtable layers it did not have, and the segments
+    // committed after its change counter.  the check can be
+    // wrong in one direction only.  when the memtable collapses
+    // its layers, or a merge takes in a newer segment, the older
+    // writes in there look new too, and we report a conflict
+    // which did not happen.
+    fn commitSegmentsUnlessChanged(&self, snap: &Snapshot, keys: &[&[u8]], newSegs: Vec<SegmentNum>) -> Result<()> {
+        let mut mt = try!(self.memtable.lock());
+        {
+            let st = try!(self.header.lock());
+            for k in keys {
+                if try!(self.changedSince(&mt, &st, snap, k)) {
+                    return Err(Error::Conflict(k.to_vec().into_boxed_slice()));
+                }
+            }
+        }
+        try!(self.flushMemtableLocked(&mut mt));
+        self.commitSegments(newSegs)
+    }
+
+    // the memtable is always in front of the segments, so whatever
+    // is in it has to become a segment before newer ones from
+    // outside can be committed.  the memtable lock is held across
+    // both, so no write can get in between.
+    fn commitSegmentsAfterMemtable(&self, newSegs: Vec<SegmentNum>) -> Result<()> {
+        let mut mt = try!(self.memtable.lock());
+        try!(self.flushMemtableLocked(&mut mt));
+        self.commitSegments(newSegs)
+    }
+
+    fn changedSince(&self, mt: &SafeMemtable, st: &SafeHeader, snap: &Snapshot, k: &[u8]) -> Result<bool> {
+        let c = self.settings.Comparator;
+        let kr = KeyRef::for_slice(k);
+        for layer in mt.memtable.layers.iter() {
+            let old = snap.memtable.iter().any(|s| Arc::ptr_eq(&s.pairs, &layer.pairs) && Arc::ptr_eq(&s.graveyard, &layer.graveyard));
+            if old {
+                continue;
+            }
+            if layer.graveyard.covers(c, &kr) || layer.pairs.contains_key(&MemtableKey::new(c, k.to_vec().into_boxed_slice())) {
+                return Ok(true);
+            }
+        }
+        for g in st.header.currentState.iter() {
+            let info = st.header.segments.get(g).unwrap();
+            if info.committed <= snap.changeCounter {
+                continue;
+            }
+            match info.graveyard {
+                Some(ref sg) => {
+                    if sg.ranges.covers(c, &kr) {
+                        return Ok(true);
+                    }
+                },
+                None => {
+                },
+            }
+            // as in MultiCursor, the bloom filter only knows bytes
+            match info.bloom {
+                Some(ref b) => {
+                    if c.is_none() && !try!(self.bloomFilter(*g, b)).might_contain(&kr) {
+                        continue;
+                    }
+                },
+                None => {
+                },
+            }
+            let mut csr = try!(self.getCursor(st, *g));
+            let sr = try!(csr.SeekRef(&kr, SeekOp::SEEK_EQ));
+            if sr.is_valid_and_equal() {
+                return Ok(true);
+            }
         }
+        Ok(false)
+    }
 
-        fn spaceForHeader(h: &HeaderData) -> usize {
-            let mut a = varint::space_needed_for(h.currentState.len() as u64);
-            // TODO use currentState with a lookup into h.segments instead?
-            // should be the same, right?
-            for (g,info) in h.segments.iter() {
-                a = a + spaceNeededForSegmentInfo(&info) + varint::space_needed_for(*g);
+    fn put(&self, k: &[u8], v: Option<&[u8]>) -> Result<()> {
+        let k = k.to_vec().into_boxed_slice();
+        let v = v.map(|v| v.to_vec().into_boxed_slice());
+        self.writeBatch(vec![(k, v)])
+    }
+
+    fn writeBatch(&self, pairs: MemtableBatch) -> Result<()> {
+        let full = {
+            let mut mt = try!(self.memtable.lock());
+            // the batch goes into the log before the memtable, so if
+            // the append fails, the memtable has not changed.
+            try!(try!(mt.wal()).append(&pairs, self.settings.SyncWal));
+            for (k,v) in pairs {
+                mt.memtable.insert(k, v);
             }
-            a
+            mt.memtable.size > self.settings.MaxMemtableBytes
+        };
+        if full {
+            try!(self.flushIfUnlocked());
         }
+        Ok(())
+    }
 
-        fn buildSegmentList(h: &HeaderData) -> PageBuilder {
-            let space = spaceForHeader(h);
-            let mut pb = PageBuilder::new(space);
-            // TODO format version number
-            pb.PutVarint(h.currentState.len() as u64);
-            for g in h.currentState.iter() {
-                pb.PutVarint(*g);
-                match h.segments.get(&g) {
-                    Some(info) => {
-                        pb.PutVarint(info.root as u64);
-                        pb.PutVarint(info.age as u64);
-                        pb.PutVarint(info.blocks.len() as u64);
-                        // we store PageBlock as first/count instead of first/last, since the
-                        // count will always compress better as a varint.
-                        for t in info.blocks.iter() {
-                            pb.PutVarint(t.firstPage as u64);
-                            pb.PutVarint(t.count_pages() as u64);
-                        }
-                    },
-                    None => panic!("segment num in currentState but not in segments")
-                }
-            }
-            assert!(0 == pb.Available());
-            pb
+    fn deleteRange(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        if compareKeys(self.settings.Comparator, start, end) != Ordering::Less {
+            return Ok(());
+        }
+        let full = {
+            let mut mt = try!(self.memtable.lock());
+            try!(try!(mt.wal()).append_range(start, end, self.settings.SyncWal));
+            mt.memtable.delete_range(start, end);
+            mt.memtable.size > self.settings.MaxMemtableBytes
+        };
+        if full {
+            try!(self.flushIfUnlocked());
         }
+        Ok(())
+    }
 
-        let mut pb = PageBuilder::new(HEADER_SIZE_IN_BYTES);
-        pb.PutInt32(self.pgsz as u32);
+    // whoever holds the write lock may be the caller, further up
+    // the stack, so a full memtable does not wait for it.  the
+    // memtable just keeps growing until a write finds the lock free.
+    fn flushIfUnlocked(&self) -> Result<()> {
+        match self.writeLock.try_lock() {
+            Ok(_lck) => self.flushMemtable(),
+            Err(TryLockError::WouldBlock) => Ok(()),
+            Err(TryLockError::Poisoned(_)) => Err(Error::Poisoned),
+        }
+    }
 
-        pb.PutVarint(hdr.changeCounter);
-        pb.PutVarint(hdr.mergeCounter);
+    fn sync(&self) -> Result<()> {
+        let mut mt = try!(self.memtable.lock());
+        try!(try!(mt.wal()).sync());
+        let fs = try!(self.OpenForWriting());
+        // holding the header lock keeps anybody from committing
+        // while we sync
+        let mut st = try!(self.header.lock());
+        try!(fs.sync_all());
+        st.syncs = st.syncs + 1;
+        Ok(())
+    }
 
-        let pbSegList = buildSegmentList(&hdr);
-        let buf = pbSegList.Buffer();
-        pb.PutVarint(buf.len() as u64);
+    fn get(&self, k: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let mut csr = try!(self.OpenCursor());
+        getValue(&mut csr, k)
+    }
 
-        let headerOverflow =
-            if pb.Available() >= (buf.len() + 1) {
-                pb.PutByte(0u8);
-                pb.PutArray(buf);
-                None
-            } else {
-                pb.PutByte(1u8);
-                let fits = pb.Available() - 4 - 4;
-                let extra = buf.len() - fits;
-                let extraPages = extra / self.pgsz + if (extra % self.pgsz) != 0 { 1 } else { 0 };
-                //printfn "extra pages: %d" extraPages
-                let blk = self.getBlock(space, extraPages as PageNum);
-                try!(utils::SeekPage(fs, self.pgsz, blk.firstPage));
-                try!(fs.write(&buf[fits .. buf.len()]));
-                pb.PutInt32(fits as u32);
-                pb.PutInt32(blk.firstPage);
-                pb.PutArray(&buf[0 .. fits]);
-                Some(blk)
-            };
+    fn flush(&self) -> Result<()> {
+        try!(self.takeMergeError());
+        let _lck = try!(self.writeLock.lock());
+        self.flushMemtable()
+    }
 
-        try!(fs.seek(SeekFrom::Start(0)));
-        try!(pb.Write(fs));
-        try!(fs.flush());
-        let oldHeaderOverflow = hdr.headerOverflow;
-        hdr.headerOverflow = headerOverflow;
-        st.header = hdr;
-        Ok((oldHeaderOverflow))
+    // the caller must hold the write lock.  the memtable lock is held
+    // until the memtable is cleared, so that happens in the same
+    // critical section in which its segment becomes visible.  the
+    // merge afterwards does not need it.
+    fn flushMemtable(&self) -> Result<()> {
+        {
+            let mut mt = try!(self.memtable.lock());
+            try!(self.flushMemtableLocked(&mut mt));
+        }
+        self.autoMerge();
+        Ok(())
     }
 
-    // TODO this function looks for the segment in the header.segments,
-    // which means it cannot be used to open a cursor on a pendingSegment,
-    // which we think we might need in the future.
-    fn getCursor(&self, 
-                 st: &SafeHeader,
-                 g: SegmentNum
-                ) -> Result<SegmentCursor> {
-        match st.header.segments.get(&g) {
-            None => Err(Error::Misc("getCursor: segment not found")),
-            Some(seg) => {
-                let rootPage = seg.root;
-                let mut cursors = try!(self.cursors.lock());
-                let csrnum = cursors.nextCursorNum;
-                let csr = try!(SegmentCursor::new(&self.path, self.pgsz, rootPage, seg.blocks.clone(), &self, g, csrnum));
+    fn flushMemtableLocked(&self, mt: &mut SafeMemtable) -> Result<()> {
+        if mt.memtable.is_empty() {
+            return Ok(());
+        }
+        mt.memtable.collapse();
+        let g = {
+            let layer = &mt.memtable.layers[0];
+            let source = layer.pairs.iter().map(|(k,v)| {
+                let v = match *v {
+                    Some(ref v) => Blob::Array(v.clone()),
+                    None => Blob::Tombstone,
+                };
+                Ok(kvp {Key:k.k.clone(), Value:v})
+            });
+            let graveyard = (*layer.graveyard).clone();
+            let mut fs = try!(self.OpenForWriting());
+            let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source, Some(graveyard), &self.settings));
+            g
+        };
+        // nobody else can commit while we hold the write lock, so
+        // the commit below is the one which produces this counter.
+        let changeCounter = {
+            let st = try!(self.header.lock());
+            st.header.changeCounter + 1
+        };
+        // the marker has to be on disk before the header is
+        let sync = self.settings.SyncMode != SyncMode::Off;
+        let walLen = match try!(mt.wal()).append_marker(changeCounter, sync) {
+            Ok(len) => len,
+            Err(e) => {
+                self.forgetOrKeepError(g);
+                return Err(e);
+            },
+        };
+        match self.commitSegments(vec![g]) {
+            Ok(()) => {
+            },
+            Err(e) => {
+                // the pairs are still in the memtable, and will be in
+                // the next segment instead.  the segment goes first,
+                // so its pages come back even if the marker cannot be
+                // taken back.  the error of the commit is the one the
+                // caller gets.
+                self.forgetOrKeepError(g);
+                match try!(mt.wal()).take_back_marker(walLen) {
+                    Ok(()) => {
+                    },
+                    Err(e2) => {
+                        self.keepMergeError(e2);
+                    },
+                }
+                return Err(e);
+            },
+        }
+        mt.memtable.clear();
+        // if we crash before this, the marker tells the replay to
+        // skip what the commit above already has.  so a failed
+        // truncate does not fail a flush which has already been
+        // committed.  the log just stays long until the next flush
+        // truncates it, and the error is kept for later.
+        match try!(mt.wal()).truncate() {
+            Ok(()) => {
+            },
+            Err(e) => {
+                self.keepMergeError(e);
+            },
+        }
+        Ok(())
+    }
 
-                cursors.nextCursorNum = cursors.nextCursorNum + 1;
-                let was = cursors.cursors.insert(csrnum, g);
-                assert!(was.is_none());
-                Ok(csr)
-            }
+    // for cleaning up after a failure, when the caller is already
+    // returning another error
+    fn forgetOrKeepError(&self, g: SegmentNum) {
+        match self.forgetWaitingSegments(&[g]) {
+            Ok(()) => {
+            },
+            Err(e) => {
+                self.keepMergeError(e);
+            },
         }
     }
 
-    // TODO we also need a way to open a cursor on segments in waiting
-    fn OpenCursor(&self) -> Result<LivingCursor> {
-        // TODO this cursor needs to expose the changeCounter and segment list
-        // on which it is based. for optimistic writes. caller can grab a cursor,
-        // do their writes, then grab the writelock, and grab another cursor, then
-        // compare the two cursors to see if anything important changed.  if not,
-        // commit their writes.  if so, nevermind the written segments and start over.
+    // called after segments are committed.  whatever the merge policy
+    // proposes for the foreground is small, so it happens right here.
+    // the rest gets handed to the background thread.  the commit has
+    // already happened, so a failed merge does not fail it.  the
+    // error is kept for takeMergeError instead.
+    fn autoMerge(&self) {
+        if !self.settings.AutoMergeEnabled {
+            return;
+        }
+        match self.autoMergeWith(false) {
+            Ok(()) => {
+            },
+            Err(e) => {
+                self.keepMergeError(e);
+            },
+        }
+        match self.merger.lock() {
+            Ok(mut merger) => {
+                let gone = match *merger {
+                    Some(ref tx) => tx.send(()).is_err(),
+                    None => false,
+                };
+                if gone {
+                    // the merge thread only stops by itself if it
+                    // panicked.  without it, merges only happen in
+                    // the foreground, and we say so just once.
+                    *merger = None;
+                    self.keepMergeError(Error::Misc("the merge thread is gone"));
+                }
+            },
+            Err(_) => {
+                self.keepMergeError(Error::Poisoned);
+            },
+        }
+    }
 
-        let st = try!(self.header.lock());
-        let mut clist = Vec::with_capacity(st.header.currentState.len());
-        for g in st.header.currentState.iter() {
-            clist.push(try!(self.getCursor(&*st, *g)));
+    fn backgroundMerge(&self) {
+        match self.autoMergeWith(true) {
+            Ok(()) => {
+            },
+            Err(e) => {
+                self.keepMergeError(e);
+            },
+        }
+    }
+
+    fn keepMergeError(&self, e: Error) {
+        match self.mergeError.lock() {
+            Ok(mut me) => {
+                if me.is_none() {
+                    *me = Some(e);
+                }
+            },
+            Err(poisoned) => {
+                let mut me = poisoned.into_inner();
+                if me.is_none() {
+                    *me = Some(e);
+                }
+            },
+        }
+    }
+
+    fn takeMergeError(&self) -> Result<()> {
+        let mut me = try!(self.mergeError.lock());
+        match me.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // the merges themselves are written without the write lock.  like
+    // the F# version, the background takes it just for each commit.
+    // in the foreground, the caller already holds it.
+    fn autoMergeWith(&self, background: bool) -> Result<()> {
+        let tiered = TieredMergePolicy {
+            Segments: self.settings.AutoMergeMinimumSegments,
+            ForegroundAges: 4,
+        };
+        let policy: &IMergePolicy = match self.settings.MergePolicy {
+            Some(p) => p,
+            None => &tiered,
+        };
+        loop {
+            match try!(self.mergeByPolicy(policy, background)) {
+                Some(g) => {
+                    let res =
+                        if background {
+                            let _lck = try!(self.writeLock.lock());
+                            self.commitMerge(g)
+                        } else {
+                            self.commitMerge(g)
+                        };
+                    match res {
+                        Ok(()) => {
+                        },
+                        Err(e) => {
+                            // so its segments can be merged again
+                            try!(self.forgetWaitingSegments(&[g]));
+                            return Err(e);
+                        },
+                    }
+                },
+                None => return Ok(()),
+            }
         }
-        let mc = MultiCursor::Create(clist);
-        let lc = LivingCursor::Create(mc);
-        Ok(lc)
     }
 
     fn commitSegments(&self, 
@@ -3839,6 +8523,9 @@ impl InnerPart {
                      ) -> Result<()> {
         assert_eq!(newSegs.len(), newSegs.iter().map(|g| *g).collect::<HashSet<SegmentNum>>().len());
 
+        // the handle comes before the locks, since whoever has the
+        // handle we would wait for may need the space lock.
+        let mut fs = try!(self.OpenForWriting());
         let mut st = try!(self.header.lock());
         let mut waiting = try!(self.segmentsInWaiting.lock());
         let mut space = try!(self.space.lock());
@@ -3863,7 +8550,8 @@ impl InnerPart {
         let mut newSegmentsInWaiting = waiting.segmentsInWaiting.clone();
         for g in newSegs.iter() {
             match newSegmentsInWaiting.remove(&g) {
-                Some(info) => {
+                Some(mut info) => {
+                    info.committed = newHeader.changeCounter + 1;
                     newHeader.segments.insert(*g,info);
                 },
                 None => {
@@ -3881,7 +8569,6 @@ impl InnerPart {
 
         newHeader.changeCounter = newHeader.changeCounter + 1;
 
-        let mut fs = try!(self.OpenForWriting());
         let oldHeaderOverflow = try!(self.writeHeader(&mut st, &mut space, &mut fs, newHeader));
         waiting.segmentsInWaiting = newSegmentsInWaiting;
 
@@ -3902,7 +8589,7 @@ impl InnerPart {
     // TODO bad fn name
     fn WriteSegmentFromSortedSequence<I>(&self, source: I) -> Result<SegmentNum> where I:Iterator<Item=Result<kvp>> {
         let mut fs = try!(self.OpenForWriting());
-        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source));
+        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source, None, &self.settings));
         Ok(g)
     }
 
@@ -3913,14 +8600,14 @@ impl InnerPart {
         a.sort_by(|a,b| {
             let (ref ka,_) = *a;
             let (ref kb,_) = *b;
-            bcmp::Compare(&ka,&kb)
+            compareKeys(self.settings.Comparator, &ka, &kb)
         });
         let source = a.into_iter().map(|t| {
             let (k,v) = t;
             Ok(kvp {Key:k, Value:Blob::Array(v)})
         });
         let mut fs = try!(self.OpenForWriting());
-        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source));
+        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source, None, &self.settings));
         Ok(g)
     }
 
@@ -3931,14 +8618,14 @@ impl InnerPart {
         a.sort_by(|a,b| {
             let (ref ka,_) = *a;
             let (ref kb,_) = *b;
-            bcmp::Compare(&ka,&kb)
+            compareKeys(self.settings.Comparator, &ka, &kb)
         });
         let source = a.into_iter().map(|t| {
             let (k,v) = t;
             Ok(kvp {Key:k, Value:v})
         });
         let mut fs = try!(self.OpenForWriting());
-        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source));
+        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source, None, &self.settings));
         Ok(g)
     }
 
@@ -3992,28 +8679,101 @@ impl InnerPart {
                     None => (),
                 }
                 segs.reverse();
-                let mut clist = Vec::with_capacity(segs.len());
-                for g in segs.iter() {
-                    clist.push(try!(self.getCursor(&st, *g)));
-                }
-                for g in segs.iter() {
-                    mergeStuff.merging.insert(*g);
-                }
-                Some((segs,clist))
+                Some(try!(self.startMerge(&st, &mut mergeStuff, segs)))
             } else {
                 None
             }
         };
+        self.finishMerge(mrg)
+    }
+
+    // the merge proposed by the policy, if any
+    fn mergeByPolicy(&self, policy: &IMergePolicy, background: bool) -> Result<Option<SegmentNum>> {
+        let mrg = {
+            let st = try!(self.header.lock());
+            let mut mergeStuff = try!(self.mergeStuff.lock());
+            let candidates = st.header.currentState.iter().map(|g| {
+                let info = st.header.segments.get(g).unwrap();
+                MergeCandidate {
+                    segment: *g,
+                    age: info.age,
+                    pages: info.count_pages(),
+                    merging: mergeStuff.merging.contains(g),
+                }
+            }).collect::<Vec<MergeCandidate>>();
+            match policy.Propose(&candidates, background) {
+                Some(r) => {
+                    if r.start >= r.end || r.end > candidates.len() || candidates[r.start .. r.end].iter().any(|c| c.merging) {
+                        return Err(Error::Misc("merge policy proposed an impossible merge"));
+                    }
+                    let segs = candidates[r.start .. r.end].iter().map(|c| c.segment).collect::<Vec<SegmentNum>>();
+                    Some(try!(self.startMerge(&st, &mut mergeStuff, segs)))
+                },
+                None => None,
+            }
+        };
+        self.finishMerge(mrg)
+    }
+
+    // segs are contiguous in currentState, and not being merged yet.
+    // the header lock is held throughout, so they cannot go away
+    // before they are marked.
+    fn startMerge(&self, st: &SafeHeader, mergeStuff: &mut SafeMergeStuff, segs: Vec<SegmentNum>) -> Result<(Vec<SegmentNum>,Vec<SubCursor>,Option<Graveyard>)> {
+        let mut clist = Vec::with_capacity(segs.len());
+        for g in segs.iter() {
+            clist.push(SubCursor::Segment(try!(self.getCursor(st, *g))));
+        }
+        for g in segs.iter() {
+            mergeStuff.merging.insert(*g);
+        }
+        // keys covered by range tombstones within this group get
+        // dropped by the merge.  the range tombstones themselves
+        // still have to hide keys in older segments, unless there
+        // aren't any.  new segments only ever show up in front.
+        let graveyard = 
+            if st.header.currentState.last() == segs.last() {
+                None
+            } else {
+                let mut graveyard = Graveyard::new();
+                for g in segs.iter() {
+                    match st.header.segments.get(g).unwrap().graveyard {
+                        Some(ref sg) => graveyard.add_all(self.settings.Comparator, &sg.ranges),
+                        None => (),
+                    }
+                }
+                Some(graveyard)
+            };
+        Ok((segs,clist,graveyard))
+    }
+
+    fn writeMerge(&self, clist: Vec<SubCursor>, graveyard: Option<Graveyard>) -> Result<SegmentNum> {
+        let mut mc = MultiCursor::Create(clist, self.settings.Comparator);
+        let mut fs = try!(self.OpenForWriting());
+        try!(mc.First());
+        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, CursorIterator::new(mc), graveyard, &self.settings));
+        Ok(g)
+    }
+
+    // writes the merged segment, which then waits for commitMerge
+    fn finishMerge(&self, mrg: Option<(Vec<SegmentNum>,Vec<SubCursor>,Option<Graveyard>)>) -> Result<Option<SegmentNum>> {
         match mrg {
-            Some((segs,clist)) => {
-                let mut mc = MultiCursor::Create(clist);
-                let mut fs = try!(self.OpenForWriting());
-                try!(mc.First());
-                let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, CursorIterator::new(mc)));
-                //printfn "merged %A to get %A" segs g
-                let mut mergeStuff = try!(self.mergeStuff.lock());
-                mergeStuff.pendingMerges.insert(g, segs);
-                Ok(Some(g))
+            Some((segs,clist,graveyard)) => {
+                match self.writeMerge(clist, graveyard) {
+                    Ok(g) => {
+                        //printfn "merged %A to get %A" segs g
+                        let mut mergeStuff = try!(self.mergeStuff.lock());
+                        mergeStuff.pendingMerges.insert(g, segs);
+                        Ok(Some(g))
+                    },
+                    Err(e) => {
+                        // so they can be merged again
+                        let mut mergeStuff = try!(self.mergeStuff.lock());
+                        for g in segs.iter() {
+                            mergeStuff.merging.remove(g);
+                        }
+                        Err(e)
+                    },
+                }
             },
             None => {
                 Ok(None)
@@ -4021,10 +8781,60 @@ impl InnerPart {
         }
     }
 
+    // segments which were written but which will never be committed.
+    // this includes segments from merges, in which case the segments
+    // being merged become available for merging again.
+    fn forgetWaitingSegments(&self, segs: &[SegmentNum]) -> Result<()> {
+        let forgotten = {
+            let mut waiting = try!(self.segmentsInWaiting.lock());
+            let mut mergeStuff = try!(self.mergeStuff.lock());
+            for g in segs {
+                if !waiting.segmentsInWaiting.contains_key(g) {
+                    return Err(Error::Misc("forgetWaitingSegments: segment not found in segmentsInWaiting"));
+                }
+            }
+            let mut forgotten = HashMap::with_capacity(segs.len());
+            for g in segs {
+                let info = waiting.segmentsInWaiting.remove(g).expect("just checked");
+                forgotten.insert(*g, info);
+                match mergeStuff.pendingMerges.remove(g) {
+                    Some(old) => {
+                        for oldg in old {
+                            mergeStuff.merging.remove(&oldg);
+                        }
+                    },
+                    None => {
+                    },
+                }
+            }
+            forgotten
+        };
+
+        let mut blocksToBeFreed = Vec::new();
+        {
+            let mut cursors = try!(self.cursors.lock());
+            for (g, info) in forgotten {
+                // don't free anything that has a cursor
+                if cursors.cursors.values().any(|csrg| *csrg == g) {
+                    cursors.zombies.insert(g, info);
+                } else {
+                    blocksToBeFreed.push_all(&info.blocks);
+                }
+            }
+        }
+        let mut space = try!(self.space.lock());
+        self.addFreeBlocks(&mut space, blocksToBeFreed);
+        Ok(())
+    }
+
     // TODO maybe commitSegments and commitMerge should be the same function.
     // just check to see if the segment being committed is a merge.  if so,
     // do the extra paperwork.
+    //
+    // the caller must hold the write lock.
     fn commitMerge(&self, newSegNum:SegmentNum) -> Result<()> {
+        // as in commitSegments, the handle before the locks
+        let mut fs = try!(self.OpenForWriting());
 
         let mut st = try!(self.header.lock());
         let mut waiting = try!(self.segmentsInWaiting.lock());
@@ -4093,11 +8903,14 @@ impl InnerPart {
         };
         newSegmentInfo.age = age_of_new_segment;
 
+        // the pairs are no newer than the newest of the segments
+        // they came from
+        newSegmentInfo.committed = segmentsBeingReplaced.values().map(|info| info.committed).max().expect("this cannot be empty");
+
         newHeader.segments.insert(newSegNum, newSegmentInfo);
 
         newHeader.mergeCounter = newHeader.mergeCounter + 1;
 
-        let mut fs = try!(self.OpenForWriting());
         let oldHeaderOverflow = try!(self.writeHeader(&mut st, &mut space, &mut fs, newHeader));
 
         // the write of the new header has succeeded.
@@ -4146,6 +8959,10 @@ impl IPages for InnerPart {
         self.pgsz
     }
 
+    fn PageTransform(&self) -> Option<&'static IPageTransform> {
+        self.settings.PageTransform
+    }
+
     fn Begin(&self) -> Result<PendingSegment> {
         let mut lck = try!(self.nextSeg.lock());
         let p = PendingSegment::new(lck.nextSeg);
@@ -4161,9 +8978,9 @@ impl IPages for InnerPart {
         Ok(blk)
     }
 
-    fn End(&self, ps:PendingSegment, lastPage: PageNum) -> Result<SegmentNum> {
+    fn End(&self, ps:PendingSegment, lastPage: PageNum, bloom: Option<SegmentBloom>, graveyard: Option<SegmentGraveyard>) -> Result<SegmentNum> {
         let (g, blocks, leftovers) = ps.End(lastPage);
-        let info = SegmentInfo {age: 0,blocks:blocks,root:lastPage};
+        let info = SegmentInfo {age: 0,blocks:blocks,root:lastPage,bloom:bloom,graveyard:graveyard,committed:0};
         let mut waiting = try!(self.segmentsInWaiting.lock());
         let mut space = try!(self.space.lock());
         waiting.segmentsInWaiting.insert(g,info);
@@ -4177,90 +8994,109 @@ impl IPages for InnerPart {
 
 }
 
-// ----------------------------------------------------------------
+// what the diag binary does.  it is here so that a program which
+// knows the comparator, page transform or codec a file was made
+// with can pass them along, since the binary only knows the
+// built-in ones.
+pub mod diag {
+    use super::db;
+    use super::DbSettings;
+    use super::Error;
+    use super::ICursor;
+    use super::Result;
+    use super::SegmentNum;
 
-/*
-
-type Database(_io:IDatabaseFile, _settings:DbSettings) =
-
-    let doAutoMerge() = 
-        if settings.AutoMergeEnabled then
-            for level in 0 .. 3 do // TODO max merge level immediate
-                match getPossibleMerge level settings.AutoMergeMinimumPages false with
-                | Some f -> 
-                    let g = f()
-                    commitMerge g
-                | None -> 
-                    () // printfn "cannot merge level %d" level
-            for level in 4 .. 7 do // TODO max merge level
-                match getPossibleMerge level settings.AutoMergeMinimumPages false with
-                | Some f -> 
-                    f |> wrapMergeForLater |> startBackgroundMergeJob
-                | None -> 
-                    () // printfn "cannot merge level %d" level
-
-        member this.ForgetWaitingSegments(guids:seq<Guid>) =
-            // TODO need a test case for this
-            let guidsAsSet = Seq.fold (fun acc g -> Set.add g acc) Set.empty guids
-            let mySegmentsInWaiting = Map.filter (fun g _ -> Set.contains g guidsAsSet) segmentsInWaiting
-            lock critSectionSegmentsInWaiting (fun () ->
-                let remainingSegmentsInWaiting = Map.filter (fun g _ -> Set.contains g guidsAsSet |> not) segmentsInWaiting
-                segmentsInWaiting <- remainingSegmentsInWaiting
-            )
-            lock critSectionCursors (fun () -> 
-                let segmentsToBeFreed = Map.filter (fun g _ -> not (Map.containsKey g cursors)) mySegmentsInWaiting
-                let blocksToBeFreed = Seq.fold (fun acc info -> info.blocks @ acc) List.empty (Map.values segmentsToBeFreed)
-                addFreeBlocks blocksToBeFreed
-            )
-
-        member this.OpenSegmentCursor(g:Guid) =
-            let csr = lock critSectionCursors (fun () ->
-                let h = header
-                getCursor h.segments g (Some checkForGoneSegment)
-            )
-            csr
-
-        member this.GetFreeBlocks() = freeBlocks
-
-        member this.PageSize() = pgsz
-
-        member this.ListSegments() =
-            (header.currentState, header.segments)
-
-        member this.RequestWriteLock(timeout:int) =
-            // TODO need a test case for this
-            getWriteLock false timeout (Some doAutoMerge)
-
-        member this.RequestWriteLock() =
-            getWriteLock false (-1) (Some doAutoMerge)
-
-    type PairBuffer(_db:IDatabase, _limit:int) =
-        let db = _db
-        let limit = _limit
-        let d = System.Collections.Generic.Dictionary<byte[],Blob>()
-        let mutable segs = []
-        let emptyByteArray:byte[] = Array.empty
-        let emptyBlobValue = Blob.Array emptyByteArray
-
-        member this.Flush() =
-            if d.Count > 0 then
-                let g = db.WriteSegment(d)
-                segs <- g :: segs
-                d.Clear()
-
-        member this.AddPair(k:byte[], v:Blob) =
-            // TODO dictionary deals with byte[] keys by reference.
-            d.[k] <- v
-            if d.Count >= limit then
-                this.Flush()
-
-        member this.AddEmptyKey(k:byte[]) =
-            this.AddPair(k, emptyBlobValue)
-
-        member this.Commit(tx:IWriteLock) =
-            tx.CommitSegments segs
-            segs <- []
-*/
+    fn from_utf8(a: Box<[u8]>) -> String {
+        String::from_utf8_lossy(&a).into_owned()
+    }
+
+    fn list_segments(db: &db) -> Result<()> {
+        let (state, segs) = try!(db.ListSegments());
+        for g in state.iter() {
+            let info = segs.get(g).unwrap();
+            println!("{} : age={}  pages={}  root={}", g, info.age(), info.count_pages(), info.root());
+        }
+        Ok(())
+    }
+
+    fn list_all_keys(db: &db) -> Result<()> {
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        while csr.IsValid() {
+            let k = try!(csr.KeyRef()).into_boxed_slice();
+            println!("{} -- {:?}", from_utf8(k), try!(csr.ValueLength()));
+            try!(csr.Next());
+        }
+        Ok(())
+    }
+
+    fn list_free_blocks(db: &db) -> Result<()> {
+        let blocks = try!(db.GetFreeBlocks());
+        let pageSize = db.PageSize();
+        let total: usize = blocks.iter().map(|b| b.count_pages() as usize).sum();
+        println!("{:?}", blocks);
+        println!("Total pages: {}", total);
+        println!("Page Size: {}", pageSize);
+        println!("Total bytes: {}", total * pageSize);
+        Ok(())
+    }
+
+    fn list_segment_keys(db: &db, g: SegmentNum) -> Result<()> {
+        let mut csr = try!(db.open_segment_cursor(g));
+        try!(csr.First());
+        while csr.IsValid() {
+            let k = try!(csr.KeyRef()).into_boxed_slice();
+            println!("{}", from_utf8(k));
+            try!(csr.Next());
+        }
+        Ok(())
+    }
+
+    fn check(db: &db) -> Result<bool> {
+        let report = try!(db.check());
+        for s in report.segments.iter() {
+            println!("{} : leaves={}  parents={}  overflows={}  keys={}  pages={}",
+                     s.segment, s.leaves, s.parents, s.overflows, s.keys, s.pages);
+        }
+        for p in report.problems.iter() {
+            println!("{:?}", p);
+        }
+        println!("Problems: {}", report.problems.len());
+        Ok(report.is_ok())
+    }
+
+    // args are the file, the op, and whatever the op needs.  the
+    // file is opened read-only and never merged, whatever the
+    // settings say.  false means check found problems.
+    pub fn run(args: &[String], settings: DbSettings) -> Result<bool> {
+        if args.len() < 2 {
+            return Err(Error::Misc("diag needs a file and an op"));
+        }
+        let settings = DbSettings {
+            ReadOnly : true,
+            AutoMergeEnabled : false,
+            .. settings
+        };
+        let db = try!(db::new(args[0].clone(), settings));
+        match &args[1][..] {
+            "list_segments" => try!(list_segments(&db)),
+            "list_all_keys" => try!(list_all_keys(&db)),
+            "list_free_blocks" => try!(list_free_blocks(&db)),
+            "list_segment_keys" => {
+                let g = match args.get(2).and_then(|s| s.parse::<SegmentNum>().ok()) {
+                    Some(g) => g,
+                    None => return Err(Error::Misc("list_segment_keys needs a segment number")),
+                };
+                try!(list_segment_keys(&db, g))
+            },
+            "check" => return check(&db),
+            _ => return Err(Error::Misc("unknown op")),
+        }
+        Ok(true)
+    }
+}
+
+// ----------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
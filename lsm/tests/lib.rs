@@ -1,11 +1,13 @@
 
 #![feature(collections)]
+#![feature(collections_bound)]
 #![feature(vec_push_all)]
 
 extern crate misc;
 extern crate lsm;
 
 use lsm::ICursor;
+use lsm::IMergePolicy;
 use misc::tempfile;
 
 fn into_utf8(s : String) -> Box<[u8]> {
@@ -792,6 +794,162 @@ fn blobs_of_many_sizes() {
     assert!(f().is_ok());
 }
 
+// values which overflow across several blocks, each block but the
+// last ending on a boundary page
+fn overflows_across_blocks(name: &str, sizes: &[usize]) -> lsm::Result<()> {
+    fn settings() -> lsm::DbSettings {
+        lsm::DbSettings {
+            PagesPerBlock : 4,
+            .. lsm::DEFAULT_SETTINGS
+        }
+    }
+    fn value(len: usize) -> Box<[u8]> {
+        (0 .. len).map(|j| (j % 251) as u8).collect::<Vec<u8>>().into_boxed_slice()
+    }
+    {
+        let db = try!(lsm::db::new(String::from(name), settings()));
+        let mut d = std::collections::HashMap::new();
+        for (i, len) in sizes.iter().enumerate() {
+            d.insert(into_utf8(format!("{:02}", i)), value(*len));
+        }
+        let g = try!(db.WriteSegment(d));
+        let lck = try!(db.GetWriteLock());
+        try!(lck.commitSegments(vec![g]));
+    }
+
+    let db = try!(lsm::db::new(String::from(name), settings()));
+    assert!(try!(db.check()).is_ok());
+    let mut csr = try!(db.OpenCursor());
+    assert_eq!(sizes.len(), try!(count_keys_forward(&mut csr)));
+    assert_eq!(sizes.len(), try!(count_keys_backward(&mut csr)));
+    for (i, len) in sizes.iter().enumerate() {
+        try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(format!("{:02}", i))), lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert!(value(*len) == read_value(csr.ValueRef().unwrap()).unwrap());
+    }
+    Ok(())
+}
+
+#[test]
+fn lone_leaf_on_last_page_of_block() {
+    fn f(len: usize) -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+            PagesPerBlock : 4,
+            BloomBitsPerKey : 0,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("lone_leaf_on_last_page_of_block"), settings));
+        let mut d = std::collections::HashMap::new();
+        d.insert(into_utf8(String::from("k")), vec![7u8; len].into_boxed_slice());
+        let g = try!(db.WriteSegment(d));
+        let lck = try!(db.GetWriteLock());
+        try!(lck.commitSegments(vec![g]));
+        assert!(try!(db.check()).is_ok());
+        assert!(vec![7u8; len].into_boxed_slice() == try!(db.get(b"k")).unwrap());
+        Ok(())
+    }
+    // the overflowed value comes first, so for some of these
+    // lengths the only leaf is on the last page of the block, and
+    // the root, which is always a parent, goes in the next block.
+    for i in 0 .. 40 {
+        assert!(f(6000 + i * 250).is_ok());
+    }
+}
+
+#[test]
+fn overflow_ends_on_boundary() {
+    // FLAG_ENDS_ON_BOUNDARY used to be 3, which includes the bit
+    // of FLAG_BOUNDARY_NODE, so the first page of an overflow which
+    // ends on a boundary page looked like a boundary page itself.
+    let sizes = (0 .. 20).map(|i| 5000 + i * 3000).collect::<Vec<usize>>();
+    assert!(overflows_across_blocks(&tempfile("overflow_ends_on_boundary"), &sizes).is_ok());
+}
+
+#[test]
+fn overflow_spans_many_blocks() {
+    // after a boundary page, the overflow writer used to stop if
+    // the value was not finished, and carry on if it was, so a
+    // value needing more than two blocks came back short.
+    let sizes = [200000, 10, 100000];
+    assert!(overflows_across_blocks(&tempfile("overflow_spans_many_blocks"), &sizes).is_ok());
+}
+
+#[test]
+fn overflow_ends_on_boundary_among_small_values() {
+    // FLAG_ENDS_ON_BOUNDARY used to be 3, which includes the bit
+    // of FLAG_BOUNDARY_NODE, so the first page of an overflow which
+    // ends on a boundary page looked like a boundary page itself.
+    fn f() -> lsm::Result<()> {
+        fn settings() -> lsm::DbSettings {
+            lsm::DbSettings {
+                PagesPerBlock : 4,
+                .. lsm::DEFAULT_SETTINGS
+            }
+        }
+        fn value(i: usize) -> Box<[u8]> {
+            let len = if i % 10 == 0 { 5000 } else { 50 };
+            (0 .. len).map(|j| (j % 251) as u8).collect::<Vec<u8>>().into_boxed_slice()
+        }
+        let name = tempfile("overflow_ends_on_boundary_among_small_values");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings()));
+            let mut d = std::collections::HashMap::new();
+            for i in 0 .. 300 {
+                d.insert(into_utf8(format!("{:03}", i)), value(i));
+            }
+            let g = try!(db.WriteSegment(d));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        let db = try!(lsm::db::new(name, settings()));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(300, try!(count_keys_forward(&mut csr)));
+        assert_eq!(300, try!(count_keys_backward(&mut csr)));
+        for i in 0 .. 300 {
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(format!("{:03}", i))), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            assert!(value(i) == try!(read_value(try!(csr.ValueRef()))));
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn header_overflow_sizes() {
+    // a segment list too long for the header page is split in two.
+    // the first chunk is the rest of the header page, and the second
+    // is read into the buffer after it.  the first used to be read
+    // from wherever the file happened to be, and the second into
+    // the wrong part of the buffer.
+    fn f(count: usize) -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("header_overflow_sizes");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let mut a = Vec::new();
+            for i in 0 .. count {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i, end: i, step: 1}));
+                a.push(g);
+            }
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(a));
+        }
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(count, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    for &count in [300, 500, 800, 1500, 4000].iter() {
+        assert!(f(count).is_ok());
+    }
+}
+
 #[test]
 fn write_then_read() {
     fn f() -> lsm::Result<()> {
@@ -1094,3 +1252,2673 @@ fn simple_merge() {
     assert!(f().is_ok());
 }
 
+
+#[test]
+fn memtable_put_get_delete() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("memtable_put_get_delete"), lsm::DEFAULT_SETTINGS));
+
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "a", "1");
+        insert_pair_string_string(&mut t1, "b", "2");
+        insert_pair_string_string(&mut t1, "c", "3");
+        let g1 = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g1]));
+        }
+
+        try!(db.put(b"b", b"20"));
+        try!(db.put(b"d", b"4"));
+        try!(db.delete(b"a"));
+
+        assert!(try!(db.get(b"a")).is_none());
+        assert_eq!("20", from_utf8(try!(db.get(b"b")).unwrap()));
+        assert_eq!("3", from_utf8(try!(db.get(b"c")).unwrap()));
+        assert_eq!("4", from_utf8(try!(db.get(b"d")).unwrap()));
+        assert!(try!(db.get(b"e")).is_none());
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(3, try!(count_keys_forward(&mut csr)));
+        assert_eq!(3, try!(count_keys_backward(&mut csr)));
+
+        try!(csr.First());
+        assert_eq!("b", key_as_string(&csr));
+        assert_eq!("20", from_utf8(read_value(csr.ValueRef().unwrap()).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn memtable_flush() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                MaxMemtableBytes : 4096,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("memtable_flush");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            for i in 0 .. 1000 {
+                let k = format!("{:05}", i);
+                let v = format!("{}", i);
+                try!(db.put(k.as_bytes(), v.as_bytes()));
+            }
+            try!(db.delete(b"00500"));
+            try!(db.flush());
+
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(999, try!(count_keys_forward(&mut csr)));
+        }
+
+        // everything was flushed, so it should all be there after reopening
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(999, try!(count_keys_forward(&mut csr)));
+        assert_eq!(999, try!(count_keys_backward(&mut csr)));
+        assert!(try!(db.get(b"00500")).is_none());
+        assert_eq!("42", from_utf8(try!(db.get(b"00042")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn memtable_layers() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("memtable_layers"), settings));
+        // every write lands on a memtable which a cursor still has,
+        // and every cursor has to keep seeing what it saw
+        let mut model = std::collections::BTreeMap::new();
+        let mut csrs = Vec::new();
+        for i in 0 .. 30 {
+            let k = format!("{:03}", i);
+            let v = format!("{}", i);
+            try!(db.put(k.as_bytes(), v.as_bytes()));
+            model.insert(k, v);
+            if i % 3 == 2 {
+                // the key from the last step is in an older layer
+                let k = format!("{:03}", i - 1);
+                try!(db.put(k.as_bytes(), b"again"));
+                model.insert(k, "again".to_string());
+            }
+            if i % 5 == 4 {
+                let start = format!("{:03}", i - 3);
+                let end = format!("{:03}", i - 1);
+                try!(db.delete_range(start.as_bytes(), end.as_bytes()));
+                let doomed: Vec<String> = model.keys().filter(|k| **k >= start && **k < end).cloned().collect();
+                for k in doomed {
+                    model.remove(&k);
+                }
+            }
+            csrs.push((try!(db.OpenCursor()), model.clone()));
+        }
+        for &mut (ref mut csr, ref model) in csrs.iter_mut() {
+            assert_eq!(model.len(), try!(count_keys_forward(csr)));
+            assert_eq!(model.len(), try!(count_keys_backward(csr)));
+            for (k, v) in model.iter() {
+                try!(csr.SeekRef(&lsm::KeyRef::for_slice(k.as_bytes()), lsm::SeekOp::SEEK_EQ));
+                assert!(csr.IsValid());
+                assert_eq!(*v, from_utf8(try!(read_value(try!(csr.ValueRef())))));
+            }
+        }
+        drop(csrs);
+
+        try!(db.flush());
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(model.len(), try!(count_keys_forward(&mut csr)));
+        for (k, v) in model.iter() {
+            assert_eq!(*v, from_utf8(try!(db.get(k.as_bytes())).unwrap()));
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn memtable_flush_write_lock() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                MaxMemtableBytes : 4096,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("memtable_flush_write_lock"), settings));
+        {
+            // a flush commits, so it waits for the write lock to be
+            // free.  meanwhile the memtable keeps everything.
+            let _lck = try!(db.GetWriteLock());
+            for i in 0 .. 1000 {
+                try!(db.put(format!("{:05}", i).as_bytes(), b"v"));
+            }
+            assert_eq!(0, try!(db.ChangeCounter()));
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(1000, try!(count_keys_forward(&mut csr)));
+        }
+        try!(db.put(b"01000", b"v"));
+        assert_eq!(1, try!(db.ChangeCounter()));
+        assert_eq!(1, try!(db.ListSegments()).0.len());
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(1001, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn wal_replay() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("wal_replay");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(db.put(b"a", b"1"));
+            try!(db.put(b"b", b"2"));
+            let mut t1 = std::collections::HashMap::new();
+            insert_pair_string_blob(&mut t1, "c", lsm::Blob::Array(str_to_utf8("3")));
+            insert_pair_string_blob(&mut t1, "d", lsm::Blob::Array(str_to_utf8("4")));
+            insert_pair_string_blob(&mut t1, "a", lsm::Blob::Tombstone);
+            try!(db.write_batch(t1));
+            // no flush
+        }
+
+        // the writes never made it into a segment, but the log has them
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert!(try!(db.get(b"a")).is_none());
+        assert_eq!("2", from_utf8(try!(db.get(b"b")).unwrap()));
+        assert_eq!("3", from_utf8(try!(db.get(b"c")).unwrap()));
+        assert_eq!("4", from_utf8(try!(db.get(b"d")).unwrap()));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(3, try!(count_keys_forward(&mut csr)));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn sync_modes() {
+    // syncs of the file and of the log per commit
+    fn f(mode: lsm::SyncMode, file: u64, log: u64) -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                SyncMode : mode,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile(&format!("sync_modes_{:?}", mode));
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let before = try!(db.SyncStats());
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+            assert_eq!(before, try!(db.SyncStats()));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            let after = try!(db.SyncStats());
+            assert_eq!(before.file + file, after.file);
+            assert_eq!(before.log, after.log);
+
+            // SyncWal is off
+            try!(db.put(b"y", b"2"));
+            assert_eq!(after, try!(db.SyncStats()));
+
+            // the commit marker gets synced before the header
+            try!(db.flush());
+            let before = after;
+            let after = try!(db.SyncStats());
+            assert_eq!(before.file + file, after.file);
+            assert_eq!(before.log + log, after.log);
+
+            try!(db.put(b"x", b"1"));
+            try!(db.sync());
+            let before = after;
+            let after = try!(db.SyncStats());
+            assert_eq!(before.file + 1, after.file);
+            assert_eq!(before.log + 1, after.log);
+        }
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert_eq!("1", from_utf8(try!(db.get(b"x")).unwrap()));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(102, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f(lsm::SyncMode::Off, 0, 0).is_ok());
+    assert!(f(lsm::SyncMode::HeaderOnly, 1, 1).is_ok());
+    assert!(f(lsm::SyncMode::Full, 2, 1).is_ok());
+}
+
+#[test]
+fn wal_torn_tail() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("wal_torn_tail");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(db.put(b"a", b"1"));
+            try!(db.put(b"b", b"2"));
+        }
+
+        // simulate a crash in the middle of appending the last record
+        {
+            let f = try!(std::fs::OpenOptions::new().write(true).open(format!("{}-wal", name)));
+            let len = try!(f.metadata()).len();
+            try!(f.set_len(len - 3));
+        }
+
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            assert_eq!("1", from_utf8(try!(db.get(b"a")).unwrap()));
+            assert!(try!(db.get(b"b")).is_none());
+            try!(db.put(b"c", b"3"));
+        }
+
+        // the torn record was discarded, so the next one is readable
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert_eq!("1", from_utf8(try!(db.get(b"a")).unwrap()));
+        assert!(try!(db.get(b"b")).is_none());
+        assert_eq!("3", from_utf8(try!(db.get(b"c")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// builds a write-ahead log record by hand:  payload length,
+// crc32 of the payload, and the payload.
+fn wal_record(payload: &[u8]) -> Vec<u8> {
+    let mut crc = 0xffffffffu32;
+    for b in payload {
+        crc = crc ^ (*b as u32);
+        for _ in 0 .. 8 {
+            crc = if 0 != (crc & 1) { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    crc = !crc;
+    let mut rec = Vec::new();
+    rec.push_all(&misc::endian::u32_to_bytes_be(payload.len() as u32));
+    rec.push_all(&misc::endian::u32_to_bytes_be(crc));
+    rec.push_all(payload);
+    rec
+}
+
+#[test]
+fn wal_commit_marker() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Read;
+        use std::io::Write;
+
+        fn read_wal(name: &str) -> lsm::Result<Vec<u8>> {
+            let mut f = try!(std::fs::File::open(format!("{}-wal", name)));
+            let mut v = Vec::new();
+            try!(f.read_to_end(&mut v));
+            Ok(v)
+        }
+
+        fn append_wal(name: &str, v: &[u8]) -> lsm::Result<()> {
+            let mut f = try!(std::fs::OpenOptions::new().append(true).open(format!("{}-wal", name)));
+            try!(f.write_all(v));
+            Ok(())
+        }
+
+        // one pair with FLAG_COMMIT_MARKER, holding the counter
+        fn marker(changeCounter: u64) -> Vec<u8> {
+            assert!(changeCounter <= 240);
+            wal_record(&[1, 8, changeCounter as u8])
+        }
+
+        let name = tempfile("wal_commit_marker");
+        let (old, c) = {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(db.put(b"a", b"old"));
+            let old = try!(read_wal(&name));
+            try!(db.flush());
+            let c = try!(db.ChangeCounter());
+            assert_eq!(0, try!(read_wal(&name)).len());
+            try!(db.put(b"a", b"new"));
+            try!(db.flush());
+            (old, c)
+        };
+
+        // simulate a crash after the first flush committed but
+        // before it truncated the log
+        try!(append_wal(&name, &old));
+        try!(append_wal(&name, &marker(c)));
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            assert_eq!("new", from_utf8(try!(db.get(b"a")).unwrap()));
+            // the replay removed the marker and what it covered
+            assert_eq!(0, try!(read_wal(&name)).len());
+        }
+
+        // and a crash after appending the marker but before the
+        // commit, so the batch is not in a segment
+        try!(append_wal(&name, &old));
+        try!(append_wal(&name, &marker(c + 2)));
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            assert_eq!("old", from_utf8(try!(db.get(b"a")).unwrap()));
+            assert_eq!(old, try!(read_wal(&name)));
+
+            // a later commit reaches the counter of that marker
+            for _ in 0 .. 2 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}));
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            assert!(try!(db.ChangeCounter()) >= c + 2);
+        }
+
+        // the batch is still replayed, since its marker is gone
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert_eq!("old", from_utf8(try!(db.get(b"a")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn wal_malformed_record() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Write;
+
+        let name = tempfile("wal_malformed_record");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(db.put(b"a", b"1"));
+        }
+
+        // the checksum is fine, but the key is longer than the record
+        {
+            let mut f = try!(std::fs::OpenOptions::new().append(true).open(format!("{}-wal", name)));
+            try!(f.write_all(&wal_record(&[1, 0, 200, b'b'])));
+        }
+
+        match lsm::db::new(name, lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::CorruptFile(_)) => {
+            },
+            _ => {
+                panic!("expected CorruptFile");
+            },
+        }
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn range_delete() {
+    fn f() -> lsm::Result<()> {
+        fn check(db: &lsm::db, expected: usize) -> lsm::Result<()> {
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(expected, try!(count_keys_forward(&mut csr)));
+            assert_eq!(expected, try!(count_keys_backward(&mut csr)));
+            Ok(())
+        }
+
+        let name = tempfile("range_delete");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            // move it to level 1, so later merges of level 0
+            // still have something older to shadow
+            let r = try!(db.merge(0, 1, None));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitMerge(r.unwrap()));
+            }
+
+            try!(db.delete_range(b"00000010", b"00000020"));
+            try!(check(&db, 90));
+            assert!(try!(db.get(b"00000010")).is_none());
+            assert!(try!(db.get(b"00000019")).is_none());
+            assert_eq!("40", from_utf8(try!(db.get(b"00000020")).unwrap()));
+
+            let mut csr = try!(db.OpenCursor());
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"00000015"), lsm::SeekOp::SEEK_EQ));
+            assert!(!csr.IsValid());
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"00000015"), lsm::SeekOp::SEEK_GE));
+            assert_eq!("00000020", key_as_string(&csr));
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"00000015"), lsm::SeekOp::SEEK_LE));
+            assert_eq!("00000009", key_as_string(&csr));
+
+            // a put after the range delete is newer than it
+            try!(db.put(b"00000015", b"x"));
+            try!(check(&db, 91));
+            // no flush
+        }
+
+        {
+            // the range delete gets replayed from the log
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(check(&db, 91));
+            assert_eq!("x", from_utf8(try!(db.get(b"00000015")).unwrap()));
+            try!(db.flush());
+
+            // a memtable with nothing but a range delete
+            try!(db.delete_range(b"00000030", b"00000040"));
+            try!(db.flush());
+            try!(check(&db, 81));
+        }
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        try!(check(&db, 81));
+        assert_eq!("x", from_utf8(try!(db.get(b"00000015")).unwrap()));
+
+        // merging level 0 leaves the older segment in level 1 alone,
+        // so the range tombstones have to survive the merge
+        let r = try!(db.merge(0, 2, None));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+        try!(check(&db, 81));
+
+        // now everything gets merged and the covered keys are gone
+        let r = try!(db.merge(1, 2, None));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+        try!(check(&db, 81));
+        assert!(try!(db.get(b"00000035")).is_none());
+        assert_eq!("x", from_utf8(try!(db.get(b"00000015")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn snapshot() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("snapshot"), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        {
+            let snap = try!(db.snapshot());
+            assert_eq!(vec![g], snap.segments().to_vec());
+
+            try!(db.put(b"00000005", b"changed"));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 100, end: 199, step: 1}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            try!(db.flush());
+            let r = try!(db.merge(0, 2, None));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitMerge(r.unwrap()));
+            }
+
+            // the merged segments are still there for the snapshot
+            assert!(snap.changeCounter() < try!(db.snapshot()).changeCounter());
+            let mut csr = try!(snap.OpenCursor());
+            assert_eq!(100, try!(count_keys_forward(&mut csr)));
+            assert_eq!(100, try!(count_keys_backward(&mut csr)));
+            assert_eq!("10", from_utf8(try!(snap.get(b"00000005")).unwrap()));
+            let mut csr = try!(snap.OpenCursor());
+            assert_eq!(100, try!(count_keys_forward(&mut csr)));
+        }
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(200, try!(count_keys_forward(&mut csr)));
+        assert_eq!("changed", from_utf8(try!(db.get(b"00000005")).unwrap()));
+
+        // optimistic writes
+        let snap = try!(db.snapshot());
+        assert_eq!("14", from_utf8(try!(snap.get(b"00000007")).unwrap()));
+        let mut t = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t, "00000007", "15");
+        let g = try!(db.WriteSegment(t));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegmentsUnlessChanged(&snap, &[b"00000007"], vec![g]));
+        }
+
+        // the same snapshot is now stale for that key, but not others
+        let mut t = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t, "00000007", "16");
+        let g = try!(db.WriteSegment(t));
+        {
+            let lck = try!(db.GetWriteLock());
+            match lck.commitSegmentsUnlessChanged(&snap, &[b"00000008", b"00000007"], vec![g]) {
+                Err(lsm::Error::Conflict(k)) => assert_eq!("00000007", from_utf8(k)),
+                _ => assert!(false),
+            }
+            try!(lck.commitSegmentsUnlessChanged(&snap, &[b"00000008"], vec![g]));
+        }
+        assert_eq!("16", from_utf8(try!(db.get(b"00000007")).unwrap()));
+
+        // writes which are still in the memtable count too
+        let snap = try!(db.snapshot());
+        try!(db.delete(b"00000009"));
+        let mut t = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t, "00000010", "x");
+        let g = try!(db.WriteSegment(t));
+        {
+            let lck = try!(db.GetWriteLock());
+            assert!(lck.commitSegmentsUnlessChanged(&snap, &[b"00000009"], vec![g]).is_err());
+        }
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn commit_after_put() {
+    fn f() -> lsm::Result<()> {
+        fn commit(db: &lsm::db, v: &str) -> lsm::Result<()> {
+            let mut t = std::collections::HashMap::new();
+            insert_pair_string_string(&mut t, "k", v);
+            let g = try!(db.WriteSegment(t));
+            let lck = try!(db.GetWriteLock());
+            lck.commitSegments(vec![g])
+        }
+
+        let name = tempfile("commit_after_put");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            try!(db.put(b"k", b"a"));
+            try!(commit(&db, "b"));
+            assert_eq!("b", from_utf8(try!(db.get(b"k")).unwrap()));
+            try!(db.flush());
+            assert_eq!("b", from_utf8(try!(db.get(b"k")).unwrap()));
+
+            // and the same for an optimistic write
+            try!(db.put(b"k", b"c"));
+            let snap = try!(db.snapshot());
+            let mut t = std::collections::HashMap::new();
+            insert_pair_string_string(&mut t, "k", "d");
+            let g = try!(db.WriteSegment(t));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegmentsUnlessChanged(&snap, &[b"k"], vec![g]));
+            }
+            assert_eq!("d", from_utf8(try!(db.get(b"k")).unwrap()));
+
+            try!(db.put(b"k", b"e"));
+            try!(commit(&db, "f"));
+        }
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert_eq!("f", from_utf8(try!(db.get(b"k")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn snapshot_conflict_aba() {
+    fn f() -> lsm::Result<()> {
+        fn commit(db: &lsm::db, snap: &lsm::Snapshot, k: &[u8]) -> lsm::Result<()> {
+            let mut t = std::collections::HashMap::new();
+            insert_pair_string_string(&mut t, "00000050", "x");
+            let g = try!(db.WriteSegment(t));
+            let lck = try!(db.GetWriteLock());
+            lck.commitSegmentsUnlessChanged(snap, &[k], vec![g])
+        }
+
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled : false,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("snapshot_conflict_aba"), settings));
+        for i in 0 .. 2 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 100, end: i * 100 + 99, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // a merge of what the snapshot already had changes nothing
+        let snap = try!(db.snapshot());
+        let r = try!(db.merge(0, 2, None));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+        try!(commit(&db, &snap, b"00000005"));
+
+        // changed and changed back, in the memtable
+        let snap = try!(db.snapshot());
+        try!(db.put(b"00000006", b"changed"));
+        try!(db.put(b"00000006", b"12"));
+        assert_eq!("12", from_utf8(try!(db.get(b"00000006")).unwrap()));
+        assert!(commit(&db, &snap, b"00000006").is_err());
+        try!(commit(&db, &snap, b"00000007"));
+
+        // and in segments
+        let snap = try!(db.snapshot());
+        try!(db.put(b"00000008", b"changed"));
+        try!(db.flush());
+        try!(db.put(b"00000008", b"16"));
+        try!(db.flush());
+        assert_eq!("16", from_utf8(try!(db.get(b"00000008")).unwrap()));
+        match commit(&db, &snap, b"00000008") {
+            Err(lsm::Error::Conflict(k)) => assert_eq!("00000008", from_utf8(k)),
+            _ => assert!(false),
+        }
+
+        // a range delete counts as a write to every key in it
+        let snap = try!(db.snapshot());
+        try!(db.delete_range(b"00000020", b"00000030"));
+        assert!(commit(&db, &snap, b"00000025").is_err());
+        try!(db.flush());
+        assert!(commit(&db, &snap, b"00000025").is_err());
+        try!(commit(&db, &snap, b"00000030"));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn zombie_with_two_cursors() {
+    fn f() -> lsm::Result<()> {
+        fn count_free(db: &lsm::db) -> lsm::Result<lsm::PageNum> {
+            Ok(try!(db.GetFreeBlocks()).iter().map(|b| b.count_pages()).sum())
+        }
+
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled : false,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("zombie_with_two_cursors"), settings));
+        for i in 0 .. 2 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 1000, end: i * 1000 + 999, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        let csr1 = try!(db.OpenCursor());
+        let mut csr2 = try!(db.OpenCursor());
+        let r = try!(db.merge(0, 2, None));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+
+        // the merged segments are zombies now.  they used to be
+        // freed as soon as any one of their cursors went away.
+        let free = try!(count_free(&db));
+        drop(csr1);
+        assert_eq!(free, try!(count_free(&db)));
+        assert_eq!(2000, try!(count_keys_forward(&mut csr2)));
+        drop(csr2);
+        assert!(try!(count_free(&db)) > free);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn write_lock_busy() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("write_lock_busy"), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}));
+        {
+            let _lck = try!(db.GetWriteLock());
+            match db.try_write_lock() {
+                Err(lsm::Error::Busy) => (),
+                _ => assert!(false),
+            }
+            match db.write_lock_timeout(std::time::Duration::from_millis(30)) {
+                Err(lsm::Error::Busy) => (),
+                _ => assert!(false),
+            }
+        }
+        {
+            let lck = try!(db.try_write_lock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        {
+            let _lck = try!(db.write_lock_timeout(std::time::Duration::from_millis(30)));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(10, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn forget_waiting_segments() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("forget_waiting_segments");
+        let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+        let g1 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999, step: 1}));
+        let len = try!(std::fs::metadata(&name)).len();
+        try!(db.forget_waiting_segments(&[g1]));
+        assert!(db.forget_waiting_segments(&[g1]).is_err());
+
+        // the pages of the forgotten segment get used again
+        let g2 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999, step: 1}));
+        assert_eq!(len, try!(std::fs::metadata(&name)).len());
+        let g3 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 1000, end: 1999, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            assert!(lck.commitSegments(vec![g1]).is_err());
+            try!(lck.commitSegments(vec![g2, g3]));
+        }
+
+        // forgetting a merge lets its segments be merged again
+        let r = try!(db.merge(0, 2, None));
+        assert!(try!(db.merge(0, 2, None)).is_none());
+        try!(db.forget_waiting_segments(&[r.unwrap()]));
+        let r = try!(db.merge(0, 2, None));
+        assert!(r.is_some());
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn pair_buffer() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("pair_buffer"), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        {
+            let mut pb = lsm::PairBuffer::new(&db, 10, 0);
+            for i in 100 .. 125 {
+                try!(pb.AddPair(into_utf8(format!("{:08}", i)), lsm::Blob::Array(str_to_utf8("a"))));
+            }
+            assert_eq!(2, pb.segments().len());
+            // a later pair for the same key wins, even across segments
+            try!(pb.AddPair(into_utf8(format!("{:08}", 100)), lsm::Blob::Array(str_to_utf8("b"))));
+            try!(pb.AddTombstone(into_utf8(format!("{:08}", 3))));
+            // nothing is visible before the commit
+            assert!(try!(db.get(b"00000100")).is_none());
+            let lck = try!(db.GetWriteLock());
+            try!(pb.Commit(&lck));
+            assert_eq!(0, pb.segments().len());
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(34, try!(count_keys_forward(&mut csr)));
+        assert_eq!("b", from_utf8(try!(db.get(b"00000100")).unwrap()));
+        assert!(try!(db.get(b"00000003")).is_none());
+
+        // limited by size
+        {
+            let mut pb = lsm::PairBuffer::new(&db, 0, 1000);
+            for i in 200 .. 300 {
+                try!(pb.AddPair(into_utf8(format!("{:08}", i)), lsm::Blob::Array(into_utf8(format!("{:092}", i)))));
+            }
+            assert_eq!(10, pb.segments().len());
+            let lck = try!(db.GetWriteLock());
+            try!(pb.Commit(&lck));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(134, try!(count_keys_forward(&mut csr)));
+
+        // stream values count toward the size too
+        {
+            let mut pb = lsm::PairBuffer::new(&db, 0, 1000);
+            for i in 0 .. 10 {
+                let v = vec![i as u8; 492];
+                try!(pb.AddPair(into_utf8(format!("{:08}", 400 + i)), lsm::Blob::Stream(Box::new(std::io::Cursor::new(v)))));
+            }
+            assert_eq!(5, pb.segments().len());
+            let lck = try!(db.GetWriteLock());
+            try!(pb.Commit(&lck));
+        }
+        assert_eq!(vec![9u8; 492].into_boxed_slice(), try!(db.get(b"00000409")).unwrap());
+
+        // a buffer dropped without a commit frees its segments
+        fn free(db: &lsm::db) -> lsm::Result<lsm::PageNum> {
+            Ok(try!(db.GetFreeBlocks()).iter().map(|b| b.count_pages()).sum())
+        }
+        let before = try!(free(&db));
+        let segs = {
+            let mut pb = lsm::PairBuffer::new(&db, 10, 0);
+            for i in 300 .. 325 {
+                try!(pb.AddPair(into_utf8(format!("{:08}", i)), lsm::Blob::Array(str_to_utf8("a"))));
+            }
+            assert_eq!(2, pb.segments().len());
+            assert!(try!(free(&db)) < before);
+            pb.segments().to_vec()
+        };
+        assert!(db.forget_waiting_segments(&segs).is_err());
+        assert_eq!(before, try!(free(&db)));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(144, try!(count_keys_forward(&mut csr)));
+
+        // and so does one which is abandoned, which says whether
+        // that worked.  whatever was not written yet goes too.
+        {
+            let mut pb = lsm::PairBuffer::new(&db, 10, 0);
+            for i in 300 .. 325 {
+                try!(pb.AddPair(into_utf8(format!("{:08}", i)), lsm::Blob::Array(str_to_utf8("a"))));
+            }
+            assert_eq!(2, pb.segments().len());
+            try!(pb.Abandon());
+            assert_eq!(0, pb.segments().len());
+            assert_eq!(before, try!(free(&db)));
+            let lck = try!(db.GetWriteLock());
+            try!(pb.Commit(&lck));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(144, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn open_segment_cursor() {
+    fn f() -> lsm::Result<()> {
+        fn count(csr: &mut lsm::SegmentCursor) -> lsm::Result<usize> {
+            let mut r = 0;
+            try!(csr.First());
+            while csr.IsValid() {
+                r = r + 1;
+                try!(csr.Next());
+            }
+            Ok(r)
+        }
+
+        let name = tempfile("open_segment_cursor");
+        let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+        let g1 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999, step: 1}));
+        let mut t = std::collections::HashMap::new();
+        insert_pair_string_blob(&mut t, "00000005", lsm::Blob::Tombstone);
+        let g2 = try!(db.WriteSegment2(t));
+
+        // segments in waiting
+        {
+            let mut csr = try!(db.open_segment_cursor(g1));
+            assert_eq!(1000, try!(count(&mut csr)));
+            let mut csr = try!(db.open_segment_cursor(g2));
+            try!(csr.First());
+            assert!(csr.IsValid());
+            assert_eq!(None, try!(csr.ValueLength()));
+        }
+
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2, g1]));
+        }
+        let mut csr = try!(db.open_segment_cursor(g1));
+        assert_eq!(1000, try!(count(&mut csr)));
+        assert!(db.open_segment_cursor(g1 + g2 + 100).is_err());
+
+        // the cursor keeps a forgotten segment from being reused
+        let g3 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999, step: 1}));
+        let mut csr3 = try!(db.open_segment_cursor(g3));
+        try!(db.forget_waiting_segments(&[g3]));
+        let len = try!(std::fs::metadata(&name)).len();
+        let g4 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 2000, end: 2999, step: 1}));
+        assert!(try!(std::fs::metadata(&name)).len() > len);
+        assert_eq!(1000, try!(count(&mut csr3)));
+        try!(db.forget_waiting_segments(&[g4]));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn introspection() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("introspection"), lsm::DEFAULT_SETTINGS));
+        let (segs, _) = try!(db.ListSegments());
+        assert!(segs.is_empty());
+        let changes = try!(db.ChangeCounter());
+        let merges = try!(db.MergeCounter());
+
+        let g1 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999, step: 1}));
+        let g2 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 1000, end: 1999, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2, g1]));
+        }
+        assert_eq!(changes + 1, try!(db.ChangeCounter()));
+
+        let (segs, infos) = try!(db.ListSegments());
+        assert_eq!(vec![g2, g1], segs);
+        let next = try!(db.NextPage());
+        let mut pages = 0;
+        for g in segs.iter() {
+            let info = infos.get(g).unwrap();
+            assert_eq!(0, info.age());
+            assert!(info.blocks().iter().any(|b| b.contains_page(info.root())));
+            assert!(info.blocks().iter().all(|b| b.lastPage < next));
+            pages = pages + info.count_pages();
+        }
+        assert!(pages > 0);
+
+        let r = try!(db.merge(0, 2, None)).unwrap();
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r));
+        }
+        assert_eq!(merges + 1, try!(db.MergeCounter()));
+        let (segs, infos) = try!(db.ListSegments());
+        assert_eq!(vec![r], segs);
+        assert_eq!(1, infos.get(&r).unwrap().age());
+
+        // the merged segments were freed
+        let free = try!(db.GetFreeBlocks());
+        let freePages = free.iter().map(|b| b.count_pages()).fold(0, |a, b| a + b);
+        assert!(freePages >= pages);
+        assert!(db.PageSize() > 0);
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn check() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("check");
+        let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+        let report = try!(db.check());
+        assert!(report.is_ok());
+        assert!(report.segments.is_empty());
+
+        let g1 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9999, step: 1}));
+        let mut t = std::collections::HashMap::new();
+        let v = vec![7u8; 20000];
+        insert_pair_string_blob(&mut t, "e", lsm::Blob::Array(v.into_boxed_slice()));
+        let v = vec![8u8; 9000];
+        insert_pair_string_blob(&mut t, "f", lsm::Blob::Array(v.into_boxed_slice()));
+        insert_pair_string_blob(&mut t, "00000005", lsm::Blob::Tombstone);
+        let g2 = try!(db.WriteSegment2(t));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2, g1]));
+        }
+
+        let report = try!(db.check());
+        assert!(report.is_ok());
+        assert_eq!(2, report.segments.len());
+        assert_eq!(g2, report.segments[0].segment);
+        assert_eq!(3, report.segments[0].keys);
+        // two values and the bloom filter
+        assert_eq!(3, report.segments[0].overflows);
+        assert_eq!(10000, report.segments[1].keys);
+        assert!(report.segments[1].parents > 0);
+
+        // every page of a segment is reached from its root
+        let (_, infos) = try!(db.ListSegments());
+        for s in report.segments.iter() {
+            assert_eq!(infos.get(&s.segment).unwrap().count_pages() as usize, s.pages);
+        }
+
+        let r = try!(db.merge(0, 2, None)).unwrap();
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r));
+        }
+        let report = try!(db.check());
+        assert!(report.is_ok());
+        assert_eq!(1, report.segments.len());
+        assert_eq!(10002, report.segments[0].keys);
+
+        // the first page of a file is the header, so page n
+        // starts at (n - 1) * the page size
+        let root = try!(db.ListSegments()).1.get(&r).unwrap().root();
+        let pgsz = db.PageSize() as u64;
+        try!(flip_byte(&name, (root as u64 - 1) * pgsz + 20));
+        let report = try!(db.check());
+        assert!(!report.is_ok());
+        assert!(report.problems.contains(&lsm::Problem::UnreadablePage(r, root)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn read_only() {
+    fn f() -> lsm::Result<()> {
+        fn settings(read_only: bool) -> lsm::DbSettings {
+            lsm::DbSettings {
+                Comparator : Some(&CASE_INSENSITIVE),
+                ReadOnly : read_only,
+                .. lsm::DEFAULT_SETTINGS
+            }
+        }
+        fn args(name: &str, op: &str) -> Vec<String> {
+            vec![String::from(name), String::from(op)]
+        }
+
+        let name = tempfile("read_only");
+        match lsm::db::new(name.clone(), settings(true)) {
+            Err(lsm::Error::Io(_)) => {
+            },
+            _ => panic!(),
+        }
+
+        {
+            let db = try!(lsm::db::new(name.clone(), settings(false)));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            try!(db.put(b"only in the log", b"x"));
+        }
+        let wal = format!("{}-wal", name);
+        let walLen = try!(std::fs::metadata(&wal)).len();
+        assert!(walLen > 0);
+        let len = try!(std::fs::metadata(&name)).len();
+
+        {
+            let db = try!(lsm::db::new(name.clone(), settings(true)));
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(100, try!(count_keys_forward(&mut csr)));
+            match db.put(b"a", b"b") {
+                Err(lsm::Error::ReadOnly) => {
+                },
+                _ => panic!(),
+            }
+            match db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}) {
+                Err(lsm::Error::ReadOnly) => {
+                },
+                _ => panic!(),
+            }
+            assert!(try!(db.check()).is_ok());
+        }
+
+        // diag opens the file read-only whatever it is given, but
+        // it needs the comparator
+        assert!(try!(lsm::diag::run(&args(&name, "check"), settings(false))));
+        match lsm::diag::run(&args(&name, "list_segments"), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::WrongComparator(_)) => {
+            },
+            _ => panic!(),
+        }
+
+        assert_eq!(walLen, try!(std::fs::metadata(&wal)).len());
+        assert_eq!(len, try!(std::fs::metadata(&name)).len());
+        let db = try!(lsm::db::new(name, settings(false)));
+        assert_eq!("x", from_utf8(try!(db.get(b"ONLY IN THE LOG")).unwrap()));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn range_scan() {
+    fn f() -> lsm::Result<()> {
+        fn keys(it: lsm::RangeIterator) -> lsm::Result<Vec<String>> {
+            let mut a = Vec::new();
+            for r in it {
+                let (k, _) = try!(r);
+                a.push(from_utf8(k));
+            }
+            Ok(a)
+        }
+
+        fn keys_rev(it: lsm::RangeIterator) -> lsm::Result<Vec<String>> {
+            let mut a = Vec::new();
+            for r in it.rev() {
+                let (k, _) = try!(r);
+                a.push(from_utf8(k));
+            }
+            Ok(a)
+        }
+
+        let db = try!(lsm::db::new(tempfile("range_scan"), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        try!(db.delete(b"00000012"));
+        try!(db.delete_range(b"00000015", b"00000018"));
+
+        let a = try!(keys(try!(db.range(&b"00000010"[..] .. &b"00000020"[..]))));
+        assert_eq!(vec!["00000010", "00000011", "00000013", "00000014", "00000018", "00000019"], a);
+        let a = try!(keys_rev(try!(db.range(&b"00000010"[..] .. &b"00000020"[..]))));
+        assert_eq!(vec!["00000019", "00000018", "00000014", "00000013", "00000011", "00000010"], a);
+
+        // bounds which are not keys, or which are deleted
+        let a = try!(keys(try!(db.range(&b"00000012"[..] .. &b"00000016"[..]))));
+        assert_eq!(vec!["00000013", "00000014"], a);
+        let a = try!(keys_rev(try!(db.range(&b"0000001"[..] .. &b"00000011x"[..]))));
+        assert_eq!(vec!["00000011", "00000010"], a);
+
+        let a = try!(keys(try!(db.range((std::collections::Bound::Excluded(&b"00000090"[..]), std::collections::Bound::Included(&b"00000093"[..]))))));
+        assert_eq!(vec!["00000091", "00000092", "00000093"], a);
+        let a = try!(keys_rev(try!(db.range((std::collections::Bound::Excluded(&b"00000090"[..]), std::collections::Bound::Included(&b"00000093"[..]))))));
+        assert_eq!(vec!["00000093", "00000092", "00000091"], a);
+        let a = try!(keys(try!(db.range(&b"00000097"[..] ..))));
+        assert_eq!(vec!["00000097", "00000098", "00000099"], a);
+        let a = try!(keys_rev(try!(db.range(.. &b"00000003"[..]))));
+        assert_eq!(vec!["00000002", "00000001", "00000000"], a);
+        assert_eq!(96, try!(db.range(..)).count());
+        assert_eq!(0, try!(db.range(&b"00000020"[..] .. &b"00000020"[..])).count());
+        assert_eq!(0, try!(db.range(&b"00000030"[..] .. &b"00000020"[..])).count());
+
+        let a = try!(keys(try!(db.prefix(b"0000009"))));
+        assert_eq!(10, a.len());
+        assert_eq!("00000090", a[0]);
+        assert_eq!(0, try!(db.prefix(b"1")).count());
+        assert_eq!(96, try!(db.prefix(b"")).count());
+
+        // the two ends meet in the middle, with nothing twice
+        let mut it = try!(db.range(&b"00000040"[..] .. &b"00000045"[..]));
+        let (k, v) = try!(it.next().unwrap());
+        assert_eq!("00000040", from_utf8(k));
+        match v {
+            lsm::Blob::Array(a) => assert_eq!("80", from_utf8(a)),
+            _ => panic!(),
+        }
+        assert_eq!("00000044", from_utf8(try!(it.next_back().unwrap()).0));
+        assert_eq!("00000041", from_utf8(try!(it.next().unwrap()).0));
+        assert_eq!("00000043", from_utf8(try!(it.next_back().unwrap()).0));
+        assert_eq!("00000042", from_utf8(try!(it.next().unwrap()).0));
+        assert!(it.next_back().is_none());
+        assert!(it.next().is_none());
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn multicursor_walk() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("multicursor_walk"), lsm::DEFAULT_SETTINGS));
+        // lots of overlapping segments, each newer than the one
+        // before, and a model of what they should add up to
+        let mut model = std::collections::BTreeMap::new();
+        for s in 0 .. 30 {
+            let mut t = std::collections::HashMap::new();
+            let mut k = s * 3;
+            while k < s * 3 + 60 {
+                let v = format!("{}-{}", k, s);
+                insert_pair_string_blob(&mut t, &format!("{:05}", k), lsm::Blob::Array(into_utf8(v.clone())));
+                model.insert(format!("{:05}", k), v);
+                k = k + s % 4 + 1;
+            }
+            if s % 5 == 0 && s > 0 {
+                insert_pair_string_blob(&mut t, &format!("{:05}", s * 3 - 2), lsm::Blob::Tombstone);
+                model.remove(&format!("{:05}", s * 3 - 2));
+            }
+            let g = try!(db.WriteSegment2(t));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let keys: Vec<String> = model.keys().map(|k| k.clone()).collect();
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(keys.len(), try!(count_keys_forward(&mut csr)));
+        assert_eq!(keys.len(), try!(count_keys_backward(&mut csr)));
+
+        // a random walk, changing direction often
+        let mut pos: Option<usize> = None;
+        let mut r = 12345u32;
+        for _ in 0 .. 3000 {
+            r = r.wrapping_mul(1103515245).wrapping_add(12345);
+            let op = (r >> 16) % 10;
+            match pos {
+                None => {
+                    let target = ((r >> 8) % 160) as usize;
+                    let k = format!("{:05}", target);
+                    if op < 3 {
+                        try!(csr.First());
+                        pos = Some(0);
+                    } else if op < 6 {
+                        try!(csr.Last());
+                        pos = Some(keys.len() - 1);
+                    } else if op < 8 {
+                        try!(csr.SeekRef(&lsm::KeyRef::for_slice(k.as_bytes()), lsm::SeekOp::SEEK_GE));
+                        pos = keys.iter().position(|x| *x >= k);
+                    } else {
+                        try!(csr.SeekRef(&lsm::KeyRef::for_slice(k.as_bytes()), lsm::SeekOp::SEEK_LE));
+                        pos = keys.iter().rposition(|x| *x <= k);
+                    }
+                },
+                Some(i) => {
+                    if op < 5 {
+                        try!(csr.Next());
+                        pos = if i + 1 < keys.len() { Some(i + 1) } else { None };
+                    } else {
+                        try!(csr.Prev());
+                        pos = if i > 0 { Some(i - 1) } else { None };
+                    }
+                },
+            }
+            match pos {
+                Some(i) => {
+                    assert!(csr.IsValid());
+                    assert_eq!(keys[i], key_as_string(&csr));
+                    let v = from_utf8(try!(read_value(try!(csr.ValueRef()))));
+                    assert_eq!(*model.get(&keys[i]).unwrap(), v);
+                },
+                None => {
+                    assert!(!csr.IsValid());
+                },
+            }
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn page_cache() {
+    fn f() -> lsm::Result<()> {
+        fn write(db: &lsm::db, v: &str) -> lsm::Result<lsm::SegmentNum> {
+            let mut t = std::collections::HashMap::new();
+            for i in 0 .. 3000 {
+                insert_pair_string_string(&mut t, &format!("k{:05}", i), v);
+            }
+            db.WriteSegment(t)
+        }
+
+        fn count_values(db: &lsm::db, v: &str) -> lsm::Result<usize> {
+            let mut csr = try!(db.OpenCursor());
+            try!(csr.First());
+            let mut count = 0;
+            while csr.IsValid() {
+                let a = try!(read_value(try!(csr.ValueRef())));
+                if from_utf8(a) == v {
+                    count = count + 1;
+                }
+                try!(csr.Next());
+            }
+            Ok(count)
+        }
+
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: false,
+            PageCacheSize: 1000,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("page_cache"), settings));
+        let ga = try!(write(&db, "a"));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![ga]));
+        }
+
+        // the first pass reads from the file, the second from the
+        // cache.  only the first page of the bloom filter, which the
+        // cursor steps over after the last leaf, is read again.
+        assert_eq!(3000, try!(count_values(&db, "a")));
+        let st1 = try!(db.PageCacheStats());
+        assert!(st1.pages > 0);
+        assert_eq!(st1.pages as u64 + 1, st1.misses);
+        assert_eq!(3000, try!(count_values(&db, "a")));
+        let st2 = try!(db.PageCacheStats());
+        assert_eq!(st1.misses + 1, st2.misses);
+        assert!(st2.hits > st1.hits);
+        assert_eq!(st1.pages, st2.pages);
+
+        // merging frees the pages of both segments, so they must
+        // leave the cache, or the next segment written into that
+        // space would be read as its predecessor
+        let gb = try!(write(&db, "b"));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![gb]));
+        }
+        assert_eq!(3000, try!(count_values(&db, "b")));
+        let before = try!(db.PageCacheStats()).pages;
+        let r = try!(db.merge(0, 2, None)).unwrap();
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r));
+        }
+        assert!(try!(db.PageCacheStats()).pages < before);
+        assert_eq!(3000, try!(count_values(&db, "b")));
+
+        let gc = try!(write(&db, "c"));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![gc]));
+        }
+        assert_eq!(3000, try!(count_values(&db, "c")));
+        let report = try!(db.check());
+        assert!(report.is_ok());
+
+        // a small cache stays small
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: false,
+            PageCacheSize: 4,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("page_cache_small"), settings));
+        let g = try!(write(&db, "d"));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        assert_eq!(3000, try!(count_values(&db, "d")));
+        assert_eq!(3000, try!(count_values(&db, "d")));
+        assert!(try!(db.PageCacheStats()).pages <= 4);
+
+        // and no cache at all never counts anything
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: false,
+            PageCacheSize: 0,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("page_cache_none"), settings));
+        let g = try!(write(&db, "e"));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        assert_eq!(3000, try!(count_values(&db, "e")));
+        let st = try!(db.PageCacheStats());
+        assert_eq!(0, st.hits + st.misses);
+        assert_eq!(0, st.pages);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn mmap_reads() {
+    fn f() -> lsm::Result<()> {
+        fn write(db: &lsm::db, start: usize, v: &str) -> lsm::Result<lsm::SegmentNum> {
+            let mut t = std::collections::HashMap::new();
+            for i in start .. start + 3000 {
+                insert_pair_string_string(&mut t, &format!("k{:05}", i), v);
+            }
+            let g = try!(db.WriteSegment(t));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            Ok(g)
+        }
+
+        fn check_values(csr: &mut lsm::LivingCursor, start: usize, v: &str) -> lsm::Result<()> {
+            for i in start .. start + 3000 {
+                let k = format!("k{:05}", i);
+                try!(csr.SeekRef(&lsm::KeyRef::for_slice(k.as_bytes()), lsm::SeekOp::SEEK_EQ));
+                assert!(csr.IsValid());
+                assert_eq!(k, key_as_string(csr));
+                assert_eq!(v, from_utf8(try!(read_value(try!(csr.ValueRef())))));
+            }
+            Ok(())
+        }
+
+        for codec in vec![None, Some(&lsm::LZ4 as &lsm::ICodec)] {
+            let settings = lsm::DbSettings {
+                AutoMergeEnabled: false,
+                MmapReads: true,
+                Compression: codec,
+                .. lsm::DEFAULT_SETTINGS
+            };
+            let name = tempfile("mmap_reads");
+            let db = try!(lsm::db::new(name.clone(), settings));
+            try!(write(&db, 0, "a"));
+            let mut csr1 = try!(db.OpenCursor());
+            try!(check_values(&mut csr1, 0, "a"));
+
+            // the file grows past the mapping which csr1 is using,
+            // and each cursor keeps reading from its own
+            try!(write(&db, 3000, "b"));
+            let mut t = std::collections::HashMap::new();
+            insert_pair_string_blob(&mut t, "k99999", lsm::Blob::Array(vec![9u8; 30000].into_boxed_slice()));
+            // and a key just too long to be inline.  overflowed keys
+            // are read out of the mapping too.
+            let long = format!("l{}", std::iter::repeat("x").take(4069).collect::<String>());
+            insert_pair_string_blob(&mut t, &long, lsm::Blob::Array(str_to_utf8("long")));
+            let g = try!(db.WriteSegment2(t));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            let mut csr2 = try!(db.OpenCursor());
+            try!(check_values(&mut csr2, 0, "a"));
+            try!(check_values(&mut csr2, 3000, "b"));
+            try!(csr2.SeekRef(&lsm::KeyRef::for_slice(b"k99999"), lsm::SeekOp::SEEK_EQ));
+            assert_eq!(30000, try!(read_value(try!(csr2.ValueRef()))).len());
+            try!(csr2.SeekRef(&lsm::KeyRef::for_slice(long.as_bytes()), lsm::SeekOp::SEEK_EQ));
+            assert!(csr2.IsValid());
+            assert_eq!(long, key_as_string(&csr2));
+            assert_eq!("long", from_utf8(try!(read_value(try!(csr2.ValueRef())))));
+            try!(check_values(&mut csr1, 0, "a"));
+            assert_eq!(6002, try!(count_keys_backward(&mut csr2)));
+
+            // the mapping is not copied into the page cache
+            let st = try!(db.PageCacheStats());
+            assert_eq!(0, st.hits + st.misses);
+
+            let report = try!(db.check());
+            assert!(report.is_ok());
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn file_pool() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: false,
+            MaxOpenFiles: 2,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("file_pool"), settings));
+        for i in 0 .. 50 {
+            let mut t = std::collections::HashMap::new();
+            for j in 0 .. 20 {
+                insert_pair_string_string(&mut t, &format!("{:03}-{:03}", j, i), "v");
+            }
+            let g = try!(db.WriteSegment(t));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // fifty segment cursors, still one reader and one writer
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(1000, try!(count_keys_forward(&mut csr)));
+        assert_eq!(1000, try!(count_keys_backward(&mut csr)));
+        let mut csr2 = try!(db.OpenCursor());
+        assert_eq!(1000, try!(count_keys_forward(&mut csr2)));
+        assert_eq!(2, try!(db.OpenFileCount()));
+
+        // more writers than handles.  they wait for each other
+        // instead of failing, whether they write segments or the
+        // header.
+        let settings = lsm::DbSettings {
+            MaxOpenFiles: 2,
+            MaxMemtableBytes: 4096,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = std::sync::Arc::new(try!(lsm::db::new(tempfile("file_pool_wait"), settings)));
+        let handles: Vec<_> = (0 .. 4).map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || -> lsm::Result<()> {
+                for i in 0 .. 500 {
+                    try!(db.put(format!("{}-{:04}", t, i).as_bytes(), b"some value or other"));
+                }
+                try!(db.flush());
+                try!(db.sync());
+                Ok(())
+            })
+        }).collect();
+        for h in handles {
+            assert!(h.join().unwrap().is_ok());
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        assert!(try!(db.OpenFileCount()) <= 2);
+
+        // with room for nothing but the reader, nothing could
+        // ever be committed
+        let settings = lsm::DbSettings {
+            MaxOpenFiles: 1,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        match lsm::db::new(tempfile("file_pool_one"), settings) {
+            Err(lsm::Error::TooManyOpenFiles(1)) => (),
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+static LEVELED : lsm::LeveledMergePolicy = lsm::LeveledMergePolicy { Ratio: 2, ForegroundPages: 1000 };
+
+#[test]
+fn merge_policy() {
+    fn f() -> lsm::Result<()> {
+        fn candidates(a: &[(u32, lsm::PageNum, bool)]) -> Vec<lsm::MergeCandidate> {
+            a.iter().enumerate().map(|(i, &(age, pages, merging))| {
+                lsm::MergeCandidate { segment: i as lsm::SegmentNum, age: age, pages: pages, merging: merging }
+            }).collect()
+        }
+
+        // newest first, so the oldest four of age 0 get merged
+        let tiered = lsm::TieredMergePolicy { Segments: 4, ForegroundAges: 4 };
+        let c = candidates(&[(0, 1, false), (0, 1, false), (0, 1, false), (0, 1, false), (0, 1, false), (1, 4, false)]);
+        assert_eq!(Some(1 .. 5), tiered.Propose(&c, false));
+        assert_eq!(None, tiered.Propose(&c, true));
+        let c = candidates(&[(0, 1, false), (0, 1, false), (0, 1, false), (0, 1, false), (0, 1, true), (1, 4, false)]);
+        assert_eq!(None, tiered.Propose(&c, false));
+        let c = candidates(&[(4, 1, false), (4, 1, false), (4, 1, false), (4, 1, false), (4, 1, false)]);
+        assert_eq!(None, tiered.Propose(&c, false));
+        assert_eq!(Some(1 .. 5), tiered.Propose(&c, true));
+        // there is no oldest age
+        let c = candidates(&[(0, 1, false), (9, 1, false), (9, 1, false), (9, 1, false), (9, 1, false), (9, 1, false)]);
+        assert_eq!(None, tiered.Propose(&c, false));
+        assert_eq!(Some(2 .. 6), tiered.Propose(&c, true));
+        let tiered = lsm::TieredMergePolicy { Segments: 4, ForegroundAges: 10 };
+        assert_eq!(Some(2 .. 6), tiered.Propose(&c, false));
+        assert_eq!(None, tiered.Propose(&c, true));
+
+        let leveled = lsm::LeveledMergePolicy { Ratio: 4, ForegroundPages: 20 };
+        let c = candidates(&[(0, 1, false), (0, 4, false), (0, 16, false), (0, 64, false)]);
+        assert_eq!(None, leveled.Propose(&c, false));
+        let c = candidates(&[(0, 2, false), (0, 4, false), (0, 16, false), (0, 64, false)]);
+        assert_eq!(Some(0 .. 2), leveled.Propose(&c, false));
+        let c = candidates(&[(0, 1, false), (0, 8, false), (0, 16, false), (0, 64, false)]);
+        assert_eq!(None, leveled.Propose(&c, false));
+        assert_eq!(Some(1 .. 3), leveled.Propose(&c, true));
+        let c = candidates(&[(0, 2, true), (0, 4, false), (0, 16, false), (0, 64, false)]);
+        assert_eq!(None, leveled.Propose(&c, true));
+        // only the pair which is out of order, not everything newer
+        let c = candidates(&[(0, 1, false), (0, 4, false), (0, 8, false), (0, 16, false), (0, 256, false)]);
+        assert_eq!(Some(2 .. 4), leveled.Propose(&c, true));
+        // and when that one is too big or busy, the next one
+        assert_eq!(Some(1 .. 3), leveled.Propose(&c, false));
+        let c = candidates(&[(0, 2, false), (0, 4, false), (0, 8, true), (0, 16, false), (0, 256, false)]);
+        assert_eq!(Some(0 .. 2), leveled.Propose(&c, true));
+        let huge = lsm::LeveledMergePolicy { Ratio: lsm::PageNum::max_value() / 2, ForegroundPages: 20 };
+        let c = candidates(&[(0, 3, false), (0, lsm::PageNum::max_value() - 1, false)]);
+        assert_eq!(None, huge.Propose(&c, false));
+        assert_eq!(Some(0 .. 2), huge.Propose(&c, true));
+
+        // whatever the merger does, foreground or background, it is
+        // done when the db is dropped
+        let name = tempfile("merge_policy");
+        {
+            let settings = lsm::DbSettings {
+                AutoMergeEnabled: true,
+                MergePolicy: Some(&LEVELED),
+                .. lsm::DEFAULT_SETTINGS
+            };
+            let db = try!(lsm::db::new(name.clone(), settings));
+            for i in 0 .. 30 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 100, end: (i+1) * 100 - 1, step: 1}));
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+        }
+
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: false,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(name, settings));
+        let (state, segs) = try!(db.ListSegments());
+        assert!(state.len() > 1 && state.len() < 30);
+        for w in state.windows(2) {
+            let newer = segs.get(&w[0]).unwrap().count_pages();
+            let older = segs.get(&w[1]).unwrap().count_pages();
+            assert!(newer * 2 <= older);
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(3000, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// merges everything, but only on the merge thread
+struct BackgroundOnly;
+
+impl lsm::IMergePolicy for BackgroundOnly {
+    fn Propose(&self, segments: &[lsm::MergeCandidate], background: bool) -> Option<std::ops::Range<usize>> {
+        if background && segments.len() > 1 && !segments.iter().any(|s| s.merging) {
+            Some(0 .. segments.len())
+        } else {
+            None
+        }
+    }
+}
+
+static BACKGROUND_ONLY : BackgroundOnly = BackgroundOnly;
+
+#[test]
+fn background_merge_write_lock() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: true,
+            MergePolicy: Some(&BACKGROUND_ONLY),
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("background_merge_write_lock"), settings));
+        let merges = try!(db.MergeCounter());
+        {
+            let lck = try!(db.GetWriteLock());
+            for i in 0 .. 2 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 100, end: (i+1) * 100 - 1, step: 1}));
+                try!(lck.commitSegments(vec![g]));
+            }
+            // the merge thread can write its segment, but it
+            // cannot commit it while we hold the lock
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            assert_eq!(merges, try!(db.MergeCounter()));
+        }
+
+        let mut waited = 0;
+        while try!(db.MergeCounter()) == merges {
+            assert!(waited < 10000);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            waited = waited + 10;
+        }
+        let (state, _) = try!(db.ListSegments());
+        assert_eq!(1, state.len());
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(200, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// proposes a merge of more segments than there are
+struct Impossible {
+    background: bool,
+}
+
+impl lsm::IMergePolicy for Impossible {
+    fn Propose(&self, segments: &[lsm::MergeCandidate], background: bool) -> Option<std::ops::Range<usize>> {
+        if background == self.background {
+            Some(0 .. segments.len() + 1)
+        } else {
+            None
+        }
+    }
+}
+
+static IMPOSSIBLE_FOREGROUND : Impossible = Impossible { background: false };
+static IMPOSSIBLE_BACKGROUND : Impossible = Impossible { background: true };
+
+#[test]
+fn automerge_errors() {
+    fn f(policy: &'static Impossible) -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled: true,
+            MergePolicy: Some(policy),
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("automerge_errors"), settings));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            // the merge fails, but the commit does not
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(100, try!(count_keys_forward(&mut csr)));
+
+        // the error shows up the next time somebody wants the lock
+        let mut waited = 0;
+        loop {
+            match db.GetWriteLock() {
+                Err(lsm::Error::Misc(_)) => {
+                    break;
+                },
+                Err(e) => {
+                    return Err(e);
+                },
+                Ok(_) => {
+                    assert!(!policy.background);
+                    assert!(waited < 10000);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    waited = waited + 10;
+                },
+            }
+        }
+        // but only once
+        {
+            let _lck = try!(db.GetWriteLock());
+        }
+        Ok(())
+    }
+    assert!(f(&IMPOSSIBLE_FOREGROUND).is_ok());
+    assert!(f(&IMPOSSIBLE_BACKGROUND).is_ok());
+}
+
+#[test]
+fn automerge() {
+    fn f(enabled: bool) -> lsm::Result<usize> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : enabled,
+                AutoMergeMinimumSegments : 4,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("automerge"), settings));
+        for i in 0 .. 20 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 100, end: (i+1) * 100 - 1, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        assert_eq!(2000, try!(count_keys_backward(&mut csr)));
+
+        // level 0 never gets more than AutoMergeMinimumSegments
+        // segments when automerge is on.
+        let r = try!(db.merge(0, 5, None));
+        Ok(if r.is_some() { 1 } else { 0 })
+    }
+    assert_eq!(0, f(true).unwrap());
+    assert_eq!(1, f(false).unwrap());
+}
+
+fn compressible_pairs(count: usize) -> std::collections::HashMap<Box<[u8]>,lsm::Blob> {
+    let mut t = std::collections::HashMap::new();
+    for i in 0 .. count {
+        let k = format!("key{:08}", i);
+        // every tenth value is big enough to overflow
+        let reps = if i % 10 == 0 { 2000 } else { 3 };
+        let v = std::iter::repeat(format!("value of {} ", i)).take(reps).collect::<String>();
+        insert_pair_string_blob(&mut t, &k, lsm::Blob::Array(into_utf8(v)));
+    }
+    t
+}
+
+#[test]
+fn compression() {
+    fn write(name: &str, codec: Option<&'static lsm::ICodec>) -> lsm::Result<u64> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                Compression : codec,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(String::from(name), settings));
+        let g = try!(db.WriteSegment2(compressible_pairs(5000)));
+        let lck = try!(db.GetWriteLock());
+        try!(lck.commitSegments(vec![g]));
+        Ok(try!(std::fs::metadata(name)).len())
+    }
+
+    fn f() -> lsm::Result<()> {
+        let plain = try!(write(&tempfile("compression_none"), None));
+        let name = tempfile("compression_lz4");
+        let compressed = try!(write(&name, Some(&lsm::LZ4)));
+        assert!(compressed * 2 < plain);
+
+        // the built-in codec can be read without being in the settings
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(5000, try!(count_keys_forward(&mut csr)));
+        assert_eq!(5000, try!(count_keys_backward(&mut csr)));
+        for i in 0 .. 5000 {
+            let k = format!("key{:08}", i);
+            let reps = if i % 10 == 0 { 2000 } else { 3 };
+            let v = std::iter::repeat(format!("value of {} ", i)).take(reps).collect::<String>();
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(k)), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            assert_eq!(Some(v.len()), try!(csr.ValueLength()));
+            assert_eq!(v, from_utf8(try!(read_value(try!(csr.ValueRef())))));
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// the built-in codec under another id, to stand in for a codec
+// the db knows nothing about
+struct OtherCodec;
+
+impl lsm::ICodec for OtherCodec {
+    fn Id(&self) -> u8 {
+        200
+    }
+
+    fn Compress(&self, a: &[u8]) -> Vec<u8> {
+        lsm::LZ4.Compress(a)
+    }
+
+    fn Decompress(&self, a: &[u8], len: usize) -> Option<Vec<u8>> {
+        lsm::LZ4.Decompress(a, len)
+    }
+}
+
+static OTHER_CODEC : OtherCodec = OtherCodec;
+
+#[test]
+fn custom_codec() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("custom_codec");
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                Compression : Some(&OTHER_CODEC),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let g = try!(db.WriteSegment2(compressible_pairs(1000)));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let mut csr = try!(db.OpenCursor());
+            match csr.First() {
+                Err(lsm::Error::UnknownCodec(200)) => {
+                },
+                _ => panic!(),
+            }
+        }
+
+        let settings = lsm::DbSettings {
+                Compression : Some(&OTHER_CODEC),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(name, settings));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(1000, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// a keyed xor stream, with a mac over the plain page in the
+// reserved bytes.  no good as encryption, but enough to show that
+// every page goes through the transform.
+struct XorTransform {
+    key: u64,
+}
+
+impl XorTransform {
+    fn mac(&self, page: lsm::PageNum, buf: &[u8]) -> u64 {
+        let mut h = 0xcbf29ce484222325u64 ^ self.key ^ (page as u64);
+        for b in buf {
+            h = (h ^ (*b as u64)).wrapping_mul(0x100000001b3);
+        }
+        h
+    }
+
+    fn xor(&self, page: lsm::PageNum, buf: &mut [u8]) {
+        let mut x = self.key ^ (page as u64).wrapping_mul(0x9e3779b97f4a7c15) | 1;
+        for b in buf.iter_mut() {
+            x = x ^ (x << 13);
+            x = x ^ (x >> 7);
+            x = x ^ (x << 17);
+            *b = *b ^ (x as u8);
+        }
+    }
+}
+
+impl lsm::IPageTransform for XorTransform {
+    fn Id(&self) -> u8 {
+        100
+    }
+
+    fn ReservedBytes(&self) -> usize {
+        8
+    }
+
+    fn Encode(&self, page: lsm::PageNum, buf: &mut [u8]) {
+        let at = buf.len() - 8;
+        let mac = self.mac(page, &buf[0 .. at]);
+        for i in 0 .. 8 {
+            buf[at + i] = (mac >> (8 * i)) as u8;
+        }
+        self.xor(page, buf);
+    }
+
+    fn Decode(&self, page: lsm::PageNum, buf: &mut [u8]) -> bool {
+        self.xor(page, buf);
+        let at = buf.len() - 8;
+        let mut mac = 0u64;
+        for i in 0 .. 8 {
+            mac = mac | ((buf[at + i] as u64) << (8 * i));
+        }
+        mac == self.mac(page, &buf[0 .. at])
+    }
+}
+
+static XOR_TRANSFORM : XorTransform = XorTransform { key: 0x0123456789abcdef };
+static XOR_TRANSFORM_OTHER_KEY : XorTransform = XorTransform { key: 42 };
+
+#[test]
+fn page_transform() {
+    fn f(codec: Option<&'static lsm::ICodec>) -> lsm::Result<()> {
+        let name = tempfile("page_transform");
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                Compression : codec,
+                PageTransform : Some(&XOR_TRANSFORM),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let g = try!(db.WriteSegment2(compressible_pairs(2000)));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // none of the values are in the file as they were written
+        {
+            use std::io::Read;
+            let mut a = Vec::new();
+            try!(try!(std::fs::File::open(&name)).read_to_end(&mut a));
+            let v = b"value of";
+            assert!(!a.windows(v.len()).any(|w| w == v));
+        }
+
+        match lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::WrongPageTransform(100)) => {
+            },
+            _ => panic!(),
+        }
+
+        // the wrong key gets noticed by the first page which is read
+        {
+            let settings = lsm::DbSettings {
+                    PageTransform : Some(&XOR_TRANSFORM_OTHER_KEY),
+                    .. lsm::DEFAULT_SETTINGS
+                };
+            let db = try!(lsm::db::new(name.clone(), settings));
+            match db.get(b"key00000005") {
+                Err(lsm::Error::CorruptPage{..}) => {
+                },
+                _ => panic!(),
+            }
+        }
+
+        let settings = lsm::DbSettings {
+                PageTransform : Some(&XOR_TRANSFORM),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(name, settings));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        assert_eq!(2000, try!(count_keys_backward(&mut csr)));
+        for i in 0 .. 2000 {
+            let k = format!("key{:08}", i);
+            let reps = if i % 10 == 0 { 2000 } else { 3 };
+            let v = std::iter::repeat(format!("value of {} ", i)).take(reps).collect::<String>();
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(k)), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            assert_eq!(v, from_utf8(try!(read_value(try!(csr.ValueRef())))));
+        }
+        Ok(())
+    }
+    assert!(f(None).is_ok());
+    assert!(f(Some(&lsm::LZ4)).is_ok());
+}
+
+#[test]
+fn wal_page_transform() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Read;
+        use std::io::Write;
+
+        fn read_wal(name: &str) -> lsm::Result<Vec<u8>> {
+            let mut f = try!(std::fs::File::open(format!("{}-wal", name)));
+            let mut v = Vec::new();
+            try!(f.read_to_end(&mut v));
+            Ok(v)
+        }
+
+        fn contains(a: &[u8], v: &[u8]) -> bool {
+            a.windows(v.len()).any(|w| w == v)
+        }
+
+        fn settings() -> lsm::DbSettings {
+            lsm::DbSettings {
+                AutoMergeEnabled : false,
+                PageTransform : Some(&XOR_TRANSFORM),
+                .. lsm::DEFAULT_SETTINGS
+            }
+        }
+
+        let name = tempfile("wal_page_transform");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings()));
+            try!(db.put(b"a", b"secret value one"));
+            try!(db.put(b"b", b"secret value two"));
+            try!(db.delete_range(b"c", b"d"));
+            // no flush
+        }
+
+        // neither the keys nor the values are in the log as they were written
+        {
+            let a = try!(read_wal(&name));
+            assert!(a.len() > 0);
+            assert!(!contains(&a, b"secret value"));
+        }
+
+        // the wrong key gets noticed by the replay
+        {
+            let otherKey = lsm::DbSettings {
+                    PageTransform : Some(&XOR_TRANSFORM_OTHER_KEY),
+                    .. lsm::DEFAULT_SETTINGS
+                };
+            match lsm::db::new(name.clone(), otherKey) {
+                Err(lsm::Error::CorruptFile(_)) => {
+                },
+                _ => panic!(),
+            }
+        }
+
+        {
+            let db = try!(lsm::db::new(name.clone(), settings()));
+            assert_eq!("secret value one", from_utf8(try!(db.get(b"a")).unwrap()));
+            assert_eq!("secret value two", from_utf8(try!(db.get(b"b")).unwrap()));
+            // a record written after the replay is read back too
+            try!(db.put(b"b", b"secret value three"));
+        }
+
+        // a plain record, as in a log from before records were
+        // transformed, still gets replayed
+        {
+            let mut f = try!(std::fs::OpenOptions::new().append(true).open(format!("{}-wal", name)));
+            try!(f.write_all(&wal_record(&[1, 0, 1, b'e', 1, b'5'])));
+        }
+
+        let db = try!(lsm::db::new(name.clone(), settings()));
+        assert_eq!("secret value one", from_utf8(try!(db.get(b"a")).unwrap()));
+        assert_eq!("secret value three", from_utf8(try!(db.get(b"b")).unwrap()));
+        assert_eq!("5", from_utf8(try!(db.get(b"e")).unwrap()));
+        try!(db.flush());
+        assert_eq!(0, try!(read_wal(&name)).len());
+        assert_eq!("secret value three", from_utf8(try!(db.get(b"b")).unwrap()));
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// ascii letters in either case are the same key
+struct CaseInsensitive;
+
+impl lsm::IComparator for CaseInsensitive {
+    fn Name(&self) -> &str {
+        "case insensitive"
+    }
+
+    fn Compare(&self, x: &[u8], y: &[u8]) -> std::cmp::Ordering {
+        let x = x.iter().map(|b| b.to_ascii_lowercase());
+        let y = y.iter().map(|b| b.to_ascii_lowercase());
+        x.cmp(y)
+    }
+}
+
+static CASE_INSENSITIVE : CaseInsensitive = CaseInsensitive;
+
+#[test]
+fn comparator_equal_keys() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+            AutoMergeEnabled : false,
+            Comparator : Some(&CASE_INSENSITIVE),
+            BloomBitsPerKey : 10,
+            .. lsm::DEFAULT_SETTINGS
+        };
+        let db = try!(lsm::db::new(tempfile("comparator_equal_keys"), settings));
+        let mut t = std::collections::HashMap::new();
+        for i in 0 .. 100 {
+            insert_pair_string_string(&mut t, &format!("Key{:03}", i), &format!("{}", i));
+        }
+        let g = try!(db.WriteSegment(t));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        // a bloom filter over the bytes would say these are not there
+        for i in 0 .. 100 {
+            assert_eq!(format!("{}", i), from_utf8(try!(db.get(format!("KEY{:03}", i).as_bytes())).unwrap()));
+            assert_eq!(format!("{}", i), from_utf8(try!(db.get(format!("key{:03}", i).as_bytes())).unwrap()));
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+// numbers without padding, in numeric order, as long as there
+// are no leading zeros
+struct LengthFirst;
+
+impl lsm::IComparator for LengthFirst {
+    fn Name(&self) -> &str {
+        "length first"
+    }
+
+    fn Compare(&self, x: &[u8], y: &[u8]) -> std::cmp::Ordering {
+        (x.len(), x).cmp(&(y.len(), y))
+    }
+}
+
+static LENGTH_FIRST : LengthFirst = LengthFirst;
+
+#[test]
+fn comparator() {
+    fn f() -> lsm::Result<()> {
+        fn settings() -> lsm::DbSettings {
+            lsm::DbSettings {
+                AutoMergeEnabled : false,
+                Comparator : Some(&LENGTH_FIRST),
+                .. lsm::DEFAULT_SETTINGS
+            }
+        }
+
+        fn check(db: &lsm::db) -> lsm::Result<()> {
+            let mut csr = try!(db.OpenCursor());
+            let mut prev: Option<u32> = None;
+            try!(csr.First());
+            while csr.IsValid() {
+                let n = from_utf8(key_as_boxed_slice(&csr)).parse::<u32>().unwrap();
+                assert!(prev.map_or(true, |p| p < n));
+                prev = Some(n);
+                try!(csr.Next());
+            }
+            assert_eq!(Some(1000), prev);
+            assert_eq!(491, try!(count_keys_backward(&mut csr)));
+
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"25"), lsm::SeekOp::SEEK_GE));
+            assert_eq!("30", key_as_string(&csr));
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"25"), lsm::SeekOp::SEEK_LE));
+            assert_eq!("19", key_as_string(&csr));
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"100"), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            Ok(())
+        }
+
+        let name = tempfile("comparator");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings()));
+            // evens and odds, so a merge has to interleave them
+            let mut t1 = std::collections::HashMap::new();
+            let mut t2 = std::collections::HashMap::new();
+            for i in 0 .. 500 {
+                let t = if i % 2 == 0 { &mut t1 } else { &mut t2 };
+                t.insert(into_utf8(format!("{}", i)), lsm::Blob::Array(into_utf8(format!("{}", i * 2))));
+            }
+            let g1 = try!(db.WriteSegment2(t1));
+            let g2 = try!(db.WriteSegment2(t2));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g2, g1]));
+            }
+            // 20 through 29 are gone, but not 200 through 299
+            try!(db.put(b"1000", b"x"));
+            try!(db.delete_range(b"20", b"30"));
+            try!(check(&db));
+            assert!(try!(db.get(b"25")).is_none());
+            assert_eq!("500", from_utf8(try!(db.get(b"250")).unwrap()));
+
+            let r = try!(db.merge(0, 2, None)).unwrap();
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitMerge(r));
+            }
+            try!(check(&db));
+            assert!(try!(db.check()).is_ok());
+        }
+
+        match lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::WrongComparator(ref s)) if s == "length first" => {
+            },
+            _ => panic!(),
+        }
+
+        // the memtable comes back from the log in the right order too
+        let db = try!(lsm::db::new(name, settings()));
+        try!(check(&db));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+fn flip_byte(name: &str, pos: u64) -> lsm::Result<()> {
+    use std::io::Seek;
+    use std::io::Read;
+    use std::io::Write;
+    let mut f = try!(std::fs::OpenOptions::new().read(true).write(true).open(name));
+    let mut b = [0u8; 1];
+    try!(f.seek(std::io::SeekFrom::Start(pos)));
+    try!(f.read(&mut b));
+    b[0] = b[0] ^ 0xff;
+    try!(f.seek(std::io::SeekFrom::Start(pos)));
+    try!(f.write(&b));
+    Ok(())
+}
+
+#[test]
+fn corrupt_page() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("corrupt_page");
+        let g = {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 10, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            g
+        };
+
+        // the header is the first 4096 bytes, and the only leaf
+        // of this segment comes right after it.
+        try!(flip_byte(&name, 4096 + 20));
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        match csr.First() {
+            Err(lsm::Error::CorruptPage{page, pageType, segment}) => {
+                assert_eq!(2, page);
+                assert!(pageType == Some(lsm::PageType::LEAF_NODE));
+                assert_eq!(Some(g), segment);
+            },
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn corrupt_root_page() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("corrupt_root_page");
+        let pos = {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 10, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            let (_, segs) = try!(db.ListSegments());
+            (segs[&g].root() as u64 - 1) * (db.PageSize() as u64) + 20
+        };
+        try!(flip_byte(&name, pos));
+
+        // the cursor is dropped when it fails to read its root page,
+        // which used to wait forever for the cursors lock that
+        // getCursorOn was holding.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let res = lsm::db::new(name, lsm::DEFAULT_SETTINGS).map(|db| {
+                let first = db.OpenCursor().is_err();
+                let second = db.OpenCursor().is_err();
+                first && second
+            });
+            tx.send(res).unwrap();
+        });
+        match rx.recv_timeout(std::time::Duration::from_secs(30)) {
+            Ok(res) => assert!(try!(res)),
+            Err(_) => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn bloom_filter() {
+    fn f(bits: usize) -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                BloomBitsPerKey : bits,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile(&format!("bloom_filter_{}", bits));
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            // big enough that the filter needs more than one block
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 999998, step: 2}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 3, end: 99, step: 6}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+        }
+
+        // the filters get read back when a lookup needs them
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        for i in 0 .. 120 {
+            let k = format!("{:08}", i).into_bytes();
+            let found = try!(db.get(&k)).is_some();
+            let expected = (i % 2 == 0) || (i <= 99 && i % 6 == 3);
+            assert_eq!(expected, found);
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(500000 + 17, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f(0).is_ok());
+    assert!(f(10).is_ok());
+}
+
+#[test]
+fn bloom_cache() {
+    fn f(capacity: usize) -> lsm::Result<lsm::db> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                BloomCacheBytes : capacity,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("bloom_cache"), settings));
+        for i in 0 .. 2 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 1000, end: i * 1000 + 999, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        Ok(db)
+    }
+
+    fn g() -> lsm::Result<()> {
+        // nothing is read until a lookup needs it
+        let db = try!(f(1024 * 1024));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        assert_eq!(0, try!(db.BloomCacheStats()).reads);
+        assert!(try!(db.get(b"nope")).is_none());
+        let st = try!(db.BloomCacheStats());
+        assert_eq!(2, st.reads);
+        assert_eq!(2, st.filters);
+        assert!(try!(db.get(b"nope either")).is_none());
+        assert_eq!(2, try!(db.BloomCacheStats()).reads);
+
+        // the filters of merged segments go away with them
+        drop(csr);
+        let r = try!(db.merge(0, 2, None));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitMerge(r.unwrap()));
+        }
+        let st = try!(db.BloomCacheStats());
+        assert_eq!(0, st.filters);
+        assert_eq!(0, st.bytes);
+
+        // too small to keep either filter
+        let db = try!(f(1000));
+        for _ in 0 .. 3 {
+            assert!(try!(db.get(b"nope")).is_none());
+        }
+        let st = try!(db.BloomCacheStats());
+        assert_eq!(6, st.reads);
+        assert_eq!(0, st.filters);
+
+        // room for one
+        let db = try!(f(2000));
+        for _ in 0 .. 3 {
+            assert!(try!(db.get(b"nope")).is_none());
+        }
+        let st = try!(db.BloomCacheStats());
+        assert_eq!(1, st.filters);
+        assert!(st.bytes <= 2000);
+        Ok(())
+    }
+    assert!(g().is_ok());
+}
+
+#[test]
+fn bloom_filter_skips_segment() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("bloom_filter_skips_segment");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 10, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // damage the only leaf.  a lookup which has to read it
+        // will fail, but one the filter rules out will not.
+        try!(flip_byte(&name, 4096 + 20));
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        assert!(try!(db.get(b"nope")).is_none());
+        assert!(db.get(b"00000005").is_err());
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn corrupt_header() {
+    fn f() -> lsm::Result<()> {
+        let name = tempfile("corrupt_header");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 10, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // the first commit goes in the second header slot.  the other
+        // one was never written.  skip the magic and the format version,
+        // which have their own errors.
+        try!(flip_byte(&name, 2048 + 20));
+
+        match lsm::db::new(name, lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::CorruptPage{page: 0, pageType: None, segment: None}) => {
+            },
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn torn_header_falls_back() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("torn_header_falls_back");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 100, end: 109, step: 1}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+        }
+
+        // the second commit went in the first slot.  damage it, as
+        // if the write had been torn.
+        try!(flip_byte(&name, 20));
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        let mut count = 0;
+        while csr.IsValid() {
+            count = count + 1;
+            try!(csr.Next());
+        }
+        assert_eq!(10, count);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn header_overflow() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("header_overflow");
+        {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            // enough segments that the list does not fit in the header
+            let mut a = Vec::new();
+            for i in 0 .. 1000 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i, end: i, step: 1}));
+                a.push(g);
+            }
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(a));
+        }
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(1000, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn header_overflow_segment_list() {
+    // the first chunk of an overflowed segment list is the rest of
+    // the header page.  it used to be read from wherever the file
+    // happened to be positioned instead.
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                AutoMergeEnabled : false,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let name = tempfile("header_overflow_segment_list");
+        let before = {
+            let db = try!(lsm::db::new(name.clone(), settings));
+            let mut a = Vec::new();
+            for i in 0 .. 1000 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 10, end: i * 10 + i % 7, step: 1}));
+                a.push(g);
+            }
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(a));
+            try!(db.ListSegments())
+        };
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let after = try!(db.ListSegments());
+        assert_eq!(before.0, after.0);
+        for g in before.0.iter() {
+            let b = before.1.get(g).unwrap();
+            let a = after.1.get(g).unwrap();
+            assert_eq!(b.root(), a.root());
+            assert_eq!(b.age(), a.age());
+            assert_eq!(b.count_pages(), a.count_pages());
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn upgrade_format_1() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Write;
+        let name = tempfile("upgrade_format_1");
+
+        // a file in format 1, built by hand.  no magic, no page checksums.
+        // one segment, with one leaf, and a root page above it.
+        {
+            let mut hdr = vec![0u8; 4096];
+            let a = [
+                0, 0, 16, 0, // page size
+                1, // changeCounter
+                0, // mergeCounter
+                7, // length of the segment list
+                0, // not overflowed
+                1, // number of segments
+                1, 3, 0, // segment num, root page, age
+                1, 2, 2, // one block, first page 2, two pages
+                ];
+            for i in 0 .. a.len() {
+                hdr[i] = a[i];
+            }
+            let mut leaf = vec![0u8; 4096];
+            let a = [
+                1, 0, // leaf, no flags
+                0, 0, 0, 0, // no previous leaf
+                0, // no prefix
+                0, 2, // two keys
+                0, 1, b'a', 0, 1, b'1',
+                0, 1, b'b', 0, 1, b'2',
+                ];
+            for i in 0 .. a.len() {
+                leaf[i] = a[i];
+            }
+            let mut root = vec![0u8; 4096];
+            let a = [
+                2, 1, // parent, root
+                0, 0, // no keys
+                2, // the one child
+                ];
+            for i in 0 .. a.len() {
+                root[i] = a[i];
+            }
+            root[4096 - 8 + 3] = 2; // first leaf
+            root[4096 - 4 + 3] = 2; // last leaf
+            let mut f = try!(std::fs::File::create(&name));
+            try!(f.write_all(&hdr));
+            try!(f.write_all(&leaf));
+            try!(f.write_all(&root));
+        }
+
+        for _ in 0 .. 2 {
+            // the first open upgrades the file.  the second one
+            // makes sure the upgrade was written.
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(2, try!(count_keys_forward(&mut csr)));
+            assert_eq!("1", from_utf8(try!(db.get(b"a")).unwrap()));
+            assert_eq!("2", from_utf8(try!(db.get(b"b")).unwrap()));
+        }
+
+        let mut f = try!(std::fs::File::open(&name));
+        let mut magic = [0u8; 8];
+        try!(misc::io::read_fully(&mut f, &mut magic));
+        assert!(&magic == b"\x89LSM\r\n\x1a\n");
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn refuse_foreign_and_newer_files() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Write;
+
+        let name = tempfile("refuse_foreign");
+        {
+            let mut f = try!(std::fs::File::create(&name));
+            for _ in 0 .. 400 {
+                try!(f.write_all(b"not a db\n"));
+            }
+        }
+        match lsm::db::new(name, lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::NotADatabase) => {
+            },
+            _ => panic!(),
+        }
+
+        let name = tempfile("refuse_newer");
+        {
+            let mut hdr = vec![0u8; 4096];
+            let a = b"\x89LSM\r\n\x1a\n\x00\x00\x00\x63";
+            for i in 0 .. a.len() {
+                hdr[i] = a[i];
+            }
+            let mut f = try!(std::fs::File::create(&name));
+            try!(f.write_all(&hdr));
+        }
+        match lsm::db::new(name, lsm::DEFAULT_SETTINGS) {
+            Err(lsm::Error::UnsupportedFormatVersion(99)) => {
+            },
+            _ => panic!(),
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}